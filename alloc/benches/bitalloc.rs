@@ -0,0 +1,128 @@
+//! # Bitalloc benchmark -- compare bitmap word sizes.
+//!
+//! Run with:
+//!
+//!     cargo bench -p alloc
+//!
+//! Times BitAlloc with u32, u64 and u128 words on maps the size of
+//! descriptor tables, with and without summary levels. Prints one
+//! line per case, in nanoseconds per operation. Pick the word size
+//! for a platform from these numbers.
+//!
+//! Animats
+//! November, 2024
+//!
+use alloc::{BitAlloc, BitWord};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Map sizes, in bits. Initial descriptor table size, a typical bindless texture table, and a big one.
+const SIZES: [usize; 3] = [4096, 1 << 16, 1 << 20];
+/// Operations for the churn and threaded cases.
+const OPS: usize = 200_000;
+/// Threads for the threaded case.
+const THREADS: usize = 4;
+
+/// Cheap pseudo-random numbers, so runs are repeatable.
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 as usize
+    }
+}
+
+/// Make a map, with or without summary levels.
+fn make<W: BitWord>(size: usize, summary: bool) -> BitAlloc<W> {
+    if summary {
+        BitAlloc::new_with_summary(size)
+    } else {
+        BitAlloc::new(size)
+    }
+}
+
+/// Nanoseconds per operation for `ops` operations done by `f`.
+fn time_ns(ops: usize, f: impl FnOnce()) -> f64 {
+    let start = Instant::now();
+    f();
+    start.elapsed().as_nanos() as f64 / ops as f64
+}
+
+/// Fill an empty map, one bit at a time.
+fn fill<W: BitWord>(size: usize, summary: bool) -> f64 {
+    let bit_alloc: BitAlloc<W> = make(size, summary);
+    time_ns(size, || {
+        for _ in 0..size {
+            let _ = bit_alloc.alloc_bit().unwrap();
+        }
+    })
+}
+
+/// Full map. Free a random bit and allocate again. The worst case for a linear scan.
+fn churn<W: BitWord>(size: usize, summary: bool) -> f64 {
+    let bit_alloc: BitAlloc<W> = make(size, summary);
+    while bit_alloc.alloc_bit().is_some() {}
+    let mut rand = XorShift(0x2545F4914F6CDD1D);
+    time_ns(OPS, || {
+        for _ in 0..OPS {
+            bit_alloc.clear_bit(rand.next() % size).unwrap();
+            let _ = bit_alloc.alloc_bit().unwrap();
+        }
+    })
+}
+
+/// Half full map, several threads each allocating and freeing.
+fn threaded<W: BitWord>(size: usize, summary: bool) -> f64 {
+    let bit_alloc: Arc<BitAlloc<W>> = Arc::new(make(size, summary));
+    for _ in 0..size / 2 {
+        let _ = bit_alloc.alloc_bit().unwrap();
+    }
+    time_ns(OPS, || {
+        let workers: Vec<_> = (0..THREADS)
+            .map(|n| {
+                let bit_alloc = Arc::clone(&bit_alloc);
+                std::thread::spawn(move || {
+                    let mut held = Vec::new();
+                    let mut rand = XorShift(n as u64 + 1);
+                    for _ in 0..OPS / THREADS {
+                        if held.len() < 64 && rand.next() & 1 == 0 {
+                            held.extend(bit_alloc.alloc_bit());
+                        } else if let Some(ix) = held.pop() {
+                            bit_alloc.clear_bit(ix).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+    })
+}
+
+/// All the cases for one word type.
+fn run<W: BitWord>(name: &str) {
+    for size in SIZES {
+        for summary in [false, true] {
+            println!(
+                "{:>4} {:>8} bits {:>10}: fill {:>8.1}  churn {:>8.1}  threaded {:>8.1}",
+                name,
+                size,
+                if summary { "summary" } else { "linear" },
+                fill::<W>(size, summary),
+                churn::<W>(size, summary),
+                threaded::<W>(size, summary),
+            );
+        }
+    }
+}
+
+fn main() {
+    println!("BitAlloc, ns per operation.");
+    run::<u32>("u32");
+    run::<u64>("u64");
+    run::<u128>("u128");
+}
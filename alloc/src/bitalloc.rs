@@ -5,140 +5,959 @@
 //! November, 2024
 //!
 #![forbid(unsafe_code)]
-use anyhow::{anyhow, Error};
-use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use crate::segvec::SegVec;
+use crate::sync::{AtomicU64, AtomicUsize, Ordering};
+use crate::tracker::AllocTracker;
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use std::sync::Arc;
 
-/// Our bitmap is an array of these. u64 today, u128 when atomics that large are widely available.
-type WordType = u64;
-type AtomicWordType = AtomicU64;
-const WORDSIZE: usize = WordType::BITS as usize;
-const WORDALLONES: WordType = !0; // this had better be the all ones value
+#[cfg(all(test, loom))]
+mod loomtests;
+mod word;
+pub use word::{AtomicBitWord, BitWord};
 
-/// Bit allocator
-pub struct BitAlloc {
+/// Bit allocator.
+///
+/// The bitmap is an array of `W`, which can be u32, u64 or u128. u64 is the default.
+pub struct BitAlloc<W: BitWord = u64> {
     /// Search start position
     search_pos: AtomicUsize,
-    /// The bitmap itself
-    b: Vec<AtomicWordType>,
+    /// The bitmap itself. Can grow while in use.
+    b: SegVec<W::Atomic>,
+    /// Optional summary levels. In level 0, a set bit means that bitmap word is full.
+    /// In each higher level, a set bit means that word of the level below is all ones.
+    /// Empty if not in use.
+    summary: Vec<Vec<W::Atomic>>,
+    /// Ranges of bits which are permanently set and may not be cleared
+    reserved: Vec<Range<usize>>,
+    /// Regions searched separately by `alloc_bit_in`
+    regions: Vec<Region>,
     /// Statistics - requests
     alloc_count: AtomicU64,
     /// Statistics - word earches
     search_count: AtomicU64,
+    /// Statistics - bits currently set
+    allocated: AtomicUsize,
+    /// Statistics - most bits ever set at once
+    high_water: AtomicUsize,
+    /// Statistics - one past the highest bit ever set. Nothing above this needs scanning.
+    top: AtomicUsize,
+    /// Statistics - compare and swap retries in allocation
+    alloc_retries: AtomicU64,
+    /// Statistics - compare and swap retries in clear
+    clear_retries: AtomicU64,
+    /// Panic, rather than returning an error, on a double free or bad index. Debug builds only.
+    panic_on_misuse: bool,
+    /// Optional allocation tracking
+    tracking: Option<Tracking>,
 }
 
-impl BitAlloc {
+/// Where a BitAlloc reports its allocations.
+struct Tracking {
+    /// Shared tracker
+    tracker: Arc<AllocTracker>,
+    /// Our source number in the tracker
+    source: u32,
+    /// Label for all our allocations
+    label: Option<String>,
+}
+
+/// A sub-range of the bitmap with its own search start position.
+struct Region {
+    /// Bits in this region
+    range: Range<usize>,
+    /// Search start position, as a bit index. All bits in the region below it are set.
+    search_pos: AtomicUsize,
+}
+
+/// Errors from freeing bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitAllocError {
+    /// Index is past the end of the bitmap.
+    OutOfRange(usize),
+    /// Bit was already clear. This is a double free.
+    AlreadyClear(usize),
+    /// Bit is in a reserved range and can never be freed.
+    Reserved(usize),
+}
+
+impl std::fmt::Display for BitAllocError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BitAllocError::OutOfRange(ix) => write!(f, "Bitalloc index {} out of range.", ix),
+            BitAllocError::AlreadyClear(ix) => {
+                write!(f, "Bitalloc bit {} was already clear. Double free.", ix)
+            }
+            BitAllocError::Reserved(ix) => write!(f, "Bitalloc bit {} is reserved.", ix),
+        }
+    }
+}
+
+impl std::error::Error for BitAllocError {}
+
+/// Snapshot of BitAlloc statistics.
+///
+/// Taken with relaxed loads while allocation continues, so the fields
+/// may be very slightly inconsistent with each other.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BitAllocStats {
+    /// Size, in bits
+    pub capacity: usize,
+    /// Bits currently allocated
+    pub allocated: usize,
+    /// Most bits allocated at one time
+    pub high_water: usize,
+    /// Allocation requests
+    pub alloc_requests: u64,
+    /// Words searched by all allocation requests
+    pub words_searched: u64,
+    /// Compare and swap retries in allocation, caused by other threads
+    pub alloc_retries: u64,
+    /// Compare and swap retries in clear, caused by other threads
+    pub clear_retries: u64,
+    /// Fragmentation estimate, 0.0 to 1.0. The fraction of bits below the
+    /// highest bit now set which are free.
+    pub fragmentation: f64,
+}
+
+/// Compact dump of which bits are allocated, as runs of set bits.
+///
+/// For crash reports and leak checks. Reserved bits are left out.
+/// Serializable, and also prints compactly, as in `4096 bits: 1..3,7,10..200`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BitAllocDump {
+    /// Length of the bitmap, in bits
+    pub len: usize,
+    /// Runs of allocated bits, in increasing order
+    pub runs: Vec<Range<usize>>,
+}
+
+impl BitAllocDump {
+    /// Number of allocated bits.
+    pub fn count(&self) -> usize {
+        self.runs.iter().map(|r| r.len()).sum()
+    }
+}
+
+impl std::fmt::Display for BitAllocDump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} bits: ", self.len)?;
+        for (n, run) in self.runs.iter().enumerate() {
+            if n > 0 {
+                write!(f, ",")?;
+            }
+            if run.len() == 1 {
+                write!(f, "{}", run.start)?;
+            } else {
+                write!(f, "{}..{}", run.start, run.end)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Iterator over allocated bits. Returned by `BitAlloc::iter`.
+pub struct BitAllocIter<'a, W: BitWord = u64> {
+    /// Bitmap being scanned
+    bit_alloc: &'a BitAlloc<W>,
+    /// Word index of `val`
+    word: usize,
+    /// Bits of the current word not yet returned
+    val: W,
+}
+
+impl<W: BitWord> Iterator for BitAllocIter<'_, W> {
+    type Item = usize;
+    fn next(&mut self) -> Option<usize> {
+        loop {
+            while self.val == W::ZERO {
+                //  Done with this word. Skip over empty words.
+                self.word += 1;
+                if self.word >= self.bit_alloc.b.len() {
+                    return None;
+                }
+                self.val = self.bit_alloc.b[self.word].load(Ordering::SeqCst);
+            }
+            let bit = self.val.trailing_zeros();
+            self.val &= !(W::ONE << bit); // clear lowest set bit
+            let ix = self.word * W::BITS + bit;
+            if !self.bit_alloc.is_reserved(ix) {
+                return Some(ix);
+            }
+        }
+    }
+}
+
+impl<W: BitWord> BitAlloc<W> {
     /// Usual new.
     pub fn new(size: usize) -> Self {
-        assert!(WORDALLONES == WordType::MAX); // check on constant
-        let word_count = (size + WORDSIZE - 1) / WORDSIZE; // number of words
-        let mut b: Vec<AtomicWordType> = Vec::new();
-        b.resize_with(word_count, || AtomicWordType::new(0));
+        Self::new_growable(size, size)
+    }
+
+    /// New, starting at `size` bits and able to grow up to `ceiling` bits.
+    ///
+    /// For a descriptor table, the ceiling is the table's `max_count` from the device limits.
+    pub fn new_growable(size: usize, ceiling: usize) -> Self {
+        let word_count = size.div_ceil(W::BITS); // number of words
+        let b = SegVec::new(word_count, ceiling.max(size).div_ceil(W::BITS));
         Self {
             search_pos: AtomicUsize::new(0),
             b,
+            summary: Vec::new(),
+            reserved: Vec::new(),
+            regions: Vec::new(),
             alloc_count: Default::default(),
             search_count: Default::default(),
+            allocated: Default::default(),
+            high_water: Default::default(),
+            top: Default::default(),
+            alloc_retries: Default::default(),
+            clear_retries: Default::default(),
+            panic_on_misuse: false,
+            tracking: None,
+        }
+    }
+
+    /// New, with hierarchical summary levels above the bitmap.
+    ///
+    /// Allocation then walks down from the top summary word, which keeps it
+    /// O(log n) even when the bitmap is nearly full and fragmented. Costs about
+    /// 1/64 more space, and a little more work when words fill up or empty.
+    pub fn new_with_summary(size: usize) -> Self {
+        Self::new(size).with_summary()
+    }
+
+    /// Add hierarchical summary levels, as for `new_with_summary`.
+    ///
+    /// The levels are sized for the ceiling, so growth doesn't have to resize them.
+    pub fn with_summary(mut self) -> Self {
+        let in_use = self.b.len(); // bitmap words in use now
+        let mut below = self.b.ceiling(); // number of words in the level below
+        while below > 1 {
+            let word_count = below.div_ceil(W::BITS);
+            let mut level: Vec<W::Atomic> = Vec::new();
+            level.resize_with(word_count, || W::Atomic::new(W::ZERO));
+            for child in 0..word_count * W::BITS {
+                //  Children which don't exist, or aren't in use yet, are marked full so they are never searched.
+                let full = if child >= below {
+                    true
+                } else if let Some(prev) = self.summary.last() {
+                    prev[child].load(Ordering::SeqCst) == W::ALLONES
+                } else {
+                    child >= in_use || self.b[child].load(Ordering::SeqCst) == W::ALLONES
+                };
+                if full {
+                    let (word, bit) = Self::word_bit(child);
+                    let _ = level[word].fetch_or(W::ONE << bit, Ordering::SeqCst);
+                }
+            }
+            self.summary.push(level);
+            below = word_count;
+        }
+        self
+    }
+    /// Permanently reserve ranges of bits, such as slot 0 for an error fallback.
+    ///
+    /// Reserved bits are set, are never handed out, and can't be cleared.
+    /// They don't count as allocated in the statistics.
+    /// Ranges may go up to the ceiling. Bits past the current length are set as the bitmap grows.
+    pub fn with_reserved(mut self, reserved: &[Range<usize>]) -> Self {
+        for range in reserved {
+            assert!(
+                range.end <= self.ceiling(),
+                "Bitalloc reserved range {:?} out of range",
+                range
+            );
+            if range.is_empty() {
+                continue;
+            }
+            for (word, mask) in Self::range_masks(range.start, range.len()) {
+                if word >= self.b.len() {
+                    break; // set by grow
+                }
+                let old = self.b[word].fetch_or(mask, Ordering::SeqCst);
+                if old | mask == W::ALLONES {
+                    self.mark_full(word);
+                }
+            }
+            self.reserved.push(range.clone());
         }
+        self
+    }
+
+    /// Report allocations to `tracker`, under `label`, such as the descriptor table name.
+    ///
+    /// A run from `alloc_range` is tracked as one allocation, at its first bit.
+    /// Each allocation and free then takes the tracker's lock.
+    pub fn with_tracker(mut self, tracker: &Arc<AllocTracker>, label: Option<&str>) -> Self {
+        self.tracking = Some(Tracking {
+            tracker: Arc::clone(tracker),
+            source: tracker.add_source("BitAlloc"),
+            label: label.map(str::to_string),
+        });
+        self
+    }
+
+    /// Divide the bitmap into regions, such as static, streaming and UI textures.
+    ///
+    /// Each region keeps its own search start position for `alloc_bit_in`,
+    /// so allocation in one region doesn't slow down allocation in another.
+    pub fn with_regions(mut self, regions: &[Range<usize>]) -> Self {
+        for range in regions {
+            assert!(
+                range.end <= self.ceiling(),
+                "Bitalloc region {:?} out of range",
+                range
+            );
+            self.regions.push(Region {
+                range: range.clone(),
+                search_pos: AtomicUsize::new(range.start),
+            });
+        }
+        self
     }
 
     /// Length, in bits
     pub fn len(&self) -> usize {
-        self.b.len() * WORDSIZE
+        self.b.len() * W::BITS
+    }
+
+    /// True if the bitmap has no bits at all.
+    pub fn is_empty(&self) -> bool {
+        self.b.is_empty()
+    }
+
+    /// Maximum length, in bits, that the bitmap can grow to.
+    pub fn ceiling(&self) -> usize {
+        self.b.ceiling() * W::BITS
+    }
+
+    /// Grow the bitmap to at least `new_size` bits, limited by the ceiling.
+    ///
+    /// Other threads can keep allocating and clearing while this happens.
+    /// Existing bits don't move. Returns the new length, in bits.
+    pub fn grow(&self, new_size: usize) -> usize {
+        let old_words = self.b.len();
+        //  Reserved bits are in the new words from the start, so nobody can take them.
+        let new_words = self.b.grow_with(new_size.div_ceil(W::BITS), |word| {
+            W::Atomic::new(self.reserved_mask(word))
+        });
+        //  The new words are now searchable through the summary.
+        for word in old_words..new_words {
+            if self.b[word].load(Ordering::SeqCst) == W::ALLONES {
+                self.mark_full(word);
+            } else {
+                self.mark_not_full(word);
+            }
+        }
+        new_words * W::BITS
+    }
+
+    /// Allocate a bit, growing the bitmap if it is full and below its ceiling.
+    ///
+    /// Each growth doubles the size.
+    pub fn alloc_bit_or_grow(&self) -> Option<usize> {
+        self.alloc_bit_or_grow_with(|len| (len * 2).max(W::BITS))
+    }
+
+    /// Allocate a bit, as for `alloc_bit_or_grow`, with each growth going
+    /// from `len` bits to `next_size(len)` bits.
+    pub fn alloc_bit_or_grow_with(&self, next_size: impl Fn(usize) -> usize) -> Option<usize> {
+        loop {
+            let len = self.len(); // size before trying
+            if let Some(ix) = self.alloc_bit() {
+                return Some(ix);
+            }
+            if self.len() > len {
+                continue; // another thread grew the bitmap meanwhile, so try again
+            }
+            if len >= self.ceiling() {
+                return None; // hit the ceiling
+            }
+            let _ = self.grow(next_size(len));
+        }
     }
 
     /// Get one bit. Not atomic
     pub fn get_bit(&self, ix: usize) -> bool {
         let (word, bit) = Self::word_bit(ix);
         if word < self.b.len() {
-            (self.b[word].load(Ordering::SeqCst) >> bit) & 0x1 == 1 // bit as bool
+            (self.b[word].load(Ordering::SeqCst) >> bit) & W::ONE == W::ONE // bit as bool
         } else {
             false
         }
     }
 
+    /// Panic on a double free or bad index, instead of returning an error.
+    ///
+    /// Only effective in builds with debug assertions. Release builds always return the error.
+    pub fn set_panic_on_misuse(&mut self, panic_on_misuse: bool) {
+        self.panic_on_misuse = panic_on_misuse;
+    }
+
     /// Clear one bit. It is a error to clear an un-set bit.
-    pub fn clear_bit(&self, ix: usize) -> Result<(), Error> {
+    pub fn clear_bit(&self, ix: usize) -> Result<(), BitAllocError> {
+        if self.is_reserved(ix) {
+            return Err(self.misuse(BitAllocError::Reserved(ix)));
+        }
         let (word, bit) = Self::word_bit(ix);
         if word < self.b.len() {
+            //  Untrack while the bit is still set, so a new owner's record can't be removed.
+            let tracked = self.untrack(ix);
             //  Retry loop for atomic CAS
             loop {
                 let val = self.b[word].load(Ordering::SeqCst); // get word
-                let newval = val & !(1 << bit); // clear bit
+                let newval = val & !(W::ONE << bit); // clear bit
+                if val == newval {
+                    //  Bit was not set. Someone else owns this slot now, or nobody does.
+                    if tracked {
+                        self.retrack(ix);
+                    }
+                    return Err(self.misuse(BitAllocError::AlreadyClear(ix)));
+                }
                 let swap_result =
                     self.b[word].compare_exchange(val, newval, Ordering::SeqCst, Ordering::Relaxed);
                 if swap_result.is_ok() {
+                    self.note_free(1);
                     break;
                 }
+                let _ = self.clear_retries.fetch_add(1, Ordering::Relaxed);
                 log::warn!("Race condition in clear_bit, retrying");
             }
+            self.mark_not_full(word);
             //  Updated successfuly. Update start position for next search if this is the new min
             let _ = self.search_pos.fetch_min(word, Ordering::Relaxed);
+            self.region_freed(ix);
             Ok(())
         } else {
-            Err(anyhow!("Bitalloc clear_bit index out of range."))
+            Err(self.misuse(BitAllocError::OutOfRange(ix)))
         }
     }
 
     /// Allocate a bit, if any are available.
     pub fn alloc_bit(&self) -> Option<usize> {
-        let _ = self.alloc_count.fetch_add(1, Ordering::Relaxed); // tally words searched
+        let _ = self.alloc_count.fetch_add(1, Ordering::Relaxed); // tally requests
+        if !self.summary.is_empty() {
+            if let Some(ix) = self.alloc_bit_summary() {
+                return Some(ix);
+            }
+            //  The summary can be briefly out of date during a race with clear_bit,
+            //  so "full" has to be confirmed by a scan of the bitmap itself.
+        }
         let start_pos = self.search_pos.load(Ordering::SeqCst);
-        for word in start_pos..self.b.len() {
-            //  Retry loop for atomic CAS
+        //  The start position can move past a bit freed while another search was under way.
+        //  So before reporting full, also look at the words before it.
+        let len = self.b.len();
+        for word in (start_pos..len).chain(0..start_pos.min(len)) {
             let _ = self.search_count.fetch_add(1, Ordering::Relaxed); // tally words searched
-            loop {
-                let val = self.b[word].load(Ordering::SeqCst); // get word
-                if val == WORDALLONES {
-                    // if all ones, done with inner loop, continue outer loo[
-                    break;
+            if let Some(bit) = self.alloc_in_word(word, W::ZERO) {
+                //  Update search start position to try from here next time.
+                let pos_result = self.search_pos.compare_exchange(
+                    start_pos,
+                    word,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                );
+                if pos_result.is_err() {
+                    //  This is just an unsucessful optimization. Next search may be slightly slower.
+                    log::info!("Race condition in alloc_bit pos update, harmless.");
                 }
-                //  There may be an open slot in this word.
-                //  But we have to test that with an atomic operation.
-                let bit = (!val).trailing_zeros(); // find first zero bit.
-                let newval = val | (1 << bit); // new value for bitmap word
+                //  Return position of bit just set.
+                return Some(word * W::BITS + bit);
+            }
+        }
+        None // bitmap is full
+    }
 
-                //  Now try to insert that into the map with a compare and swap.
-                //  If that fails, we have to try again.
-                let swap_result =
-                    self.b[word].compare_exchange(val, newval, Ordering::SeqCst, Ordering::Relaxed);
-                if let Ok(_) = swap_result {
-                    //  Update search start position to try from here next time.
-                    let pos_result = self.search_pos.compare_exchange(
+    /// Allocate a bit within one word of the bitmap, if it has any clear bits.
+    /// Bits set in `outside` are treated as unavailable.
+    fn alloc_in_word(&self, word: usize, outside: W) -> Option<usize> {
+        //  Retry loop for atomic CAS
+        loop {
+            let val = self.b[word].load(Ordering::SeqCst); // get word
+            if val | outside == W::ALLONES {
+                return None; // if all ones, caller must look elsewhere
+            }
+            //  There may be an open slot in this word.
+            //  But we have to test that with an atomic operation.
+            let bit = (!(val | outside)).trailing_zeros(); // find first zero bit.
+            let newval = val | (W::ONE << bit); // new value for bitmap word
+
+            //  Now try to insert that into the map with a compare and swap.
+            //  If that fails, we have to try again.
+            let swap_result =
+                self.b[word].compare_exchange(val, newval, Ordering::SeqCst, Ordering::Relaxed);
+            if swap_result.is_ok() {
+                if newval == W::ALLONES {
+                    self.mark_full(word);
+                }
+                self.note_alloc(word * W::BITS + bit, 1);
+                return Some(bit);
+            }
+            //  Compare and swap failed. Some other thread updated this value.
+            let _ = self.alloc_retries.fetch_add(1, Ordering::Relaxed);
+            log::warn!("Race condition in alloc_bit, retrying."); // should be very rare
+
+            //  Have to try again
+        }
+    }
+
+    /// Allocate a bit within `range`, if any are available there.
+    ///
+    /// If `range` is one of the regions given to `with_regions`, the search starts
+    /// from that region's own start position. Otherwise it starts at the beginning of `range`.
+    pub fn alloc_bit_in(&self, range: Range<usize>) -> Option<usize> {
+        let _ = self.alloc_count.fetch_add(1, Ordering::Relaxed); // tally requests
+        let range = range.start..range.end.min(self.len());
+        if range.is_empty() {
+            return None;
+        }
+        let region = self.regions.iter().find(|r| r.range == range);
+        let start_pos = region.map_or(range.start, |r| r.search_pos.load(Ordering::SeqCst));
+        let (first_word, _) = Self::word_bit(start_pos.max(range.start));
+        let (range_first_word, _) = Self::word_bit(range.start);
+        let (last_word, _) = Self::word_bit(range.end - 1);
+        //  As with alloc_bit, look before the start position before reporting full.
+        for word in (first_word..=last_word).chain(range_first_word..first_word) {
+            let _ = self.search_count.fetch_add(1, Ordering::Relaxed); // tally words searched
+
+            //  Bits of this word outside the range are off limits.
+            let inside = Self::word_mask(word, range.start, range.end);
+            if let Some(bit) = self.alloc_in_word(word, !inside) {
+                if let Some(region) = region {
+                    //  Next search in this region can start here.
+                    let pos = (word * W::BITS).max(range.start);
+                    let _ = region.search_pos.compare_exchange(
                         start_pos,
-                        word,
+                        pos,
                         Ordering::Relaxed,
                         Ordering::Relaxed,
                     );
-                    if let Err(_) = pos_result {
-                        //  This is just an unsucessful optimization. Next search may be slightly slower.
-                        log::info!("Race condition in alloc_bit pos update, harmless.");
+                }
+                return Some(word * W::BITS + bit);
+            }
+        }
+        None // region is full
+    }
+
+    /// Bits starting at `ix` were freed. Move back the search start of the region holding them.
+    fn region_freed(&self, ix: usize) {
+        for region in &self.regions {
+            if region.range.contains(&ix) {
+                let _ = region.search_pos.fetch_min(ix, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// The reserved bits of one word.
+    fn reserved_mask(&self, word: usize) -> W {
+        let (word_start, word_end) = (word * W::BITS, (word + 1) * W::BITS);
+        self.reserved
+            .iter()
+            .filter(|r| r.start < word_end && word_start < r.end)
+            .fold(W::ZERO, |mask, r| {
+                mask | Self::word_mask(word, r.start, r.end)
+            })
+    }
+
+    /// True if the bit is in a reserved range.
+    fn is_reserved(&self, ix: usize) -> bool {
+        self.reserved.iter().any(|r| r.contains(&ix))
+    }
+
+    /// Allocate a bit by walking down the summary levels to a word with clear bits.
+    fn alloc_bit_summary(&self) -> Option<usize> {
+        let top = self.summary.len() - 1;
+        (0..self.summary[top].len()).find_map(|word| self.descend(top, word))
+    }
+
+    /// Search below one word of a summary level. A clear bit there means the child may have room.
+    fn descend(&self, level: usize, word: usize) -> Option<usize> {
+        let _ = self.search_count.fetch_add(1, Ordering::Relaxed); // tally words searched
+        let mut val = self.summary[level][word].load(Ordering::SeqCst);
+        while val != W::ALLONES {
+            let bit = (!val).trailing_zeros();
+            let child = word * W::BITS + bit;
+            let found = if level == 0 {
+                let _ = self.search_count.fetch_add(1, Ordering::Relaxed); // tally words searched
+                self.alloc_in_word(child, W::ZERO)
+                    .map(|bit| child * W::BITS + bit)
+            } else {
+                self.descend(level - 1, child)
+            };
+            if found.is_some() {
+                return found;
+            }
+            //  That child filled up since the summary was read. Try the next one.
+            val |= W::ONE << bit;
+        }
+        None
+    }
+
+    /// A bitmap word has become full. Record that in the summary levels.
+    ///
+    /// Each summary bit is set and then the child is checked again. If a clear
+    /// got in between, the bit is taken back out. A summary bit which wrongly says
+    /// "not full" only costs a little search time; one which wrongly says "full"
+    /// would hide free bits, so this order matters.
+    fn mark_full(&self, mut index: usize) {
+        for level in 0..self.summary.len() {
+            let (word, bit) = Self::word_bit(index);
+            let mask: W = W::ONE << bit;
+            let old = self.summary[level][word].fetch_or(mask, Ordering::SeqCst);
+            let child = if level == 0 {
+                &self.b[index]
+            } else {
+                &self.summary[level - 1][index]
+            };
+            if child.load(Ordering::SeqCst) != W::ALLONES {
+                //  Raced with a clear. Undo.
+                let _ = self.summary[level][word].fetch_and(!mask, Ordering::SeqCst);
+                return;
+            }
+            if old | mask != W::ALLONES {
+                return; // this summary word still has room, so nothing above changes
+            }
+            index = word; // summary word is now full, so propagate up
+        }
+    }
+
+    /// A bitmap word now has clear bits. Clear its bit in every summary level above it.
+    fn mark_not_full(&self, mut index: usize) {
+        for level in &self.summary {
+            let (word, bit) = Self::word_bit(index);
+            let _ = level[word].fetch_and(!(W::ONE << bit), Ordering::SeqCst);
+            index = word;
+        }
+    }
+
+    /// Allocate a run of `n` adjacent bits, if such a run is available.
+    ///
+    /// Returns the index of the first bit of the run. The run may cross word boundaries.
+    /// Each word covered by the run is claimed with its own compare and swap. If another
+    /// thread takes a bit in the run partway through, the words already claimed are
+    /// released and the search continues past the conflict.
+    pub fn alloc_range(&self, n: usize) -> Option<usize> {
+        if n == 0 {
+            return None;
+        }
+        let _ = self.alloc_count.fetch_add(1, Ordering::Relaxed); // tally requests
+        let mut from = self.search_pos.load(Ordering::SeqCst) * W::BITS;
+        loop {
+            let start = match self.find_free_run(from, n) {
+                Some(start) => start,
+                //  Nothing after the start position. It may have overshot a free, so try from the beginning.
+                None if from > 0 => {
+                    from = 0;
+                    continue;
+                }
+                None => return None, // no run is big enough
+            };
+            match self.claim_range(start, n) {
+                Ok(()) => {
+                    self.note_alloc(start, n);
+                    return Some(start);
+                }
+                Err(conflict) => {
+                    let _ = self.alloc_retries.fetch_add(1, Ordering::Relaxed);
+                    //  Some other thread got in first. Resume search after the bit it took.
+                    log::warn!("Race condition in alloc_range, retrying.");
+                    from = conflict + 1;
+                }
+            }
+        }
+    }
+
+    /// Free a run of `n` adjacent bits previously obtained from `alloc_range`.
+    ///
+    /// If any bit in the run was already clear, the rest are still cleared,
+    /// and the first one found clear is reported as a double free.
+    pub fn free_range(&self, start: usize, n: usize) -> Result<(), BitAllocError> {
+        if start.checked_add(n).is_none_or(|end| end > self.len()) {
+            return Err(self.misuse(BitAllocError::OutOfRange(start.saturating_add(n))));
+        }
+        if n == 0 {
+            return Ok(());
+        }
+        if let Some(r) = self
+            .reserved
+            .iter()
+            .find(|r| r.start < start + n && start < r.end)
+        {
+            return Err(self.misuse(BitAllocError::Reserved(r.start.max(start))));
+        }
+        //  Untrack while the bits are still set, so a new owner's record can't be removed.
+        let tracked = self.untrack(start);
+        let mut already_clear = None;
+        for (word, mask) in Self::range_masks(start, n) {
+            let old = self.b[word].fetch_and(!mask, Ordering::SeqCst); // clear our bits
+            self.mark_not_full(word);
+            self.note_free((old & mask).count_ones());
+            let missing = !old & mask; // bits which were supposed to be set but were not
+            if missing != W::ZERO && already_clear.is_none() {
+                already_clear = Some(word * W::BITS + missing.trailing_zeros());
+            }
+        }
+        //  Update start position for next search if this is the new min
+        let _ = self
+            .search_pos
+            .fetch_min(start / W::BITS, Ordering::Relaxed);
+        self.region_freed(start);
+        if tracked && already_clear == Some(start) {
+            //  The run wasn't ours, so neither was the record.
+            self.retrack(start);
+        }
+        match already_clear {
+            Some(ix) => Err(self.misuse(BitAllocError::AlreadyClear(ix))),
+            None => Ok(()),
+        }
+    }
+
+    /// Misuse detected. Panic if asked to, otherwise hand back the error.
+    fn misuse(&self, err: BitAllocError) -> BitAllocError {
+        if cfg!(debug_assertions) && self.panic_on_misuse {
+            panic!("BitAlloc misuse: {}", err);
+        }
+        log::error!("BitAlloc misuse: {}", err);
+        err
+    }
+
+    /// Find the first run of `n` clear bits at or after bit `from`.
+    ///
+    /// This is only a snapshot. The bits must still be claimed atomically.
+    fn find_free_run(&self, from: usize, n: usize) -> Option<usize> {
+        let (first_word, first_bit) = Self::word_bit(from);
+        let mut run_start = from;
+        let mut run_len = 0;
+        for word in first_word..self.b.len() {
+            let _ = self.search_count.fetch_add(1, Ordering::Relaxed); // tally words searched
+            let val = self.b[word].load(Ordering::SeqCst);
+            //  Bits below the starting point in the first word count as taken.
+            let val = if word == first_word {
+                val | !(W::ALLONES << first_bit)
+            } else {
+                val
+            };
+            let mut pos = 0;
+            while pos < W::BITS {
+                let rest = val >> pos;
+                if rest == W::ZERO {
+                    //  Everything from here to the end of the word is free.
+                    run_len += W::BITS - pos;
+                    break;
+                }
+                let zeros = rest.trailing_zeros();
+                run_len += zeros;
+                if run_len >= n {
+                    return Some(run_start);
+                }
+                pos += zeros;
+                let ones = (rest >> zeros).trailing_ones();
+                pos += ones;
+                //  Run broken by set bits. Next run starts after them.
+                run_len = 0;
+                run_start = word * W::BITS + pos;
+            }
+            if run_len >= n {
+                return Some(run_start);
+            }
+        }
+        None
+    }
+
+    /// Atomically set the bits `start..start+n`, one word at a time.
+    ///
+    /// On failure, bits already set by this call are cleared again, and the
+    /// index of a bit found already set is returned.
+    fn claim_range(&self, start: usize, n: usize) -> Result<(), usize> {
+        let masks: Vec<(usize, W)> = Self::range_masks(start, n).collect();
+        for (i, &(word, mask)) in masks.iter().enumerate() {
+            //  Retry loop for atomic CAS
+            loop {
+                let val = self.b[word].load(Ordering::SeqCst); // get word
+                let taken = val & mask;
+                if taken != W::ZERO {
+                    //  Another thread took a bit in our run. Undo the words claimed so far.
+                    for &(prev_word, prev_mask) in &masks[..i] {
+                        let _ = self.b[prev_word].fetch_and(!prev_mask, Ordering::SeqCst);
+                        self.mark_not_full(prev_word);
+                    }
+                    //  Other allocators may have skipped those words while we held them.
+                    let _ = self
+                        .search_pos
+                        .fetch_min(start / W::BITS, Ordering::Relaxed);
+                    return Err(word * W::BITS + taken.trailing_zeros());
+                }
+                let swap_result = self.b[word].compare_exchange(
+                    val,
+                    val | mask,
+                    Ordering::SeqCst,
+                    Ordering::Relaxed,
+                );
+                if swap_result.is_ok() {
+                    if val | mask == W::ALLONES {
+                        self.mark_full(word);
                     }
-                    //  Return position of bit just set.
-                    return Some(word as usize * usize::BITS as usize + bit as usize);
+                    break;
                 }
-                //  Compare and swap failed. Some other thread updated this value.
-                log::warn!("Race condition in alloc_bit, retrying."); // should be very rare
+                //  Some other bit in this word changed. Try again.
+                let _ = self.alloc_retries.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    /// The words and bit masks covering bits `start..start+n`. `n` must be nonzero.
+    fn range_masks(start: usize, n: usize) -> impl Iterator<Item = (usize, W)> {
+        let end = start + n; // one past last bit
+        let (first_word, _) = Self::word_bit(start);
+        let (last_word, _) = Self::word_bit(end - 1);
+        (first_word..=last_word).map(move |word| (word, Self::word_mask(word, start, end)))
+    }
 
-                //  Have to try again
+    /// The bits of `word` which fall within bits `start..end`. The range must overlap the word.
+    fn word_mask(word: usize, start: usize, end: usize) -> W {
+        let word_start = word * W::BITS;
+        let lo = start.max(word_start) - word_start; // first bit in this word
+        let hi = end.min(word_start + W::BITS) - word_start; // one past last bit in this word
+        if hi - lo == W::BITS {
+            W::ALLONES
+        } else {
+            (W::ALLONES >> (W::BITS - (hi - lo))) << lo
+        }
+    }
+
+    /// Iterate over the allocated bits, in increasing order. Reserved bits are left out.
+    ///
+    /// Each word is read once, atomically, but the bitmap as a whole is not a snapshot
+    /// if other threads are allocating.
+    pub fn iter(&self) -> BitAllocIter<'_, W> {
+        BitAllocIter {
+            bit_alloc: self,
+            word: 0,
+            val: if self.b.is_empty() {
+                W::ZERO
+            } else {
+                self.b[0].load(Ordering::SeqCst)
+            },
+        }
+    }
+
+    /// Compact dump of the allocated bits, as runs.
+    pub fn dump(&self) -> BitAllocDump {
+        let mut runs: Vec<Range<usize>> = Vec::new();
+        for ix in self.iter() {
+            match runs.last_mut() {
+                Some(run) if run.end == ix => run.end += 1,
+                _ => runs.push(ix..ix + 1),
             }
         }
-        None // bitmap is full
+        BitAllocDump {
+            len: self.len(),
+            runs,
+        }
+    }
+
+    /// Bits allocated now, not counting reserved bits. A counter read, with no scan.
+    pub fn allocated(&self) -> usize {
+        self.allocated.load(Ordering::Relaxed)
+    }
+
+    /// Statistics snapshot. Does not block allocation, but scans the bitmap
+    /// up to the highest bit set for the fragmentation estimate.
+    /// For just the count, use `allocated`.
+    pub fn stats(&self) -> BitAllocStats {
+        BitAllocStats {
+            capacity: self.len(),
+            allocated: self.allocated(),
+            high_water: self.high_water.load(Ordering::Relaxed),
+            alloc_requests: self.alloc_count.load(Ordering::Relaxed),
+            words_searched: self.search_count.load(Ordering::Relaxed),
+            alloc_retries: self.alloc_retries.load(Ordering::Relaxed),
+            clear_retries: self.clear_retries.load(Ordering::Relaxed),
+            fragmentation: self.fragmentation(),
+        }
+    }
+
+    /// Fraction of the bits below the highest set bit which are free.
+    /// Scans the bitmap up to the highest bit ever set.
+    fn fragmentation(&self) -> f64 {
+        let top_words = self
+            .top
+            .load(Ordering::Relaxed)
+            .div_ceil(W::BITS)
+            .min(self.b.len());
+        let mut set = 0;
+        let mut span = 0;
+        for word in (0..top_words).rev() {
+            let val = self.b[word].load(Ordering::Relaxed);
+            if span == 0 {
+                if val == W::ZERO {
+                    continue; // above the highest set bit
+                }
+                span = word * W::BITS + W::BITS - val.leading_zeros();
+            }
+            set += val.count_ones();
+        }
+        if span > 0 {
+            span.saturating_sub(set) as f64 / span as f64
+        } else {
+            0.0
+        }
+    }
+
+    /// Statistics update for `n` bits allocated starting at `first`.
+    fn note_alloc(&self, first: usize, n: usize) {
+        let allocated = self.allocated.fetch_add(n, Ordering::Relaxed) + n;
+        let _ = self.high_water.fetch_max(allocated, Ordering::Relaxed);
+        let _ = self.top.fetch_max(first + n, Ordering::Relaxed);
+        self.retrack(first);
+    }
+
+    /// Tracker update for an allocation starting at bit `first`.
+    fn retrack(&self, first: usize) {
+        if let Some(tracking) = &self.tracking {
+            tracking
+                .tracker
+                .track(tracking.source, first as u64, tracking.label.as_deref(), 0);
+        }
+    }
+
+    /// Tracker update for the allocation starting at bit `first` freed.
+    /// Returns true if it was being tracked.
+    fn untrack(&self, first: usize) -> bool {
+        match &self.tracking {
+            Some(tracking) => tracking.tracker.untrack(tracking.source, first as u64),
+            None => false,
+        }
+    }
+
+    /// Statistics update for `n` bits freed.
+    fn note_free(&self, n: usize) {
+        let _ = self.allocated.fetch_sub(n, Ordering::Relaxed);
     }
 
     /// Which word and bit for an index
     fn word_bit(index: usize) -> (usize, usize) {
-        (index / WORDSIZE, index % WORDSIZE)
+        (index / W::BITS, index % W::BITS)
     }
 }
 
-impl Drop for BitAlloc {
+impl<W: BitWord> Drop for BitAlloc<W> {
     fn drop(&mut self) {
-        //  Performance statistics
+        //  With a tracker, the tracker reports. Otherwise, performance statistics.
+        if self.tracking.is_some() {
+            return;
+        }
+        let stats = self.stats();
         log::info!(
-            "BitAlloc stats: Size (bits) {},  {} allocations, {} words searched.",
-            self.len(),
-            self.alloc_count.load(Ordering::Relaxed),
-            self.search_count.load(Ordering::Relaxed)
+            "BitAlloc stats: Size (bits) {},  {} allocations, {} words searched, {} still allocated, high water {}.",
+            stats.capacity,
+            stats.alloc_requests,
+            stats.words_searched,
+            stats.allocated,
+            stats.high_water,
         );
     }
 }
@@ -150,12 +969,10 @@ fn test_bitalloc_basics() {
     let _ = SimpleLogger::init(LevelFilter::Info, Config::default()); // log to standard output
     /// Build up a list of bits
     fn bit_list(item: &BitAlloc) -> Vec<usize> {
-        (0..item.len())
-            .filter_map(|n| if item.get_bit(n) { Some(n) } else { None })
-            .collect()
+        (0..item.len()).filter(|&n| item.get_bit(n)).collect()
     }
     //  Try some basic operations
-    let bit_alloc = BitAlloc::new(100000);
+    let bit_alloc: BitAlloc = BitAlloc::new(100000);
     let v0 = bit_alloc.alloc_bit().unwrap();
     assert_eq!(v0, 0);
     let v1 = bit_alloc.alloc_bit().unwrap();
@@ -182,3 +999,468 @@ fn test_bitalloc_basics() {
         / bit_alloc.alloc_count.load(Ordering::Relaxed) as f64;
     assert!(efficiency_ratio < 1.1); // for this case, it should be small.
 }
+
+#[test]
+/// Range allocation, including runs which cross word boundaries.
+fn test_bitalloc_ranges() {
+    let bit_alloc: BitAlloc = BitAlloc::new(1000);
+    //  Simple run at the start.
+    let r0 = bit_alloc.alloc_range(4).unwrap();
+    assert_eq!(r0, 0);
+    //  Leave a hole of one bit, so a run of 2 has to skip it.
+    let v4 = bit_alloc.alloc_bit().unwrap();
+    assert_eq!(v4, 4);
+    let v5 = bit_alloc.alloc_bit().unwrap();
+    bit_alloc.clear_bit(v4).unwrap();
+    let r1 = bit_alloc.alloc_range(2).unwrap();
+    assert_eq!(r1, v5 + 1);
+    //  Run which crosses two word boundaries.
+    let r2 = bit_alloc.alloc_range(150).unwrap();
+    assert_eq!(r2, r1 + 2);
+    assert!((r2..r2 + 150).all(|n| bit_alloc.get_bit(n)));
+    assert!(!bit_alloc.get_bit(r2 + 150));
+    //  The hole is still usable by a single bit allocation.
+    assert_eq!(bit_alloc.alloc_bit().unwrap(), v4);
+    //  Free the long run and reuse it.
+    bit_alloc.free_range(r2, 150).unwrap();
+    assert!((r2..r2 + 150).all(|n| !bit_alloc.get_bit(n)));
+    assert_eq!(bit_alloc.alloc_range(100).unwrap(), r2);
+    //  Too big, and out of range frees.
+    assert!(bit_alloc.alloc_range(bit_alloc.len()).is_none());
+    assert!(bit_alloc.free_range(bit_alloc.len() - 1, 2).is_err());
+}
+
+#[test]
+/// A failed claim must leave no bits set.
+fn test_bitalloc_range_rollback() {
+    let bit_alloc: BitAlloc = BitAlloc::new(256);
+    //  Pretend another thread took bit 100 after the search saw it free.
+    let _ = bit_alloc.b[1].fetch_or(1 << (100 - u64::BITS), Ordering::SeqCst);
+    assert_eq!(bit_alloc.claim_range(60, 50), Err(100));
+    assert!((60..110).all(|n| n == 100 || !bit_alloc.get_bit(n)));
+    //  The next search goes around the conflict.
+    assert_eq!(bit_alloc.alloc_range(50).unwrap(), 0);
+    assert_eq!(bit_alloc.alloc_range(50).unwrap(), 50);
+    assert_eq!(bit_alloc.alloc_range(50).unwrap(), 101);
+}
+
+#[test]
+/// Summary levels must keep allocation cheap when the bitmap is nearly full and fragmented.
+fn test_bitalloc_summary() {
+    const SIZE: usize = 1 << 20; // about the size of a big descriptor table
+    /// Fill the map, then repeatedly free one low and one high bit and allocate both again.
+    /// Returns words searched per allocation during the fragmented phase.
+    fn churn(bit_alloc: &BitAlloc) -> f64 {
+        for _ in 0..bit_alloc.len() {
+            let _ = bit_alloc.alloc_bit().unwrap();
+        }
+        assert!(bit_alloc.alloc_bit().is_none()); // full
+        let allocs_before = bit_alloc.alloc_count.load(Ordering::Relaxed);
+        let searches_before = bit_alloc.search_count.load(Ordering::Relaxed);
+        let mut got = Vec::new();
+        for n in 0..1000 {
+            let low = (n * 7919) % (SIZE / 4);
+            let high = SIZE - 1 - (n * 104729) % (SIZE / 4);
+            bit_alloc.clear_bit(low).unwrap();
+            bit_alloc.clear_bit(high).unwrap();
+            got.push(bit_alloc.alloc_bit().unwrap());
+            got.push(bit_alloc.alloc_bit().unwrap());
+            assert_eq!(got[got.len() - 2..], [low, high]);
+        }
+        let allocs = bit_alloc.alloc_count.load(Ordering::Relaxed) - allocs_before;
+        let searches = bit_alloc.search_count.load(Ordering::Relaxed) - searches_before;
+        searches as f64 / allocs as f64
+    }
+    let linear = churn(&BitAlloc::new(SIZE));
+    let summarized = churn(&BitAlloc::new_with_summary(SIZE));
+    log::info!("Words searched per allocation: linear {linear:.1}, summary {summarized:.1}");
+    assert!(linear > 1000.0); // the bad case for a linear scan
+    assert!(summarized < 10.0); // about one word per level
+
+    //  Small sizes, including ones which are not a multiple of the word size.
+    for size in [1, 64, 65, 4096, 4097, 300000] {
+        let bit_alloc: BitAlloc = BitAlloc::new_with_summary(size);
+        let got: Vec<usize> = (0..bit_alloc.len())
+            .map(|_| bit_alloc.alloc_bit().unwrap())
+            .collect();
+        assert_eq!(got, (0..bit_alloc.len()).collect::<Vec<usize>>());
+        assert!(bit_alloc.alloc_bit().is_none());
+        bit_alloc.clear_bit(bit_alloc.len() / 2).unwrap();
+        assert_eq!(bit_alloc.alloc_bit(), Some(bit_alloc.len() / 2));
+    }
+}
+
+#[test]
+/// Statistics snapshot.
+fn test_bitalloc_stats() {
+    let bit_alloc: BitAlloc = BitAlloc::new(256);
+    assert_eq!(bit_alloc.stats().capacity, 256);
+    assert_eq!(bit_alloc.stats().fragmentation, 0.0);
+    let bits: Vec<usize> = (0..10).map(|_| bit_alloc.alloc_bit().unwrap()).collect();
+    let run = bit_alloc.alloc_range(70).unwrap();
+    let stats = bit_alloc.stats();
+    assert_eq!(stats.allocated, 80);
+    assert_eq!(stats.high_water, 80);
+    assert_eq!(stats.alloc_requests, 11);
+    assert!(stats.words_searched >= 11);
+    assert_eq!(stats.fragmentation, 0.0);
+    //  Free every other single bit. Half of the first ten are now holes.
+    for &bit in bits.iter().step_by(2) {
+        bit_alloc.clear_bit(bit).unwrap();
+    }
+    bit_alloc.free_range(run, 70).unwrap();
+    let stats = bit_alloc.stats();
+    assert_eq!(stats.allocated, 5);
+    assert_eq!(bit_alloc.allocated(), 5);
+    assert_eq!(stats.high_water, 80);
+    assert!((stats.fragmentation - 0.5).abs() < 1e-9);
+    assert_eq!(stats.alloc_retries, 0); // single thread, so no races
+    assert_eq!(stats.clear_retries, 0);
+    //  Nothing allocated, nothing fragmented.
+    for &bit in bits.iter().skip(1).step_by(2) {
+        bit_alloc.clear_bit(bit).unwrap();
+    }
+    assert_eq!(bit_alloc.stats().fragmentation, 0.0);
+}
+
+#[test]
+/// Double frees and bad indices are reported with typed errors.
+fn test_bitalloc_double_free() {
+    let bit_alloc: BitAlloc = BitAlloc::new(128);
+    let v0 = bit_alloc.alloc_bit().unwrap();
+    bit_alloc.clear_bit(v0).unwrap();
+    assert_eq!(
+        bit_alloc.clear_bit(v0),
+        Err(BitAllocError::AlreadyClear(v0))
+    );
+    assert_eq!(
+        bit_alloc.clear_bit(1000),
+        Err(BitAllocError::OutOfRange(1000))
+    );
+    assert_eq!(bit_alloc.stats().allocated, 0); // the failed clear did not count
+
+    //  Range with a hole in it. The rest is freed, and the hole is reported.
+    let run = bit_alloc.alloc_range(100).unwrap();
+    bit_alloc.clear_bit(run + 70).unwrap();
+    assert_eq!(
+        bit_alloc.free_range(run, 100),
+        Err(BitAllocError::AlreadyClear(run + 70))
+    );
+    assert!((run..run + 100).all(|n| !bit_alloc.get_bit(n)));
+    assert_eq!(bit_alloc.stats().allocated, 0);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "Double free")]
+/// In panic on misuse mode, a double free panics.
+fn test_bitalloc_double_free_panic() {
+    let mut bit_alloc: BitAlloc = BitAlloc::new(128);
+    bit_alloc.set_panic_on_misuse(true);
+    let v0 = bit_alloc.alloc_bit().unwrap();
+    bit_alloc.clear_bit(v0).unwrap();
+    let _ = bit_alloc.clear_bit(v0);
+}
+
+#[test]
+/// Reserved ranges and regions.
+fn test_bitalloc_regions() {
+    const STATIC: Range<usize> = 1..1000;
+    const STREAMING: Range<usize> = 1000..3000;
+    const UI: Range<usize> = 3000..3100;
+    let bit_alloc: BitAlloc = BitAlloc::new_with_summary(4096)
+        .with_reserved(&[0..1, 3100..3200])
+        .with_regions(&[STATIC, STREAMING, UI]);
+    //  Slot 0 is never handed out, and can't be freed.
+    assert_eq!(bit_alloc.alloc_bit(), Some(1));
+    assert_eq!(bit_alloc.clear_bit(0), Err(BitAllocError::Reserved(0)));
+    assert_eq!(
+        bit_alloc.free_range(3050, 100),
+        Err(BitAllocError::Reserved(3100))
+    );
+    assert!(bit_alloc.get_bit(0));
+    bit_alloc.clear_bit(1).unwrap();
+    //  Each region allocates from its own start.
+    assert_eq!(bit_alloc.alloc_bit_in(STATIC), Some(1));
+    assert_eq!(bit_alloc.alloc_bit_in(STREAMING), Some(1000));
+    assert_eq!(bit_alloc.alloc_bit_in(UI), Some(3000));
+    assert_eq!(bit_alloc.alloc_bit_in(STREAMING), Some(1001));
+    //  Fill the UI region. It must not spill into the reserved bits after it.
+    let ui: Vec<usize> = std::iter::from_fn(|| bit_alloc.alloc_bit_in(UI)).collect();
+    assert_eq!(ui, (3001..3100).collect::<Vec<usize>>());
+    //  Freeing in a region makes that slot the next one found there.
+    bit_alloc.clear_bit(3050).unwrap();
+    assert_eq!(bit_alloc.alloc_bit_in(UI), Some(3050));
+    //  A range which is not a declared region is searched from its start.
+    assert_eq!(bit_alloc.alloc_bit_in(1000..1010), Some(1002));
+    //  Reserved bits are not counted as allocated.
+    assert_eq!(bit_alloc.stats().allocated, 1 + 2 + 100 + 1);
+    //  Unrestricted allocation skips the reserved range.
+    while let Some(ix) = bit_alloc.alloc_bit() {
+        assert!(!(3100..3200).contains(&ix) && ix != 0);
+    }
+}
+
+#[test]
+/// Growth while other threads allocate.
+fn test_bitalloc_grow() {
+    use std::sync::Arc;
+    const CEILING: usize = 1 << 16;
+    for summary in [false, true] {
+        let bit_alloc: BitAlloc = BitAlloc::new_growable(100, CEILING);
+        let bit_alloc = Arc::new(if summary {
+            bit_alloc.with_summary()
+        } else {
+            bit_alloc
+        });
+        assert_eq!(bit_alloc.len(), 128); // rounded up to whole words
+        assert_eq!(bit_alloc.ceiling(), CEILING);
+        //  Fill the initial size. Plain allocation doesn't grow.
+        for _ in 0..128 {
+            let _ = bit_alloc.alloc_bit().unwrap();
+        }
+        assert!(bit_alloc.alloc_bit().is_none());
+        //  Several threads allocating and freeing while they force growth.
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let bit_alloc = Arc::clone(&bit_alloc);
+                std::thread::spawn(move || {
+                    let mut mine = Vec::new();
+                    for n in 0..(CEILING - 128) / 4 {
+                        mine.push(bit_alloc.alloc_bit_or_grow().unwrap());
+                        if n % 3 == 0 {
+                            let ix = mine.swap_remove(n % mine.len());
+                            bit_alloc.clear_bit(ix).unwrap();
+                        }
+                    }
+                    mine
+                })
+            })
+            .collect();
+        let mut all: Vec<usize> = workers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap())
+            .collect();
+        let count = all.len();
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), count); // nothing handed out twice
+        assert!(all.iter().all(|&ix| ix >= 128 && ix < bit_alloc.len()));
+        assert_eq!(bit_alloc.stats().allocated, count + 128);
+        //  Fill to the ceiling, and no further.
+        while bit_alloc.alloc_bit_or_grow().is_some() {}
+        assert_eq!(bit_alloc.len(), CEILING);
+        assert_eq!(bit_alloc.stats().allocated, CEILING);
+        assert_eq!(bit_alloc.grow(CEILING * 2), CEILING);
+    }
+    //  Reserved bits past the initial size are set as growth reaches them.
+    let bit_alloc: BitAlloc =
+        BitAlloc::new_growable(64, 1024).with_reserved(&[200..210, 1000..1024]);
+    let got: Vec<usize> = std::iter::from_fn(|| bit_alloc.alloc_bit_or_grow()).collect();
+    assert_eq!(got.len(), 1024 - 10 - 24);
+    assert!(got.iter().all(|ix| !(200..210).contains(ix) && *ix < 1000));
+    assert_eq!(bit_alloc.stats().allocated, got.len());
+}
+
+#[test]
+/// The iterator finds the same bits as `get_bit`, except reserved ones.
+fn test_bitalloc_iter() {
+    let bit_alloc: BitAlloc = BitAlloc::new(1000).with_reserved(&[0..1, 500..510]);
+    assert_eq!(bit_alloc.iter().count(), 0);
+    //  Scattered bits, some in runs across words, some freed again.
+    let mut held = Vec::new();
+    for n in 0..300 {
+        let ix = bit_alloc.alloc_bit().unwrap();
+        if n % 3 == 0 {
+            bit_alloc.clear_bit(ix).unwrap();
+        } else {
+            held.push(ix);
+        }
+    }
+    let _ = bit_alloc.alloc_range(100).unwrap();
+    bit_alloc.claim_range(999, 1).unwrap();
+    let from_get: Vec<usize> = (0..bit_alloc.len())
+        .filter(|&n| bit_alloc.get_bit(n))
+        .filter(|n| *n != 0 && !(500..510).contains(n))
+        .collect();
+    assert_eq!(bit_alloc.iter().collect::<Vec<usize>>(), from_get);
+    assert_eq!(from_get.len(), held.len() + 101);
+}
+
+#[test]
+/// The compact dump of allocated bits.
+fn test_bitalloc_iter_dump() {
+    let bit_alloc: BitAlloc = BitAlloc::new(1000).with_reserved(std::slice::from_ref(&(0..1)));
+    assert_eq!(bit_alloc.iter().count(), 0);
+    assert_eq!(bit_alloc.dump().to_string(), "1024 bits: ");
+    let run = bit_alloc.alloc_range(200).unwrap(); // crosses words
+    let single = bit_alloc.alloc_bit().unwrap();
+    let _ = bit_alloc.alloc_bit().unwrap();
+    bit_alloc.clear_bit(single).unwrap();
+    let last = bit_alloc.alloc_range(2).unwrap();
+    let far = 1023;
+    bit_alloc.claim_range(far, 1).unwrap();
+    let dump = bit_alloc.dump();
+    assert_eq!(
+        dump.runs,
+        [run..run + 200, single + 1..single + 4, far..far + 1]
+    );
+    assert_eq!(last, single + 2);
+    assert_eq!(dump.count(), 204);
+    assert_eq!(dump.to_string(), "1024 bits: 1..201,202..205,1023");
+    //  Serialization round trip.
+    let json = serde_json::to_string(&dump).unwrap();
+    let back: BitAllocDump = serde_json::from_str(&json).unwrap();
+    assert_eq!(back, dump);
+}
+
+#[test]
+/// Multi-threaded stress test. Checks that no bit is ever handed out to two owners at once.
+///
+/// Operation count defaults to something quick. For a real soak, set it in the environment:
+///
+///     BITALLOC_STRESS_OPS=10000000 cargo test --release -p alloc stress
+fn test_bitalloc_stress() {
+    use std::sync::Arc;
+    const THREADS: usize = 8;
+    const SIZE: usize = 4096;
+    /// Run the stress test with one word type.
+    fn stress<W: BitWord>(summary: bool, total_ops: usize) {
+        let bit_alloc: BitAlloc<W> = if summary {
+            BitAlloc::new_with_summary(SIZE)
+        } else {
+            BitAlloc::new(SIZE)
+        };
+        let bit_alloc = Arc::new(bit_alloc);
+        //  Who owns each bit. 0 means nobody.
+        let owners: Arc<Vec<AtomicUsize>> =
+            Arc::new((0..SIZE).map(|_| AtomicUsize::new(0)).collect());
+        let workers: Vec<_> = (1..=THREADS)
+            .map(|me| {
+                let bit_alloc = Arc::clone(&bit_alloc);
+                let owners = Arc::clone(&owners);
+                std::thread::spawn(move || {
+                    let take = |ix: usize| {
+                        let prev = owners[ix].swap(me, Ordering::SeqCst);
+                        assert_eq!(
+                            prev, 0,
+                            "bit {} handed out to thread {} while owned by {}",
+                            ix, me, prev
+                        );
+                    };
+                    let give = |ix: usize| {
+                        assert_eq!(owners[ix].swap(0, Ordering::SeqCst), me);
+                    };
+                    //  Held bits, as (start, length). Cheap pseudo-random choice of operation.
+                    let mut held: Vec<(usize, usize)> = Vec::new();
+                    let mut rand = me.wrapping_mul(0x9E3779B97F4A7C15);
+                    for _ in 0..total_ops / THREADS {
+                        rand ^= rand << 13;
+                        rand ^= rand >> 7;
+                        rand ^= rand << 17;
+                        if held.len() < 64 && rand % 4 != 0 {
+                            let n = if rand % 7 == 0 { 1 + rand % 70 } else { 1 };
+                            let got = if n == 1 {
+                                bit_alloc.alloc_bit()
+                            } else {
+                                bit_alloc.alloc_range(n)
+                            };
+                            if let Some(start) = got {
+                                (start..start + n).for_each(take);
+                                held.push((start, n));
+                            }
+                        } else if !held.is_empty() {
+                            let (start, n) = held.swap_remove(rand % held.len());
+                            (start..start + n).for_each(give);
+                            if n == 1 {
+                                bit_alloc.clear_bit(start).unwrap();
+                            } else {
+                                bit_alloc.free_range(start, n).unwrap();
+                            }
+                        }
+                    }
+                    held
+                })
+            })
+            .collect();
+        let held: usize = workers
+            .into_iter()
+            .map(|w| w.join().unwrap().iter().map(|&(_, n)| n).sum::<usize>())
+            .sum();
+        assert_eq!(bit_alloc.stats().allocated, held);
+        assert_eq!(bit_alloc.iter().count(), held);
+    }
+    let total_ops: usize = std::env::var("BITALLOC_STRESS_OPS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(200_000);
+    for summary in [false, true] {
+        stress::<u32>(summary, total_ops / 3);
+        stress::<u64>(summary, total_ops / 3);
+        #[cfg(not(loom))]
+        stress::<u128>(summary, total_ops / 3);
+    }
+}
+
+#[test]
+/// Slots and runs show up in the tracker under the allocator's label until freed.
+fn test_bitalloc_tracker() {
+    let tracker = Arc::new(AllocTracker::new());
+    let bit_alloc: BitAlloc = BitAlloc::new(256)
+        .with_reserved(std::slice::from_ref(&(0..1)))
+        .with_tracker(&tracker, Some("textures"));
+    let a = bit_alloc.alloc_bit().unwrap();
+    let run = bit_alloc.alloc_range(10).unwrap();
+    let b = bit_alloc.alloc_bit_in(100..200).unwrap();
+    assert_eq!(tracker.totals(Some("textures")).count, 3); // reserved bit not counted
+    bit_alloc.clear_bit(a).unwrap();
+    bit_alloc.free_range(run, 10).unwrap();
+    //  Double frees leave the records alone.
+    assert!(bit_alloc.clear_bit(a).is_err());
+    assert!(bit_alloc.free_range(run, 10).is_err());
+    let report = tracker.report();
+    assert_eq!(report.count(), 1);
+    assert_eq!(report.labels[0].allocations[0].index, b as u64);
+    assert_eq!(report.labels[0].allocations[0].source, "BitAlloc");
+    drop(bit_alloc); // leaked slot stays in the report
+    assert_eq!(tracker.live_count(), 1);
+}
+
+#[test]
+/// The same behavior from every word size.
+fn test_bitalloc_word_types() {
+    /// Fill, runs across word boundaries, and summary search, for one word type.
+    fn check<W: BitWord>() {
+        for summary in [false, true] {
+            let bit_alloc: BitAlloc<W> = BitAlloc::new(1000);
+            let bit_alloc = if summary {
+                bit_alloc.with_summary()
+            } else {
+                bit_alloc
+            };
+            assert_eq!(bit_alloc.len(), 1000usize.div_ceil(W::BITS) * W::BITS);
+            assert_eq!(bit_alloc.alloc_bit(), Some(0));
+            //  A run exactly one word long, starting mid-word, then one covering several words.
+            assert_eq!(bit_alloc.alloc_range(W::BITS), Some(1));
+            assert_eq!(bit_alloc.alloc_range(3 * W::BITS + 5), Some(W::BITS + 1));
+            let next = 4 * W::BITS + 6;
+            assert_eq!(bit_alloc.dump().runs, std::slice::from_ref(&(0..next)));
+            bit_alloc.free_range(1, W::BITS).unwrap();
+            assert_eq!(
+                bit_alloc.free_range(1, 1),
+                Err(BitAllocError::AlreadyClear(1))
+            );
+            //  Fill up, and check every bit came out once.
+            let mut got: Vec<usize> = std::iter::from_fn(|| bit_alloc.alloc_bit()).collect();
+            got.sort();
+            assert_eq!(got.len(), bit_alloc.len() - (next - W::BITS));
+            assert_eq!(bit_alloc.iter().count(), bit_alloc.len());
+            bit_alloc.clear_bit(bit_alloc.len() - 1).unwrap();
+            assert_eq!(bit_alloc.alloc_bit(), Some(bit_alloc.len() - 1));
+        }
+    }
+    check::<u32>();
+    check::<u64>();
+    #[cfg(not(loom))]
+    check::<u128>();
+}
@@ -0,0 +1,151 @@
+//! # Loom model checks for BitAlloc.
+//!
+//! Run with:
+//!
+//!     RUSTFLAGS="--cfg loom" cargo test -p alloc --release loom_
+//!
+//! Only the `loom_` tests are meaningful in that build. The ordinary tests
+//! use the allocator outside a loom model, which loom does not allow.
+//!
+//! Each model runs two threads doing one or two allocator calls each, on a
+//! bitmap prepared so that those calls collide. Loom tries every interleaving
+//! of their atomic operations, up to the preemption bound.
+use super::*;
+use loom::sync::Arc;
+use loom::thread;
+
+/// Models use the default u64 words.
+const WORDSIZE: usize = u64::BITS as usize;
+const WORDALLONES: u64 = u64::MAX;
+
+/// Run a model with a preemption bound, which keeps the search size reasonable.
+fn model<F: Fn() + Sync + Send + 'static>(f: F) {
+    let mut builder = loom::model::Builder::new();
+    builder.preemption_bound = Some(3);
+    builder.check(f);
+}
+
+/// Set bits `range` directly, to prepare a bitmap.
+fn preset(bit_alloc: &BitAlloc, range: Range<usize>) {
+    bit_alloc.claim_range(range.start, range.len()).unwrap();
+}
+
+/// All the free bits, taken by allocating until full.
+fn drain(bit_alloc: &BitAlloc) -> Vec<usize> {
+    let mut got: Vec<usize> = std::iter::from_fn(|| bit_alloc.alloc_bit()).collect();
+    got.sort();
+    got
+}
+
+#[test]
+/// Two threads racing for the last two free bits each get a different one.
+fn loom_alloc_unique() {
+    model(|| {
+        let bit_alloc: Arc<BitAlloc> = Arc::new(BitAlloc::new(WORDSIZE));
+        preset(&bit_alloc, 0..WORDSIZE - 2);
+        let other = {
+            let bit_alloc = Arc::clone(&bit_alloc);
+            thread::spawn(move || bit_alloc.alloc_bit())
+        };
+        let mine = bit_alloc.alloc_bit().unwrap();
+        let theirs = other.join().unwrap().unwrap();
+        assert_ne!(mine, theirs);
+        assert!(mine >= WORDSIZE - 2 && theirs >= WORDSIZE - 2);
+        assert!(bit_alloc.alloc_bit().is_none());
+    });
+}
+
+#[test]
+/// A clear racing with a search must not leave a free bit unreachable,
+/// whatever order the `fetch_min` in clear_bit and the `compare_exchange`
+/// in alloc_bit happen in.
+fn loom_search_pos() {
+    model(|| {
+        let bit_alloc: Arc<BitAlloc> = Arc::new(BitAlloc::new(WORDSIZE * 2));
+        preset(&bit_alloc, 0..WORDSIZE * 2);
+        bit_alloc.clear_bit(WORDSIZE + 6).unwrap(); // search will start in word 1
+        let _ = bit_alloc.search_pos.fetch_max(1, Ordering::SeqCst);
+        let other = {
+            let bit_alloc = Arc::clone(&bit_alloc);
+            thread::spawn(move || bit_alloc.clear_bit(5).unwrap())
+        };
+        let mine = bit_alloc.alloc_bit().unwrap();
+        other.join().unwrap();
+        //  Whichever bit we got, the other one must still be found.
+        let rest = drain(&bit_alloc);
+        assert_eq!(rest.len(), 1);
+        let mut both = vec![mine, rest[0]];
+        both.sort();
+        assert_eq!(both, [5, WORDSIZE + 6]);
+    });
+}
+
+#[test]
+/// A range claim crossing a word boundary, racing a single bit allocation.
+/// Neither may get a bit the other has, and a failed claim must roll back cleanly.
+fn loom_range_vs_bit() {
+    model(|| {
+        let bit_alloc: Arc<BitAlloc> = Arc::new(BitAlloc::new(WORDSIZE * 2));
+        preset(&bit_alloc, 0..WORDSIZE - 4);
+        let other = {
+            let bit_alloc = Arc::clone(&bit_alloc);
+            thread::spawn(move || bit_alloc.alloc_bit().unwrap())
+        };
+        let run = bit_alloc.alloc_range(8).unwrap();
+        let bit = other.join().unwrap();
+        assert!(!(run..run + 8).contains(&bit));
+        assert!((run..run + 8).all(|n| bit_alloc.get_bit(n)));
+        assert_eq!(bit_alloc.iter().count(), WORDSIZE - 4 + 8 + 1);
+    });
+}
+
+#[test]
+/// A word filling up, racing a clear in the same word, must not leave
+/// the summary claiming the word is full.
+fn loom_summary_full_race() {
+    model(|| {
+        let bit_alloc: Arc<BitAlloc> = Arc::new(BitAlloc::new_with_summary(WORDSIZE * 2));
+        preset(&bit_alloc, 0..WORDSIZE - 1);
+        let other = {
+            let bit_alloc = Arc::clone(&bit_alloc);
+            thread::spawn(move || bit_alloc.clear_bit(3).unwrap())
+        };
+        let mine = bit_alloc.alloc_bit().unwrap();
+        other.join().unwrap();
+        let word0 = bit_alloc.b[0].load(Ordering::SeqCst);
+        let summary0 = bit_alloc.summary[0][0].load(Ordering::SeqCst);
+        if word0 != WORDALLONES {
+            assert_eq!(
+                summary0 & 1,
+                0,
+                "summary says word 0 full, but it has free bits"
+            );
+        }
+        //  And the summary search alone finds the free bit in word 0.
+        if mine == WORDSIZE - 1 {
+            assert_eq!(bit_alloc.alloc_bit_summary(), Some(3));
+        }
+    });
+}
+
+#[test]
+/// Two threads each allocating and freeing. No index is ever held by both.
+fn loom_no_double_allocation() {
+    model(|| {
+        let bit_alloc: Arc<BitAlloc> = Arc::new(BitAlloc::new(WORDSIZE));
+        preset(&bit_alloc, 0..WORDSIZE - 2);
+        let other = {
+            let bit_alloc = Arc::clone(&bit_alloc);
+            thread::spawn(move || {
+                let a = bit_alloc.alloc_bit().unwrap();
+                bit_alloc.clear_bit(a).unwrap();
+                bit_alloc.alloc_bit().unwrap()
+            })
+        };
+        let mine = bit_alloc.alloc_bit().unwrap();
+        let theirs = other.join().unwrap();
+        assert_ne!(mine, theirs);
+        assert!(bit_alloc.get_bit(mine) && bit_alloc.get_bit(theirs));
+        assert!(bit_alloc.alloc_bit().is_none());
+    });
+}
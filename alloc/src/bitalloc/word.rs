@@ -0,0 +1,127 @@
+//! # Word -- the integer types a BitAlloc bitmap can be made of.
+//!
+//! A bitmap word is claimed with one compare and swap, so the word size sets
+//! how many bits one atomic operation can see. Wider words mean fewer words to
+//! search, but each operation costs more and contends over more bits.
+//! Which wins depends on the platform, so all three are available.
+//!
+//! u128 uses `portable_atomic::AtomicU128`. That is a real 128-bit compare and swap
+//! where the CPU has one (cmpxchg16b, casp) and a lock-based fallback where it doesn't.
+//! Loom has no 128-bit atomics, so u128 isn't available in loom builds.
+//!
+//! Animats
+//! November, 2024
+//!
+#![forbid(unsafe_code)]
+use crate::sync::{AtomicU32, AtomicU64, Ordering};
+use std::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, Not, Shl, Shr};
+
+/// An unsigned integer type usable as a bitmap word.
+pub trait BitWord:
+    Copy
+    + Eq
+    + std::fmt::Debug
+    + Send
+    + Sync
+    + 'static
+    + Not<Output = Self>
+    + BitAnd<Output = Self>
+    + BitOr<Output = Self>
+    + BitAndAssign
+    + BitOrAssign
+    + Shl<usize, Output = Self>
+    + Shr<usize, Output = Self>
+{
+    /// Bits in a word
+    const BITS: usize;
+    /// All bits clear
+    const ZERO: Self;
+    /// Lowest bit set
+    const ONE: Self;
+    /// All bits set
+    const ALLONES: Self;
+    /// The atomic version of this type
+    type Atomic: AtomicBitWord<Self>;
+    /// Number of trailing zero bits
+    fn trailing_zeros(self) -> usize;
+    /// Number of trailing one bits
+    fn trailing_ones(self) -> usize;
+    /// Number of leading zero bits
+    fn leading_zeros(self) -> usize;
+    /// Number of one bits
+    fn count_ones(self) -> usize;
+}
+
+/// The atomic operations BitAlloc needs on a bitmap word.
+pub trait AtomicBitWord<W>: Default + Send + Sync {
+    /// Usual new.
+    fn new(val: W) -> Self;
+    /// Atomic load
+    fn load(&self, order: Ordering) -> W;
+    /// Atomic compare and swap
+    fn compare_exchange(
+        &self,
+        current: W,
+        new: W,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<W, W>;
+    /// Atomic OR, returning the previous value
+    fn fetch_or(&self, val: W, order: Ordering) -> W;
+    /// Atomic AND, returning the previous value
+    fn fetch_and(&self, val: W, order: Ordering) -> W;
+}
+
+/// Implement the word traits for one integer type and its atomic.
+macro_rules! bit_word {
+    ($word:ty, $atomic:ty) => {
+        impl BitWord for $word {
+            const BITS: usize = <$word>::BITS as usize;
+            const ZERO: Self = 0;
+            const ONE: Self = 1;
+            const ALLONES: Self = <$word>::MAX;
+            type Atomic = $atomic;
+            fn trailing_zeros(self) -> usize {
+                <$word>::trailing_zeros(self) as usize
+            }
+            fn trailing_ones(self) -> usize {
+                <$word>::trailing_ones(self) as usize
+            }
+            fn leading_zeros(self) -> usize {
+                <$word>::leading_zeros(self) as usize
+            }
+            fn count_ones(self) -> usize {
+                <$word>::count_ones(self) as usize
+            }
+        }
+
+        impl AtomicBitWord<$word> for $atomic {
+            fn new(val: $word) -> Self {
+                <$atomic>::new(val)
+            }
+            fn load(&self, order: Ordering) -> $word {
+                <$atomic>::load(self, order)
+            }
+            fn compare_exchange(
+                &self,
+                current: $word,
+                new: $word,
+                success: Ordering,
+                failure: Ordering,
+            ) -> Result<$word, $word> {
+                <$atomic>::compare_exchange(self, current, new, success, failure)
+            }
+            fn fetch_or(&self, val: $word, order: Ordering) -> $word {
+                <$atomic>::fetch_or(self, val, order)
+            }
+            fn fetch_and(&self, val: $word, order: Ordering) -> $word {
+                <$atomic>::fetch_and(self, val, order)
+            }
+        }
+    };
+}
+
+bit_word!(u32, AtomicU32);
+bit_word!(u64, AtomicU64);
+#[cfg(not(loom))]
+bit_word!(u128, portable_atomic::AtomicU128);
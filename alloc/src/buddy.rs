@@ -0,0 +1,283 @@
+//! # Buddy -- buddy system suballocator for power-of-two textures.
+//!
+//! The memory block is a power of two in size. It is split in halves, and
+//! those halves in halves, down to a minimum block size. A request is rounded
+//! up to a power of two and gets a block of exactly that size. When a block
+//! and its buddy, the other half of the same parent, are both free, they merge
+//! back into the parent.
+//!
+//! Most Second Life textures are powers of two, 32x32 to 2048x2048, so for them
+//! the rounding costs nothing, and freed space always comes back as large blocks.
+//! Blocks are aligned to their own size, which covers any Vulkan alignment
+//! requirement up to that size. With a minimum block no smaller than
+//! `bufferImageGranularity`, linear and optimal resources never share a page.
+//!
+//! Not thread safe by itself. One of these per memory block, behind a lock.
+//!
+//! Animats
+//! November, 2024
+//!
+#![forbid(unsafe_code)]
+use crate::suballoc::{SubAllocError, SubAllocation, SubAllocator, Tiling};
+use std::collections::{BTreeSet, HashMap};
+
+/// Buddy system suballocator for one memory block.
+pub struct BuddyAlloc {
+    /// Size of the memory block. A power of two.
+    size: u64,
+    /// Smallest block handed out. A power of two.
+    min_block: u64,
+    /// Free blocks of each order, by offset. Order k blocks are `min_block << k` bytes.
+    /// Sorted, so the lowest block is used first, which keeps the high end free for big requests.
+    free: Vec<BTreeSet<u64>>,
+    /// Order and requested size of each live allocation, by offset
+    allocated: HashMap<u64, (u32, u64)>,
+    /// Bytes allocated, as requested
+    used: u64,
+    /// Bytes in allocated blocks
+    reserved: u64,
+}
+
+impl BuddyAlloc {
+    /// Usual new. `size` and `min_block` must be powers of two.
+    pub fn new(size: u64, min_block: u64) -> Self {
+        assert!(
+            size.is_power_of_two(),
+            "Buddy memory block size {} is not a power of two",
+            size
+        );
+        assert!(
+            min_block.is_power_of_two() && min_block <= size,
+            "Buddy minimum block {} is not a power of two no larger than {}",
+            min_block,
+            size
+        );
+        let max_order = (size / min_block).trailing_zeros();
+        let mut free = vec![BTreeSet::new(); max_order as usize + 1];
+        let _ = free[max_order as usize].insert(0); // the whole block
+        Self {
+            size,
+            min_block,
+            free,
+            allocated: HashMap::new(),
+            used: 0,
+            reserved: 0,
+        }
+    }
+
+    /// Highest order, the whole memory block.
+    fn max_order(&self) -> u32 {
+        (self.free.len() - 1) as u32
+    }
+
+    /// Size of a block of order `order`.
+    fn block_size(&self, order: u32) -> u64 {
+        self.min_block << order
+    }
+
+    /// Smallest order whose blocks hold `size` bytes at `alignment`.
+    fn order_for(&self, size: u64, alignment: u64) -> Option<u32> {
+        let need = size
+            .max(alignment)
+            .max(self.min_block)
+            .checked_next_power_of_two()?;
+        let order = (need / self.min_block).trailing_zeros();
+        (order <= self.max_order()).then_some(order)
+    }
+}
+
+impl SubAllocator for BuddyAlloc {
+    /// Tiling is not needed. Blocks are at least a granularity page apart if `min_block` is big enough.
+    fn alloc(&mut self, size: u64, alignment: u64, _tiling: Tiling) -> Option<SubAllocation> {
+        assert!(
+            alignment.is_power_of_two(),
+            "Buddy alignment {} is not a power of two",
+            alignment
+        );
+        if size == 0 {
+            return None;
+        }
+        let order = self.order_for(size, alignment)?;
+        //  Smallest free block big enough.
+        let mut found = (order..=self.max_order()).find(|&o| !self.free[o as usize].is_empty())?;
+        let offset = self.free[found as usize]
+            .pop_first()
+            .expect("Buddy free list empty");
+        //  Split down to size. The upper half of each split goes on the free list.
+        while found > order {
+            found -= 1;
+            let upper = offset + self.block_size(found);
+            let _ = self.free[found as usize].insert(upper);
+        }
+        let _ = self.allocated.insert(offset, (order, size));
+        self.used += size;
+        self.reserved += self.block_size(order);
+        Some(SubAllocation::new(offset, size, order))
+    }
+
+    /// Free an allocation. It merges with its buddy, and upwards, as far as possible.
+    fn free(&mut self, allocation: SubAllocation) -> Result<(), SubAllocError> {
+        let mut offset = allocation.offset();
+        let mut order = allocation.tag();
+        let recorded = match self.allocated.get(&offset) {
+            Some(&(o, recorded)) if o == order => recorded,
+            _ => return Err(SubAllocError::NotAllocated(offset)),
+        };
+        if recorded != allocation.size() {
+            return Err(SubAllocError::WrongSize {
+                offset,
+                size: allocation.size(),
+                recorded,
+            });
+        }
+        let _ = self.allocated.remove(&offset);
+        self.used -= allocation.size();
+        self.reserved -= self.block_size(order);
+        while order < self.max_order() {
+            let buddy = offset ^ self.block_size(order);
+            if !self.free[order as usize].remove(&buddy) {
+                break; // buddy in use, or split
+            }
+            offset = offset.min(buddy);
+            order += 1;
+        }
+        let _ = self.free[order as usize].insert(offset);
+        Ok(())
+    }
+
+    /// Size of the memory block being managed.
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Bytes allocated, as requested.
+    fn used(&self) -> u64 {
+        self.used
+    }
+
+    /// Bytes in allocated blocks. Requests are rounded up to a power of two.
+    fn reserved(&self) -> u64 {
+        self.reserved
+    }
+
+    /// Number of live allocations.
+    fn allocation_count(&self) -> usize {
+        self.allocated.len()
+    }
+
+    /// Size of the largest free block.
+    fn largest_free(&self) -> u64 {
+        (0..=self.max_order())
+            .rev()
+            .find(|&o| !self.free[o as usize].is_empty())
+            .map_or(0, |o| self.block_size(o))
+    }
+}
+
+#[test]
+/// Split, merge, alignment, and fragmentation reporting.
+fn test_buddy_basics() {
+    const MB: u64 = 1 << 20;
+    let mut buddy = BuddyAlloc::new(16 * MB, 4096);
+    let a = buddy.alloc(4 * MB, 1, Tiling::Optimal).unwrap();
+    let b = buddy.alloc(4096, 1, Tiling::Optimal).unwrap();
+    let c = buddy.alloc(3000, 1, Tiling::Linear).unwrap();
+    assert_eq!(a.offset(), 0);
+    assert_eq!(b.offset(), 4 * MB); // lowest free block after splitting
+    assert_eq!(c.offset(), 4 * MB + 4096);
+    assert_eq!(buddy.largest_free(), 8 * MB);
+    //  Alignment bigger than the size gets a block as big as the alignment.
+    let d = buddy.alloc(100, 64 * 1024, Tiling::Linear).unwrap();
+    assert_eq!(d.offset() % (64 * 1024), 0);
+    assert_eq!(buddy.reserved(), 4 * MB + 4096 + 4096 + 64 * 1024);
+    assert!(buddy.internal_fragmentation() > 0.0);
+    //  Too big.
+    assert!(buddy.alloc(16 * MB + 1, 1, Tiling::Linear).is_none());
+    assert!(buddy.alloc(16 * MB, 1, Tiling::Linear).is_none());
+    //  Free everything. It all merges back into one block.
+    for alloc in [c, a, d, b] {
+        buddy.free(alloc).unwrap();
+    }
+    assert!(buddy.is_empty());
+    assert_eq!(buddy.largest_free(), 16 * MB);
+    assert_eq!(buddy.reserved(), 0);
+    let all = buddy.alloc(16 * MB, 1, Tiling::Linear).unwrap();
+    assert_eq!(buddy.internal_fragmentation(), 0.0);
+    //  Not ours.
+    let mut other = BuddyAlloc::new(16 * MB, 4096);
+    assert_eq!(other.free(all), Err(SubAllocError::NotAllocated(0)));
+    //  Same offset and order in `other`, but a different size.
+    let smaller = other.alloc(16 * MB - 100, 1, Tiling::Linear).unwrap();
+    assert_eq!(
+        buddy.free(smaller),
+        Err(SubAllocError::WrongSize {
+            offset: 0,
+            size: 16 * MB - 100,
+            recorded: 16 * MB
+        })
+    );
+    assert_eq!(buddy.used(), 16 * MB);
+}
+
+#[test]
+/// Second Life style texture workload. Power-of-two textures from 32x32 to 2048x2048, RGBA8.
+/// Churn them, then see how many of the biggest textures still fit.
+fn test_buddy_texture_workload() {
+    use crate::tlsf::TlsfAlloc;
+    const SIZE: u64 = 256 << 20;
+    const BIGGEST: u64 = 2048 * 2048 * 4;
+    /// Run the workload. Returns how many of the biggest textures fit afterwards,
+    /// and internal fragmentation.
+    fn churn(suballoc: &mut dyn SubAllocator) -> (usize, f64) {
+        let mut live: Vec<SubAllocation> = Vec::new();
+        let mut rand: u64 = 0x2545F4914F6CDD1D;
+        for _ in 0..20000 {
+            rand ^= rand << 13;
+            rand ^= rand >> 7;
+            rand ^= rand << 17;
+            //  Mostly small textures, as in real content.
+            let side: u64 = 32 << (rand % 64).trailing_zeros().min(6);
+            let size = side * side * 4;
+            if !rand.is_multiple_of(5) {
+                if let Some(a) = suballoc.alloc(size, 4096, Tiling::Optimal) {
+                    live.push(a);
+                }
+            } else if !live.is_empty() {
+                let a = live.swap_remove((rand >> 8) as usize % live.len());
+                suballoc.free(a).unwrap();
+            }
+            if suballoc.used() > SIZE / 2 {
+                //  Memory pressure. Drop about half of what's loaded.
+                for n in (0..live.len()).rev() {
+                    if (rand >> (n % 64)) & 1 == 1 {
+                        suballoc.free(live.swap_remove(n)).unwrap();
+                    }
+                }
+            }
+        }
+        let internal = suballoc.internal_fragmentation();
+        live.extend(std::iter::from_fn(|| {
+            suballoc.alloc(BIGGEST, 4096, Tiling::Optimal)
+        }));
+        let biggest_fit = live.iter().filter(|a| a.size() == BIGGEST).count();
+        for a in live {
+            suballoc.free(a).unwrap();
+        }
+        assert!(suballoc.is_empty());
+        assert_eq!(suballoc.largest_free(), SIZE);
+        (biggest_fit, internal)
+    }
+    let mut buddy = BuddyAlloc::new(SIZE, 4096);
+    let mut tlsf = TlsfAlloc::new(SIZE, 4096);
+    let (buddy_fit, buddy_internal) = churn(&mut buddy);
+    let (tlsf_fit, _) = churn(&mut tlsf);
+    log::info!("2048x2048 textures which fit after churn: buddy {buddy_fit}, tlsf {tlsf_fit}");
+    assert_eq!(buddy_internal, 0.0); // power of two sizes waste nothing
+    assert!(buddy_fit >= tlsf_fit);
+    //  Mip chains are 4/3 the size of the base level, so they round up, and the waste shows.
+    let mip_chain = 1024 * 1024 * 4 * 4 / 3;
+    let mips = buddy.alloc(mip_chain, 4096, Tiling::Optimal).unwrap();
+    let expected = 1.0 - mip_chain as f64 / (8 << 20) as f64;
+    assert!((buddy.internal_fragmentation() - expected).abs() < 1e-9);
+    buddy.free(mips).unwrap();
+}
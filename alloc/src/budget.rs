@@ -0,0 +1,359 @@
+//! # Budget -- per-heap device memory budget tracking.
+//!
+//! Grabbing all of device memory starves everything else on the machine,
+//! and going over what the driver wants to give us makes it quietly page
+//! to system memory, with a big drop in frame rate. So usage of each memory
+//! heap is tracked against a budget, and callbacks fire when usage crosses
+//! a soft threshold (start evicting) or a hard one (stop loading).
+//!
+//! Usage comes from two places:
+//!
+//! - Our own accounting. Every block of device memory allocated or freed is reported here.
+//! - `VK_EXT_memory_budget`, when the device has it. The driver reports usage and
+//!   a budget for each heap, which reflects what other processes are doing.
+//!   Those reports lag, so allocations since the last report are added on top.
+//!
+//! Callbacks are edge triggered. They fire once when a heap's level changes,
+//! not on every allocation while it stays over. Updates come from any thread,
+//! and the callbacks run on whichever thread made the change.
+//!
+//! Animats
+//! November, 2024
+//!
+#![forbid(unsafe_code)]
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+/// Default budget, as a fraction of heap size, without a driver report.
+const DEFAULT_BUDGET_FRACTION: f64 = 0.8;
+/// Default soft threshold, as a fraction of the budget
+const DEFAULT_SOFT: f64 = 0.8;
+/// Default hard threshold, as a fraction of the budget
+const DEFAULT_HARD: f64 = 0.95;
+
+/// How full a heap is, relative to its budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BudgetLevel {
+    /// Under the soft threshold
+    Normal,
+    /// Over the soft threshold. Time to evict.
+    Soft,
+    /// Over the hard threshold. Don't load anything more.
+    Hard,
+}
+
+impl BudgetLevel {
+    /// From the stored form.
+    fn from_u8(val: u8) -> Self {
+        match val {
+            0 => BudgetLevel::Normal,
+            1 => BudgetLevel::Soft,
+            _ => BudgetLevel::Hard,
+        }
+    }
+}
+
+/// A heap changed level. Passed to the callbacks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BudgetEvent {
+    /// Memory heap index
+    pub heap: usize,
+    /// Level before
+    pub old_level: BudgetLevel,
+    /// Level now
+    pub level: BudgetLevel,
+    /// Usage, in bytes
+    pub usage: u64,
+    /// Budget, in bytes
+    pub budget: u64,
+}
+
+/// Callback for level changes.
+pub type BudgetCallback = Box<dyn Fn(&BudgetEvent) + Send + Sync>;
+
+/// Usage and budget for one memory heap.
+struct HeapBudget {
+    /// Size of the heap, from `VkMemoryHeap::size`
+    size: u64,
+    /// Our own budget for this heap
+    configured_budget: AtomicU64,
+    /// Our own accounting of bytes allocated
+    own_usage: AtomicU64,
+    /// True once the driver has reported on this heap
+    has_driver_report: AtomicBool,
+    /// Driver's usage at the last report
+    driver_usage: AtomicU64,
+    /// Driver's budget at the last report
+    driver_budget: AtomicU64,
+    /// Our own usage at the last report
+    own_usage_at_report: AtomicU64,
+    /// Current level, as u8
+    level: AtomicU8,
+}
+
+/// Budget tracker for all the memory heaps of a device.
+pub struct BudgetTracker {
+    /// One per heap
+    heaps: Vec<HeapBudget>,
+    /// Soft threshold, as a fraction of the budget
+    soft: f64,
+    /// Hard threshold, as a fraction of the budget
+    hard: f64,
+    /// Called on level changes
+    callbacks: Vec<BudgetCallback>,
+}
+
+impl BudgetTracker {
+    /// Usual new. One entry per memory heap, with the heap sizes from `VkPhysicalDeviceMemoryProperties`.
+    pub fn new(heap_sizes: &[u64]) -> Self {
+        let heaps = heap_sizes
+            .iter()
+            .map(|&size| HeapBudget {
+                size,
+                configured_budget: AtomicU64::new((size as f64 * DEFAULT_BUDGET_FRACTION) as u64),
+                own_usage: AtomicU64::new(0),
+                has_driver_report: AtomicBool::new(false),
+                driver_usage: AtomicU64::new(0),
+                driver_budget: AtomicU64::new(0),
+                own_usage_at_report: AtomicU64::new(0),
+                level: AtomicU8::new(BudgetLevel::Normal as u8),
+            })
+            .collect();
+        Self {
+            heaps,
+            soft: DEFAULT_SOFT,
+            hard: DEFAULT_HARD,
+            callbacks: Vec::new(),
+        }
+    }
+
+    /// Set the soft and hard thresholds, as fractions of the budget.
+    pub fn with_thresholds(mut self, soft: f64, hard: f64) -> Self {
+        assert!(
+            0.0 < soft && soft <= hard,
+            "Budget thresholds soft {} and hard {} out of order",
+            soft,
+            hard
+        );
+        self.soft = soft;
+        self.hard = hard;
+        self
+    }
+
+    /// Add a callback for level changes.
+    pub fn with_callback(
+        mut self,
+        callback: impl Fn(&BudgetEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.callbacks.push(Box::new(callback));
+        self
+    }
+
+    /// Number of memory heaps.
+    pub fn heap_count(&self) -> usize {
+        self.heaps.len()
+    }
+
+    /// Set our own budget for a heap, in bytes. The driver's budget, if lower, still wins.
+    pub fn set_budget(&self, heap: usize, budget: u64) {
+        self.heaps[heap]
+            .configured_budget
+            .store(budget, Ordering::SeqCst);
+        self.update_level(heap);
+    }
+
+    /// Device memory was allocated from a heap.
+    pub fn allocated(&self, heap: usize, bytes: u64) {
+        let _ = self.heaps[heap]
+            .own_usage
+            .fetch_add(bytes, Ordering::SeqCst);
+        self.update_level(heap);
+    }
+
+    /// Device memory was freed back to a heap. Freeing more than was allocated stops at zero.
+    pub fn freed(&self, heap: usize, bytes: u64) {
+        //  Saturating, so an over-free can't wrap the usage to near u64::MAX.
+        let prev = self.heaps[heap]
+            .own_usage
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |usage| {
+                Some(usage.saturating_sub(bytes))
+            })
+            .unwrap_or_default();
+        debug_assert!(
+            bytes <= prev,
+            "Freed {} bytes from heap {} with only {} allocated",
+            bytes,
+            heap,
+            prev
+        );
+        self.update_level(heap);
+    }
+
+    /// A report from `VK_EXT_memory_budget`. These are the `heapUsage` and `heapBudget`
+    /// arrays of `VkPhysicalDeviceMemoryBudgetPropertiesEXT`. Entries past the heap count are ignored.
+    pub fn driver_report(&self, heap_usage: &[u64], heap_budget: &[u64]) {
+        for (heap, (&usage, &budget)) in heap_usage
+            .iter()
+            .zip(heap_budget)
+            .enumerate()
+            .take(self.heaps.len())
+        {
+            let h = &self.heaps[heap];
+            h.driver_usage.store(usage, Ordering::SeqCst);
+            h.driver_budget.store(budget, Ordering::SeqCst);
+            h.own_usage_at_report
+                .store(h.own_usage.load(Ordering::SeqCst), Ordering::SeqCst);
+            h.has_driver_report.store(true, Ordering::SeqCst);
+            self.update_level(heap);
+        }
+    }
+
+    /// Bytes in use on a heap. From the driver if it has reported, plus our allocations since.
+    pub fn usage(&self, heap: usize) -> u64 {
+        let h = &self.heaps[heap];
+        let own = h.own_usage.load(Ordering::SeqCst);
+        if h.has_driver_report.load(Ordering::SeqCst) {
+            let since = own as i128 - h.own_usage_at_report.load(Ordering::SeqCst) as i128;
+            (h.driver_usage.load(Ordering::SeqCst) as i128 + since).max(0) as u64
+        } else {
+            own
+        }
+    }
+
+    /// Budget for a heap, in bytes. Ours, or the driver's if that is lower.
+    pub fn budget(&self, heap: usize) -> u64 {
+        let h = &self.heaps[heap];
+        let configured = h.configured_budget.load(Ordering::SeqCst);
+        if h.has_driver_report.load(Ordering::SeqCst) {
+            configured.min(h.driver_budget.load(Ordering::SeqCst))
+        } else {
+            configured
+        }
+    }
+
+    /// Size of a heap.
+    pub fn heap_size(&self, heap: usize) -> u64 {
+        self.heaps[heap].size
+    }
+
+    /// Current level of a heap.
+    pub fn level(&self, heap: usize) -> BudgetLevel {
+        BudgetLevel::from_u8(self.heaps[heap].level.load(Ordering::SeqCst))
+    }
+
+    /// True if `bytes` more can be allocated from a heap without going over the hard threshold.
+    pub fn fits(&self, heap: usize, bytes: u64) -> bool {
+        (self.usage(heap).saturating_add(bytes)) as f64 <= self.budget(heap) as f64 * self.hard
+    }
+
+    /// Recompute a heap's level, and call the callbacks if it changed.
+    ///
+    /// Two threads updating at once can store their levels in either order,
+    /// so the level can be one update stale. The next update corrects it,
+    /// and each event still describes a real change from the level stored before.
+    fn update_level(&self, heap: usize) {
+        let usage = self.usage(heap);
+        let budget = self.budget(heap);
+        let level = if usage as f64 > budget as f64 * self.hard {
+            BudgetLevel::Hard
+        } else if usage as f64 > budget as f64 * self.soft {
+            BudgetLevel::Soft
+        } else {
+            BudgetLevel::Normal
+        };
+        let old_level =
+            BudgetLevel::from_u8(self.heaps[heap].level.swap(level as u8, Ordering::SeqCst));
+        if old_level != level {
+            let event = BudgetEvent {
+                heap,
+                old_level,
+                level,
+                usage,
+                budget,
+            };
+            log::info!(
+                "Memory heap {} budget level {:?} -> {:?}, {} of {} bytes",
+                heap,
+                old_level,
+                level,
+                usage,
+                budget
+            );
+            for callback in &self.callbacks {
+                callback(&event);
+            }
+        }
+    }
+}
+
+#[test]
+/// Own accounting crosses thresholds both ways. Callbacks fire once per crossing.
+fn test_budget_own_accounting() {
+    use std::sync::{Arc, Mutex};
+    const MB: u64 = 1 << 20;
+    let events: Arc<Mutex<Vec<BudgetEvent>>> = Arc::new(Mutex::new(Vec::new()));
+    let tracker = {
+        let events = Arc::clone(&events);
+        BudgetTracker::new(&[1000 * MB, 200 * MB])
+            .with_callback(move |event| events.lock().unwrap().push(*event))
+    };
+    assert_eq!(tracker.budget(0), 800 * MB); // default fraction of heap size
+
+    //  Up to just under soft, 0.8 of the budget.
+    tracker.allocated(0, 640 * MB);
+    assert_eq!(tracker.level(0), BudgetLevel::Normal);
+    //  Over soft. Staying over doesn't fire again.
+    tracker.allocated(0, 10 * MB);
+    tracker.allocated(0, 10 * MB);
+    assert_eq!(tracker.level(0), BudgetLevel::Soft);
+    //  Over hard, at 0.95 of the budget.
+    tracker.allocated(0, 110 * MB);
+    assert_eq!(tracker.level(0), BudgetLevel::Hard);
+    assert!(!tracker.fits(0, 1));
+    assert!(tracker.fits(1, 100 * MB)); // other heap unaffected
+
+    //  Back down.
+    tracker.freed(0, 500 * MB);
+    assert_eq!(tracker.level(0), BudgetLevel::Normal);
+    let levels: Vec<(BudgetLevel, BudgetLevel)> = events
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|e| (e.old_level, e.level))
+        .collect();
+    assert_eq!(
+        levels,
+        [
+            (BudgetLevel::Normal, BudgetLevel::Soft),
+            (BudgetLevel::Soft, BudgetLevel::Hard),
+            (BudgetLevel::Hard, BudgetLevel::Normal)
+        ]
+    );
+    //  Lowering our own budget can push a heap over without any allocation.
+    tracker.set_budget(0, 200 * MB);
+    assert_eq!(tracker.level(0), BudgetLevel::Hard);
+}
+
+#[test]
+/// Driver reports take over usage and can lower the budget.
+fn test_budget_driver_report() {
+    const MB: u64 = 1 << 20;
+    let tracker = BudgetTracker::new(&[1000 * MB]).with_thresholds(0.5, 0.9);
+    tracker.allocated(0, 100 * MB);
+    assert_eq!(tracker.usage(0), 100 * MB);
+    //  Driver says more is in use than we know of, and other programs have taken space.
+    //  Arrays are VK_MAX_MEMORY_HEAPS long. Extra entries are ignored.
+    let mut usage = [0; 16];
+    let mut budget = [0; 16];
+    usage[0] = 150 * MB;
+    budget[0] = 400 * MB;
+    tracker.driver_report(&usage, &budget);
+    assert_eq!(tracker.usage(0), 150 * MB);
+    assert_eq!(tracker.budget(0), 400 * MB); // lower than ours, so it wins
+    assert_eq!(tracker.level(0), BudgetLevel::Normal);
+    //  Allocations after the report count on top of it.
+    tracker.allocated(0, 100 * MB);
+    assert_eq!(tracker.usage(0), 250 * MB);
+    assert_eq!(tracker.level(0), BudgetLevel::Soft);
+    tracker.freed(0, 200 * MB);
+    assert_eq!(tracker.usage(0), 50 * MB);
+}
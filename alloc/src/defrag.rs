@@ -0,0 +1,423 @@
+//! # Defrag -- compaction planning for suballocated device memory blocks.
+//!
+//! After hours of textures coming and going, a memory block ends up with
+//! live allocations scattered among holes too small to use. The planner
+//! takes the live allocations in one block and slides them down towards
+//! offset 0, in offset order, keeping their alignment and the
+//! `bufferImageGranularity` separation between linear and optimal resources.
+//!
+//! The result is a list of moves, one per allocation which changes place,
+//! and the GPU copies which carry them out, grouped into passes. Copies in
+//! one pass touch no common bytes, so they can go in one `vkCmdCopyBuffer`.
+//! There must be a transfer barrier between passes. A move which overlaps
+//! its own old position is copied in pieces, one pass each, so it is only
+//! done if that takes no more than `MAX_PIECES` pieces.
+//!
+//! Planning is pure CPU code, and the same input in any order gives the same plan.
+//! Once the copies have executed, the plan goes through `DefragQueue` and is
+//! applied at a frame boundary, when the frame which did the copies has retired.
+//!
+//! Animats
+//! November, 2024
+//!
+#![forbid(unsafe_code)]
+use crate::suballoc::Tiling;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// Most pieces a self-overlapping move is split into. Moves needing more stay put.
+pub const MAX_PIECES: u64 = 8;
+
+/// A live allocation, as input to the planner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DefragItem {
+    /// Caller's identifier for the allocation, such as a texture handle
+    pub id: u64,
+    /// Offset in the block
+    pub offset: u64,
+    /// Size in bytes
+    pub size: u64,
+    /// Required alignment, a power of two
+    pub alignment: u64,
+    /// Linear or optimal, for `bufferImageGranularity`
+    pub tiling: Tiling,
+}
+
+/// An allocation which changes place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DefragMove {
+    /// Caller's identifier for the allocation
+    pub id: u64,
+    /// Where it was
+    pub src_offset: u64,
+    /// Where it goes
+    pub dst_offset: u64,
+    /// Size in bytes
+    pub size: u64,
+}
+
+/// One GPU copy, a `VkBufferCopy` region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DefragCopy {
+    /// Offset copied from
+    pub src_offset: u64,
+    /// Offset copied to
+    pub dst_offset: u64,
+    /// Bytes to copy
+    pub size: u64,
+}
+
+impl DefragCopy {
+    /// Source range.
+    fn src(&self) -> std::ops::Range<u64> {
+        self.src_offset..self.src_offset + self.size
+    }
+
+    /// Destination range.
+    fn dst(&self) -> std::ops::Range<u64> {
+        self.dst_offset..self.dst_offset + self.size
+    }
+}
+
+/// True if two ranges share a byte.
+fn overlaps(a: &std::ops::Range<u64>, b: &std::ops::Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// Compaction of one memory block.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DefragPlan {
+    /// Device memory handle of the block, if known
+    memory: u64,
+    /// Moves, in increasing offset order. Apply them in this order.
+    moves: Vec<DefragMove>,
+    /// GPU copies, in passes
+    passes: Vec<Vec<DefragCopy>>,
+    /// End of the highest allocation after compaction
+    end: u64,
+}
+
+impl DefragPlan {
+    /// Plan compaction of the live allocations in a block.
+    /// `granularity` is the device's `bufferImageGranularity`.
+    pub fn new(items: &[DefragItem], granularity: u64) -> Self {
+        assert!(
+            granularity.is_power_of_two(),
+            "bufferImageGranularity {} is not a power of two",
+            granularity
+        );
+        let mut items = items.to_vec();
+        items.sort_by_key(|item| (item.offset, item.id));
+        let mut plan = Self::default();
+        //  Previous allocation's tiling and last byte, after compaction.
+        let mut prev: Option<(Tiling, u64)> = None;
+        for item in items {
+            let mut dst = plan.end.next_multiple_of(item.alignment);
+            if let Some((tiling, last)) = prev {
+                if tiling != item.tiling && dst / granularity == last / granularity {
+                    //  Different kind on the same granularity page. Go to the next page.
+                    dst = (last / granularity + 1) * granularity;
+                    dst = dst.next_multiple_of(item.alignment);
+                }
+            }
+            //  The old layout was valid, so everything below has only moved down. dst can't be higher.
+            debug_assert!(dst <= item.offset);
+            let shift = item.offset - dst;
+            if shift > 0 && shift.saturating_mul(MAX_PIECES) >= item.size {
+                plan.add_move(DefragMove {
+                    id: item.id,
+                    src_offset: item.offset,
+                    dst_offset: dst,
+                    size: item.size,
+                });
+            } else {
+                dst = item.offset; // in place
+            }
+            plan.end = dst + item.size;
+            prev = Some((item.tiling, plan.end - 1));
+        }
+        plan
+    }
+
+    /// Set the device memory handle of the block, for the caller's reference.
+    pub fn with_memory(mut self, memory: u64) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    /// Device memory handle of the block.
+    pub fn memory(&self) -> u64 {
+        self.memory
+    }
+
+    /// The moves, in the order they must be applied.
+    pub fn moves(&self) -> &[DefragMove] {
+        &self.moves
+    }
+
+    /// The GPU copies. A transfer barrier is needed between passes.
+    pub fn passes(&self) -> &[Vec<DefragCopy>] {
+        &self.passes
+    }
+
+    /// End of the highest allocation after compaction. Everything above is free.
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// Total bytes copied.
+    pub fn bytes_moved(&self) -> u64 {
+        self.moves.iter().map(|m| m.size).sum()
+    }
+
+    /// True if nothing moves.
+    pub fn is_empty(&self) -> bool {
+        self.moves.is_empty()
+    }
+
+    /// Add a move, and the copies for it.
+    fn add_move(&mut self, mv: DefragMove) {
+        let shift = mv.src_offset - mv.dst_offset;
+        //  If the move overlaps itself, copy in pieces no bigger than the shift, lowest first.
+        //  Each piece writes where the one before it read, so needs a pass of its own.
+        let piece = mv.size.min(shift);
+        let mut done = 0;
+        while done < mv.size {
+            let size = piece.min(mv.size - done);
+            self.add_copy(DefragCopy {
+                src_offset: mv.src_offset + done,
+                dst_offset: mv.dst_offset + done,
+                size,
+            });
+            done += size;
+        }
+        self.moves.push(mv);
+    }
+
+    /// Add a copy to the last pass, or a new pass if it conflicts with a copy there.
+    /// Destinations never overlap each other, nor do sources, so only writes over reads conflict.
+    fn add_copy(&mut self, copy: DefragCopy) {
+        let conflict = self.passes.last().is_none_or(|pass| {
+            pass.iter()
+                .any(|c| overlaps(&c.src(), &copy.dst()) || overlaps(&c.dst(), &copy.src()))
+        });
+        if conflict {
+            self.passes.push(Vec::new());
+        }
+        self.passes
+            .last_mut()
+            .expect("Defrag pass list empty")
+            .push(copy);
+    }
+}
+
+/// Plans whose GPU copies have been submitted, waiting for their frame to retire.
+pub struct DefragQueue {
+    /// Plans and the frame epoch which did their copies, oldest first.
+    /// Only the render thread touches this, so the lock is never contended.
+    pending: Mutex<VecDeque<(u64, DefragPlan)>>,
+}
+
+impl DefragQueue {
+    /// Usual new.
+    pub fn new() -> Self {
+        Self {
+            pending: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// The copies for `plan` were recorded in frame `epoch`.
+    pub fn submitted(&self, plan: DefragPlan, epoch: u64) {
+        self.pending
+            .lock()
+            .expect("Defrag queue poisoned")
+            .push_back((epoch, plan));
+    }
+
+    /// Number of plans waiting.
+    pub fn len(&self) -> usize {
+        self.pending.lock().expect("Defrag queue poisoned").len()
+    }
+
+    /// True if no plans are waiting.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The GPU has finished frame `retired_epoch`. Each plan whose copies are done
+    /// goes to `apply`, which must move the owning handles and rewrite their descriptor slots
+    /// before anything else uses them. Render thread only, at a frame boundary.
+    /// Returns the number of plans applied.
+    pub fn frame_retired(&self, retired_epoch: u64, mut apply: impl FnMut(DefragPlan)) -> usize {
+        let mut done = Vec::new();
+        {
+            let mut pending = self.pending.lock().expect("Defrag queue poisoned");
+            while pending
+                .front()
+                .is_some_and(|(epoch, _)| *epoch <= retired_epoch)
+            {
+                done.extend(pending.pop_front().map(|(_, plan)| plan));
+            }
+        }
+        //  Called without the lock held, so `apply` can submit more.
+        let count = done.len();
+        for plan in done {
+            apply(plan);
+        }
+        count
+    }
+}
+
+impl Default for DefragQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+/// Plans are deterministic, respect placement rules, and the copies produce the compacted layout.
+fn test_defrag_plan() {
+    const GRAN: u64 = 1024;
+    /// Apply the plan to the items, and run its copies on a model of the block.
+    /// Within a pass all reads are done first, which is fine only if no copy
+    /// in the pass writes what another reads. Returns the new layout.
+    fn run(items: &[DefragItem], plan: &DefragPlan) -> Vec<DefragItem> {
+        let mut layout = items.to_vec();
+        for mv in plan.moves() {
+            let item = layout.iter_mut().find(|i| i.id == mv.id).unwrap();
+            assert_eq!((item.offset, item.size), (mv.src_offset, mv.size));
+            assert!(mv.dst_offset < mv.src_offset);
+            item.offset = mv.dst_offset;
+        }
+        assert!(plan
+            .moves()
+            .windows(2)
+            .all(|w| w[0].src_offset < w[1].src_offset));
+        let mut memory: Vec<u64> = vec![0; 64 * 1024];
+        for item in items {
+            memory[item.offset as usize..(item.offset + item.size) as usize].fill(item.id);
+        }
+        for pass in plan.passes() {
+            let data: Vec<Vec<u64>> = pass
+                .iter()
+                .map(|c| memory[c.src_offset as usize..(c.src_offset + c.size) as usize].to_vec())
+                .collect();
+            for (c, d) in pass.iter().zip(data) {
+                memory[c.dst_offset as usize..(c.dst_offset + c.size) as usize].copy_from_slice(&d);
+            }
+        }
+        for item in &layout {
+            assert!(
+                memory[item.offset as usize..(item.offset + item.size) as usize]
+                    .iter()
+                    .all(|&v| v == item.id)
+            );
+        }
+        layout
+    }
+    let items = [
+        DefragItem {
+            id: 1,
+            offset: 4096,
+            size: 3000,
+            alignment: 256,
+            tiling: Tiling::Linear,
+        },
+        DefragItem {
+            id: 2,
+            offset: 9000,
+            size: 500,
+            alignment: 8,
+            tiling: Tiling::Linear,
+        },
+        DefragItem {
+            id: 3,
+            offset: 20480,
+            size: 8192,
+            alignment: 4096,
+            tiling: Tiling::Optimal,
+        },
+        DefragItem {
+            id: 4,
+            offset: 30000,
+            size: 1000,
+            alignment: 16,
+            tiling: Tiling::Optimal,
+        },
+        DefragItem {
+            id: 5,
+            offset: 40000,
+            size: 100,
+            alignment: 4,
+            tiling: Tiling::Linear,
+        },
+    ];
+    let plan = DefragPlan::new(&items, GRAN);
+    //  Input order doesn't matter.
+    let mut reversed = items;
+    reversed.reverse();
+    assert_eq!(plan, DefragPlan::new(&reversed, GRAN));
+    let layout = run(&items, &plan);
+    let offsets: Vec<u64> = layout.iter().map(|i| i.offset).collect();
+    assert_eq!(offsets, [0, 3000, 4096, 12288, 13312]); // 5 goes to the next page after 4
+    assert_eq!(plan.end(), 13312 + 100);
+    assert_eq!(plan.bytes_moved(), 3000 + 500 + 8192 + 1000 + 100);
+    for w in layout.windows(2) {
+        assert!(w[0].offset + w[0].size <= w[1].offset);
+        if w[0].tiling != w[1].tiling {
+            assert!((w[0].offset + w[0].size - 1) / GRAN < w[1].offset / GRAN);
+        }
+    }
+    assert!(layout.iter().all(|i| i.offset % i.alignment == 0));
+    //  A move which overlaps itself is copied in pieces, one pass each.
+    let sliding = [DefragItem {
+        id: 6,
+        offset: 1000,
+        size: 4000,
+        alignment: 8,
+        tiling: Tiling::Linear,
+    }];
+    let plan = DefragPlan::new(&sliding, GRAN);
+    assert_eq!(plan.passes().len(), 4);
+    assert_eq!(run(&sliding, &plan)[0].offset, 0);
+    //  A move which would take too many pieces stays put.
+    let tight = [DefragItem {
+        id: 7,
+        offset: 64,
+        size: 4096,
+        alignment: 64,
+        tiling: Tiling::Linear,
+    }];
+    assert!(DefragPlan::new(&tight, GRAN).is_empty());
+}
+
+#[test]
+/// Plans wait in the queue until the frame which did their copies retires.
+fn test_defrag_queue() {
+    let queue = DefragQueue::new();
+    let item = DefragItem {
+        id: 1,
+        offset: 8192,
+        size: 4096,
+        alignment: 4096,
+        tiling: Tiling::Linear,
+    };
+    queue.submitted(DefragPlan::new(&[item], 1).with_memory(10), 5);
+    queue.submitted(DefragPlan::new(&[item], 1).with_memory(11), 6);
+    let mut applied = Vec::new();
+    assert_eq!(
+        queue.frame_retired(4, |plan| applied.push(plan.memory())),
+        0
+    );
+    assert_eq!(
+        queue.frame_retired(5, |plan| applied.push(plan.memory())),
+        1
+    );
+    assert_eq!(queue.len(), 1);
+    assert_eq!(
+        queue.frame_retired(9, |plan| applied.push(plan.memory())),
+        1
+    );
+    assert_eq!(applied, [10, 11]);
+    assert!(queue.is_empty());
+}
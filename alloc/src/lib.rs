@@ -4,4 +4,33 @@
 //! Animats
 //! November, 2024
 //!
-mod bitalloc;
+pub mod bitalloc;
+pub mod buddy;
+pub mod budget;
+pub mod defrag;
+pub mod memorypool;
+pub mod objectpool;
+pub mod retirequeue;
+mod segvec;
+pub mod slotalloc;
+pub mod slotguard;
+pub mod stagingring;
+pub mod suballoc;
+mod sync;
+pub mod tlsf;
+pub mod tracker;
+
+//  Exports
+pub use bitalloc::{AtomicBitWord, BitAlloc, BitAllocDump, BitAllocError, BitAllocStats, BitWord};
+pub use buddy::BuddyAlloc;
+pub use budget::{BudgetCallback, BudgetEvent, BudgetLevel, BudgetTracker};
+pub use defrag::{DefragCopy, DefragItem, DefragMove, DefragPlan, DefragQueue};
+pub use memorypool::{DeviceMemoryBackend, DeviceMemoryPool, MemoryPolicy, PoolAllocation};
+pub use objectpool::{ObjectHandle, ObjectPool};
+pub use retirequeue::RetireQueue;
+pub use slotalloc::{SlotAlloc, SlotHandle};
+pub use slotguard::SlotGuard;
+pub use stagingring::{RingError, StagingRange, StagingRing};
+pub use suballoc::{AllocatorKind, SubAllocError, SubAllocation, SubAllocator, Tiling};
+pub use tlsf::TlsfAlloc;
+pub use tracker::{AllocReport, AllocTracker, LabelReport, LabelTotals, LiveAllocation};
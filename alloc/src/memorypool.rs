@@ -0,0 +1,843 @@
+//! # Memorypool -- device memory blocks, suballocated, according to a policy.
+//!
+//! Resources get their memory from large `VkDeviceMemory` blocks, carved up
+//! by a suballocator. How big those blocks are, when a resource gets memory
+//! of its own instead, and whether empty blocks are kept for reuse, is set
+//! by a `MemoryPolicy`. These correspond to the WGPU `MemoryHints`:
+//!
+//! - Performance. Large blocks, so there are few of them. Big resources get
+//!   dedicated allocations. An empty block is kept rather than given back.
+//! - MemoryUsage. Small blocks, growing only as needed, packed tightly.
+//!   Empty blocks are given back at once.
+//! - Manual. Block sizes start at the bottom of the given range and double up to the top.
+//!
+//! The actual Vulkan calls are behind `DeviceMemoryBackend`, so this can be
+//! tested without a GPU. One pool per memory type.
+//!
+//! A fragmented block can be compacted with `plan_defrag`. While the GPU
+//! copies run, nothing new is allocated in that block. Once they are done,
+//! each moved allocation is updated with `relocate`, and `finish_defrag`
+//! opens the block up again. A plan which is never carried out must be
+//! ended with `cancel_defrag`, or the block stays closed.
+//!
+//! Animats
+//! November, 2024
+//!
+#![forbid(unsafe_code)]
+use crate::budget::BudgetTracker;
+use crate::defrag::{DefragItem, DefragMove, DefragPlan};
+use crate::suballoc::{SubAllocation, SubAllocator, Tiling};
+use crate::tlsf::TlsfAlloc;
+use crate::tracker::AllocTracker;
+use anyhow::{anyhow, Error};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// One megabyte
+const MB: u64 = 1024 * 1024;
+
+/// Id for the next pool created, so allocations can be checked against their pool.
+static NEXT_POOL_ID: AtomicU32 = AtomicU32::new(0);
+
+/// How device memory is split into blocks.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum MemoryPolicy {
+    /// Favor speed. Few, large blocks, and dedicated memory for big resources.
+    #[default]
+    Performance,
+    /// Favor low memory use. Small blocks, tightly packed.
+    MemoryUsage,
+    /// Block sizes start at the beginning of the range and grow up to the end.
+    Manual {
+        /// Allowed sizes for suballocated blocks
+        block_sizes: Range<u64>,
+    },
+}
+
+impl MemoryPolicy {
+    /// Size of the first block, and the largest block size.
+    pub fn block_sizes(&self) -> Range<u64> {
+        match self {
+            MemoryPolicy::Performance => 128 * MB..512 * MB,
+            MemoryPolicy::MemoryUsage => 8 * MB..64 * MB,
+            MemoryPolicy::Manual { block_sizes } => {
+                let start = block_sizes.start.max(1);
+                start..block_sizes.end.max(start)
+            }
+        }
+    }
+
+    /// Resources bigger than this get their own device memory.
+    pub fn dedicated_threshold(&self) -> u64 {
+        match self {
+            MemoryPolicy::Performance => 32 * MB,
+            //  Only what doesn't fit in a block.
+            MemoryPolicy::MemoryUsage | MemoryPolicy::Manual { .. } => self.block_sizes().end,
+        }
+    }
+
+    /// Size of the block after one of `last` bytes. Doubles, up to the largest block size.
+    pub fn next_block_size(&self, last: u64) -> u64 {
+        last.saturating_mul(2).min(self.block_sizes().end)
+    }
+
+    /// Empty blocks kept for reuse instead of being given back.
+    pub fn empty_blocks_kept(&self) -> usize {
+        match self {
+            MemoryPolicy::Performance | MemoryPolicy::Manual { .. } => 1,
+            MemoryPolicy::MemoryUsage => 0,
+        }
+    }
+}
+
+/// The device memory calls. In real use these are `vkAllocateMemory` and `vkFreeMemory`.
+pub trait DeviceMemoryBackend: Send + Sync {
+    /// Allocate `size` bytes of memory type `memory_type`.
+    /// Returns an opaque handle, such as a raw `VkDeviceMemory`.
+    fn allocate(&self, memory_type: u32, size: u64) -> Result<u64, Error>;
+
+    /// Free memory obtained from `allocate`.
+    fn free(&self, memory_type: u32, memory: u64);
+}
+
+/// Where an allocation's memory came from.
+#[derive(Debug)]
+enum Placement {
+    /// Device memory of its own
+    Dedicated,
+    /// Part of a block
+    Block {
+        /// Index into the pool's blocks
+        block: usize,
+        /// The part of the block
+        sub: SubAllocation,
+    },
+}
+
+/// Memory for one resource. Give it back with `DeviceMemoryPool::free`.
+#[derive(Debug)]
+pub struct PoolAllocation {
+    /// Device memory handle
+    memory: u64,
+    /// Offset into the device memory
+    offset: u64,
+    /// Size requested
+    size: u64,
+    /// Alignment requested
+    alignment: u64,
+    /// Linear or optimal
+    tiling: Tiling,
+    /// Index for allocation tracking, unique within the pool
+    index: u64,
+    /// Id of the pool it came from
+    pool: u32,
+    /// Block or dedicated
+    placement: Placement,
+}
+
+impl PoolAllocation {
+    /// Device memory handle, for `vkBindBufferMemory` or `vkBindImageMemory`.
+    pub fn memory(&self) -> u64 {
+        self.memory
+    }
+
+    /// Offset into the device memory.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Size, as requested.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// True if this resource has device memory of its own.
+    pub fn is_dedicated(&self) -> bool {
+        matches!(self.placement, Placement::Dedicated)
+    }
+}
+
+/// One block of device memory and its suballocator.
+struct PoolBlock {
+    /// Device memory handle
+    memory: u64,
+    /// The suballocator for it
+    suballoc: TlsfAlloc,
+    /// Being compacted. No new allocations until that's finished.
+    defragmenting: bool,
+}
+
+/// The mutable part of a pool.
+struct PoolInner {
+    /// Blocks. Freed blocks leave a None, so indices in allocations stay valid.
+    blocks: Vec<Option<PoolBlock>>,
+    /// Size for the next new block
+    next_block_size: u64,
+    /// Dedicated allocations outstanding
+    dedicated_count: usize,
+    /// Next allocation index
+    next_index: u64,
+}
+
+/// Device memory for one memory type, allocated according to a policy.
+pub struct DeviceMemoryPool {
+    /// Unique to this pool, and in each of its allocations
+    id: u32,
+    /// Block sizes and dedicated allocation rules
+    policy: MemoryPolicy,
+    /// Where the device memory comes from
+    backend: Arc<dyn DeviceMemoryBackend>,
+    /// Vulkan memory type index
+    memory_type: u32,
+    /// Heap that memory type is in
+    heap: usize,
+    /// `bufferImageGranularity`
+    granularity: u64,
+    /// Optional budget. New device memory is refused if it would go over the hard threshold.
+    budget: Option<Arc<BudgetTracker>>,
+    /// Optional allocation tracking, and our source number there
+    tracker: Option<(Arc<AllocTracker>, u32)>,
+    /// Blocks and counts, behind a lock
+    inner: Mutex<PoolInner>,
+}
+
+impl DeviceMemoryPool {
+    /// Usual new.
+    pub fn new(
+        policy: MemoryPolicy,
+        backend: Arc<dyn DeviceMemoryBackend>,
+        memory_type: u32,
+        heap: usize,
+        granularity: u64,
+    ) -> Self {
+        let next_block_size = policy.block_sizes().start;
+        Self {
+            id: NEXT_POOL_ID.fetch_add(1, Ordering::Relaxed),
+            policy,
+            backend,
+            memory_type,
+            heap,
+            granularity,
+            budget: None,
+            tracker: None,
+            inner: Mutex::new(PoolInner {
+                blocks: Vec::new(),
+                next_block_size,
+                dedicated_count: 0,
+                next_index: 0,
+            }),
+        }
+    }
+
+    /// Track device memory use against a budget.
+    pub fn with_budget(mut self, budget: Arc<BudgetTracker>) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Report allocations, with their labels, to `tracker`.
+    pub fn with_tracker(mut self, tracker: &Arc<AllocTracker>) -> Self {
+        let source = tracker.add_source(&format!(
+            "DeviceMemoryPool, memory type {}",
+            self.memory_type
+        ));
+        self.tracker = Some((Arc::clone(tracker), source));
+        self
+    }
+
+    /// The policy in use.
+    pub fn policy(&self) -> &MemoryPolicy {
+        &self.policy
+    }
+
+    /// Memory for a resource of `size` bytes, aligned to `alignment`, with the given tiling.
+    /// `label` names the owner, for allocation tracking, as with WGPU's `label` fields.
+    pub fn alloc(
+        &self,
+        label: Option<&str>,
+        size: u64,
+        alignment: u64,
+        tiling: Tiling,
+    ) -> Result<PoolAllocation, Error> {
+        let allocation = self.alloc_untracked(size, alignment, tiling)?;
+        if let Some((tracker, source)) = &self.tracker {
+            tracker.track(*source, allocation.index, label, allocation.size);
+        }
+        Ok(allocation)
+    }
+
+    /// Memory for a resource, as for `alloc`, without the tracking.
+    fn alloc_untracked(
+        &self,
+        size: u64,
+        alignment: u64,
+        tiling: Tiling,
+    ) -> Result<PoolAllocation, Error> {
+        if size == 0 {
+            return Err(anyhow!("Device memory request of size zero."));
+        }
+        let mut inner = self.inner.lock().expect("Memory pool lock poisoned");
+        let index = inner.next_index;
+        inner.next_index += 1;
+        if size > self.policy.dedicated_threshold() {
+            let memory = self.allocate_device_memory(size)?;
+            inner.dedicated_count += 1;
+            return Ok(PoolAllocation {
+                memory,
+                offset: 0,
+                size,
+                alignment,
+                tiling,
+                index,
+                pool: self.id,
+                placement: Placement::Dedicated,
+            });
+        }
+        //  Try the existing blocks, newest first, since older ones are more likely full.
+        for (ix, block) in inner.blocks.iter_mut().enumerate().rev() {
+            if let Some(block) = block.as_mut().filter(|b| !b.defragmenting) {
+                if let Some(sub) = block.suballoc.alloc(size, alignment, tiling) {
+                    return Ok(self.block_allocation(
+                        block.memory,
+                        ix,
+                        sub,
+                        alignment,
+                        tiling,
+                        index,
+                    ));
+                }
+            }
+        }
+        //  Need a new block. It must hold this request, after padding for alignment.
+        let mut block_size = inner.next_block_size;
+        while block_size < size.saturating_add(alignment)
+            && block_size < self.policy.block_sizes().end
+        {
+            block_size = self.policy.next_block_size(block_size);
+        }
+        //  Check it fits before getting device memory or advancing the block size.
+        let mut suballoc = TlsfAlloc::new(block_size, self.granularity);
+        let sub = suballoc.alloc(size, alignment, tiling).ok_or_else(|| {
+            anyhow!(
+                "Device memory request of {} bytes, alignment {}, does not fit in a new block of {} bytes.",
+                size,
+                alignment,
+                block_size
+            )
+        })?;
+        let memory = self.allocate_device_memory(block_size)?;
+        inner.next_block_size = self.policy.next_block_size(block_size);
+        let block = PoolBlock {
+            memory,
+            suballoc,
+            defragmenting: false,
+        };
+        let ix = match inner.blocks.iter().position(|b| b.is_none()) {
+            Some(ix) => {
+                inner.blocks[ix] = Some(block);
+                ix
+            }
+            None => {
+                inner.blocks.push(Some(block));
+                inner.blocks.len() - 1
+            }
+        };
+        Ok(self.block_allocation(memory, ix, sub, alignment, tiling, index))
+    }
+
+    /// Give back a resource's memory.
+    pub fn free(&self, allocation: PoolAllocation) -> Result<(), Error> {
+        if allocation.pool != self.id {
+            return Err(anyhow!("Free of device memory not from this pool."));
+        }
+        let index = allocation.index;
+        let mut inner = self.inner.lock().expect("Memory pool lock poisoned");
+        match allocation.placement {
+            Placement::Dedicated => {
+                inner.dedicated_count -= 1;
+                self.free_device_memory(allocation.memory, allocation.size);
+            }
+            Placement::Block { block, sub } => {
+                let pool_block = inner
+                    .blocks
+                    .get_mut(block)
+                    .and_then(|b| b.as_mut())
+                    .filter(|b| b.memory == allocation.memory)
+                    .ok_or_else(|| anyhow!("Free of device memory not from this pool."))?;
+                pool_block.suballoc.free(sub)?;
+                if pool_block.suballoc.is_empty() && !pool_block.defragmenting {
+                    self.release_empty_blocks(&mut inner);
+                }
+            }
+        }
+        //  Only once the free has worked, so a refused free leaves the record.
+        if let Some((tracker, source)) = &self.tracker {
+            let _ = tracker.untrack(*source, index);
+        }
+        Ok(())
+    }
+
+    /// Number of device memory blocks, and of dedicated allocations.
+    pub fn device_allocation_counts(&self) -> (usize, usize) {
+        let inner = self.inner.lock().expect("Memory pool lock poisoned");
+        (inner.blocks.iter().flatten().count(), inner.dedicated_count)
+    }
+
+    /// Sizes of the blocks, in order of creation slot.
+    pub fn block_sizes(&self) -> Vec<u64> {
+        let inner = self.inner.lock().expect("Memory pool lock poisoned");
+        inner
+            .blocks
+            .iter()
+            .flatten()
+            .map(|b| b.suballoc.size())
+            .collect()
+    }
+
+    /// Plan compaction of a block. `allocations` must be every live allocation
+    /// in that block, each with the caller's id for it.
+    /// The block takes no new allocations until `finish_defrag`.
+    pub fn plan_defrag(&self, allocations: &[(u64, &PoolAllocation)]) -> Result<DefragPlan, Error> {
+        let mut inner = self.inner.lock().expect("Memory pool lock poisoned");
+        let block = match allocations.first().map(|(_, a)| &a.placement) {
+            Some(Placement::Block { block, .. }) => *block,
+            _ => return Err(anyhow!("Defrag needs suballocated allocations.")),
+        };
+        let pool_block = inner
+            .blocks
+            .get_mut(block)
+            .and_then(|b| b.as_mut())
+            .ok_or_else(|| anyhow!("Defrag of device memory not from this pool."))?;
+        let same_block = allocations.iter().all(|(_, a)| {
+            a.memory == pool_block.memory
+                && matches!(a.placement, Placement::Block { block: b, .. } if b == block)
+        });
+        if !same_block || allocations.len() != pool_block.suballoc.allocation_count() {
+            return Err(anyhow!(
+                "Defrag needs all {} allocations of one block, got {}.",
+                pool_block.suballoc.allocation_count(),
+                allocations.len()
+            ));
+        }
+        if pool_block.defragmenting {
+            return Err(anyhow!("Block is already being defragmented."));
+        }
+        let items: Vec<DefragItem> = allocations
+            .iter()
+            .map(|&(id, a)| DefragItem {
+                id,
+                offset: a.offset,
+                size: a.size,
+                alignment: a.alignment,
+                tiling: a.tiling,
+            })
+            .collect();
+        pool_block.defragmenting = true;
+        Ok(DefragPlan::new(&items, self.granularity).with_memory(pool_block.memory))
+    }
+
+    /// The GPU has copied an allocation to its new place. Update the allocation to match.
+    /// Moves must be applied in plan order. Moves of allocations freed meanwhile are skipped.
+    pub fn relocate(&self, allocation: &mut PoolAllocation, mv: &DefragMove) -> Result<(), Error> {
+        let mut inner = self.inner.lock().expect("Memory pool lock poisoned");
+        let Placement::Block { block, sub } = &mut allocation.placement else {
+            return Err(anyhow!("Relocate of a dedicated allocation."));
+        };
+        let pool_block = inner
+            .blocks
+            .get_mut(*block)
+            .and_then(|b| b.as_mut())
+            .filter(|b| b.memory == allocation.memory && b.defragmenting)
+            .ok_or_else(|| anyhow!("Relocate of an allocation not being defragmented."))?;
+        if (allocation.offset, allocation.size) != (mv.src_offset, mv.size) {
+            return Err(anyhow!(
+                "Relocate of allocation at {}, size {}, by move from {}, size {}.",
+                allocation.offset,
+                allocation.size,
+                mv.src_offset,
+                mv.size
+            ));
+        }
+        //  Everything below has already moved down, and everything above is above the old place,
+        //  so once this one is freed, the new place is free.
+        let old = std::mem::replace(sub, SubAllocation::new(0, 0, 0));
+        let (old_offset, old_size, old_tag) = (old.offset(), old.size(), old.tag());
+        if let Err(err) = pool_block.suballoc.free(old) {
+            *sub = SubAllocation::new(old_offset, old_size, old_tag);
+            return Err(err.into());
+        }
+        match pool_block
+            .suballoc
+            .alloc_at(mv.dst_offset, mv.size, allocation.tiling)
+        {
+            Some(new) => *sub = new,
+            None => {
+                //  Put it back. The old place was just freed, so it's free.
+                *sub = pool_block
+                    .suballoc
+                    .alloc_at(old_offset, old_size, allocation.tiling)
+                    .expect("Relocate could not restore the old place");
+                return Err(anyhow!("Defrag destination {} not free.", mv.dst_offset));
+            }
+        }
+        allocation.offset = mv.dst_offset;
+        Ok(())
+    }
+
+    /// All moves of the plan have been applied. The block can take new allocations again.
+    pub fn finish_defrag(&self, plan: &DefragPlan) -> Result<(), Error> {
+        self.end_defrag(plan)
+    }
+
+    /// Give up on a plan whose copies were never executed. Nothing has moved,
+    /// and the block can take new allocations again.
+    pub fn cancel_defrag(&self, plan: &DefragPlan) -> Result<(), Error> {
+        self.end_defrag(plan)
+    }
+
+    /// Open a block being defragmented to new allocations again.
+    fn end_defrag(&self, plan: &DefragPlan) -> Result<(), Error> {
+        let mut inner = self.inner.lock().expect("Memory pool lock poisoned");
+        let pool_block = inner
+            .blocks
+            .iter_mut()
+            .flatten()
+            .find(|b| b.memory == plan.memory() && b.defragmenting)
+            .ok_or_else(|| anyhow!("End of a defrag not in progress."))?;
+        pool_block.defragmenting = false;
+        if pool_block.suballoc.is_empty() {
+            self.release_empty_blocks(&mut inner);
+        }
+        Ok(())
+    }
+
+    /// An allocation within a block.
+    fn block_allocation(
+        &self,
+        memory: u64,
+        block: usize,
+        sub: SubAllocation,
+        alignment: u64,
+        tiling: Tiling,
+        index: u64,
+    ) -> PoolAllocation {
+        PoolAllocation {
+            memory,
+            offset: sub.offset(),
+            size: sub.size(),
+            alignment,
+            tiling,
+            index,
+            pool: self.id,
+            placement: Placement::Block { block, sub },
+        }
+    }
+
+    /// Give back empty blocks beyond the number the policy keeps.
+    fn release_empty_blocks(&self, inner: &mut PoolInner) {
+        let mut kept = 0;
+        for slot in inner.blocks.iter_mut() {
+            if slot
+                .as_ref()
+                .is_some_and(|b| b.suballoc.is_empty() && !b.defragmenting)
+            {
+                if kept < self.policy.empty_blocks_kept() {
+                    kept += 1;
+                } else if let Some(block) = slot.take() {
+                    self.free_device_memory(block.memory, block.suballoc.size());
+                }
+            }
+        }
+    }
+
+    /// Get device memory from the backend, within the budget.
+    fn allocate_device_memory(&self, size: u64) -> Result<u64, Error> {
+        if let Some(budget) = &self.budget {
+            if !budget.fits(self.heap, size) {
+                return Err(anyhow!(
+                    "Device memory request of {} bytes from heap {} would exceed the budget of {} bytes.",
+                    size,
+                    self.heap,
+                    budget.budget(self.heap)
+                ));
+            }
+        }
+        let memory = self.backend.allocate(self.memory_type, size)?;
+        if let Some(budget) = &self.budget {
+            budget.allocated(self.heap, size);
+        }
+        Ok(memory)
+    }
+
+    /// Give device memory back to the backend.
+    fn free_device_memory(&self, memory: u64, size: u64) {
+        self.backend.free(self.memory_type, memory);
+        if let Some(budget) = &self.budget {
+            budget.freed(self.heap, size);
+        }
+    }
+}
+
+impl Drop for DeviceMemoryPool {
+    fn drop(&mut self) {
+        let inner = self.inner.get_mut().expect("Memory pool lock poisoned");
+        let blocks: Vec<PoolBlock> = inner.blocks.drain(..).flatten().collect();
+        let in_use: usize = blocks.iter().map(|b| b.suballoc.allocation_count()).sum();
+        if in_use > 0 || inner.dedicated_count > 0 {
+            log::error!(
+                "Device memory pool dropped with {} suballocations and {} dedicated allocations still in use.",
+                in_use,
+                inner.dedicated_count
+            );
+        }
+        //  Dedicated allocations can't be freed here, since their handles are elsewhere.
+        for block in blocks {
+            self.free_device_memory(block.memory, block.suballoc.size());
+        }
+    }
+}
+
+/// Backend which records calls, for tests.
+#[cfg(test)]
+#[derive(Default)]
+struct RecordingBackend {
+    /// Live device memory, as (handle, size)
+    live: Mutex<Vec<(u64, u64)>>,
+    /// Next handle
+    next: std::sync::atomic::AtomicU64,
+}
+
+#[cfg(test)]
+impl DeviceMemoryBackend for RecordingBackend {
+    fn allocate(&self, _memory_type: u32, size: u64) -> Result<u64, Error> {
+        let memory = self.next.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+        self.live.lock().unwrap().push((memory, size));
+        Ok(memory)
+    }
+
+    fn free(&self, _memory_type: u32, memory: u64) {
+        self.live.lock().unwrap().retain(|&(m, _)| m != memory);
+    }
+}
+
+#[test]
+/// Each policy shapes the blocks differently.
+fn test_memorypool_policies() {
+    //  Performance. One big block, big resources dedicated, and an empty block kept.
+    let backend = Arc::new(RecordingBackend::default());
+    let pool = DeviceMemoryPool::new(MemoryPolicy::Performance, backend.clone(), 0, 0, 1024);
+    let small = pool.alloc(None, MB, 256, Tiling::Optimal).unwrap();
+    let big = pool.alloc(None, 40 * MB, 256, Tiling::Optimal).unwrap();
+    assert!(!small.is_dedicated());
+    assert!(big.is_dedicated());
+    assert_eq!(pool.block_sizes(), [128 * MB]);
+    pool.free(small).unwrap();
+    pool.free(big).unwrap();
+    assert_eq!(pool.device_allocation_counts(), (1, 0)); // empty block kept
+
+    //  MemoryUsage. Small blocks, growing to fit, and given back when empty.
+    let backend = Arc::new(RecordingBackend::default());
+    let pool = DeviceMemoryPool::new(MemoryPolicy::MemoryUsage, backend.clone(), 0, 0, 1024);
+    let a = pool.alloc(None, MB, 256, Tiling::Optimal).unwrap();
+    let b = pool.alloc(None, 40 * MB, 256, Tiling::Optimal).unwrap();
+    assert!(!b.is_dedicated()); // packed into a block, not dedicated
+    assert_eq!(pool.block_sizes(), [8 * MB, 64 * MB]);
+    pool.free(a).unwrap();
+    assert_eq!(pool.block_sizes(), [64 * MB]);
+    pool.free(b).unwrap();
+    assert_eq!(pool.device_allocation_counts(), (0, 0));
+    assert!(backend.live.lock().unwrap().is_empty());
+
+    //  Manual. Blocks double from the start of the range up to the end.
+    let backend = Arc::new(RecordingBackend::default());
+    let policy = MemoryPolicy::Manual {
+        block_sizes: MB..4 * MB,
+    };
+    let pool = DeviceMemoryPool::new(policy, backend.clone(), 0, 0, 1);
+    let held: Vec<PoolAllocation> = (0..12)
+        .map(|_| pool.alloc(None, MB - 4096, 1, Tiling::Linear).unwrap())
+        .collect();
+    assert_eq!(pool.block_sizes(), [MB, 2 * MB, 4 * MB, 4 * MB, 4 * MB]);
+    let huge = pool.alloc(None, 5 * MB, 1, Tiling::Linear).unwrap();
+    assert!(huge.is_dedicated()); // bigger than the largest block
+    pool.free(huge).unwrap();
+    for a in held {
+        pool.free(a).unwrap();
+    }
+    assert_eq!(pool.device_allocation_counts(), (1, 0));
+    drop(pool);
+    assert!(backend.live.lock().unwrap().is_empty());
+}
+
+#[test]
+/// New device memory is refused when it would go over budget.
+fn test_memorypool_budget() {
+    let backend = Arc::new(RecordingBackend::default());
+    let budget = Arc::new(BudgetTracker::new(&[200 * MB]));
+    let pool = DeviceMemoryPool::new(MemoryPolicy::MemoryUsage, backend, 0, 0, 1)
+        .with_budget(budget.clone());
+    //  Budget is 160MB, hard threshold 152MB.
+    let a = pool.alloc(None, 40 * MB, 1, Tiling::Linear).unwrap(); // 64MB block
+    assert_eq!(budget.usage(0), 64 * MB);
+    let b = pool.alloc(None, 40 * MB, 1, Tiling::Linear).unwrap(); // another, 128MB total
+    assert_eq!(budget.usage(0), 128 * MB);
+    assert!(pool.alloc(None, 40 * MB, 1, Tiling::Linear).is_err()); // a third would pass the hard threshold
+    pool.free(a).unwrap();
+    pool.free(b).unwrap();
+    assert_eq!(budget.usage(0), 0);
+}
+
+#[test]
+/// Compact a block with holes, through the defrag queue, and use the space regained.
+fn test_memorypool_defrag() {
+    use crate::defrag::DefragQueue;
+    use std::collections::BTreeMap;
+    const KB: u64 = 1024;
+    let backend = Arc::new(RecordingBackend::default());
+    let pool = DeviceMemoryPool::new(MemoryPolicy::MemoryUsage, backend, 0, 0, 1024);
+    //  Fill most of an 8MB block, then free every other allocation.
+    let mut textures: BTreeMap<u64, PoolAllocation> = (0..100)
+        .map(|id| {
+            (
+                id,
+                pool.alloc(None, 64 * KB, 4096, Tiling::Optimal).unwrap(),
+            )
+        })
+        .collect();
+    let first_block = textures[&0].memory();
+    for id in (1..100).step_by(2) {
+        pool.free(textures.remove(&id).unwrap()).unwrap();
+    }
+    let big = pool.alloc(None, 4 * MB, 4096, Tiling::Optimal).unwrap();
+    assert_ne!(big.memory(), first_block); // fragmented, so it didn't fit
+    pool.free(big).unwrap();
+    //  Plan. Then, while the copies run, the block takes no new allocations.
+    let live: Vec<(u64, &PoolAllocation)> = textures.iter().map(|(&id, a)| (id, a)).collect();
+    assert!(pool.plan_defrag(&live[1..]).is_err()); // must be all of them
+    let plan = pool.plan_defrag(&live).unwrap();
+    assert_eq!(plan.memory(), first_block);
+    assert_eq!(plan.moves().len(), 49); // all but the first
+    assert!(pool.plan_defrag(&live).is_err()); // already in progress
+    let small = pool.alloc(None, 4096, 1, Tiling::Linear).unwrap();
+    assert_ne!(small.memory(), first_block);
+    pool.free(small).unwrap();
+    //  Copies recorded in frame 3. One texture is freed before that frame retires.
+    let queue = DefragQueue::new();
+    queue.submitted(plan, 3);
+    pool.free(textures.remove(&50).unwrap()).unwrap();
+    assert_eq!(queue.frame_retired(2, |_| panic!("too soon")), 0);
+    let applied = queue.frame_retired(3, |plan| {
+        for mv in plan.moves() {
+            if let Some(texture) = textures.get_mut(&mv.id) {
+                pool.relocate(texture, mv).unwrap();
+                //  Descriptor slot for mv.id would be rewritten here.
+            }
+        }
+        pool.finish_defrag(&plan).unwrap();
+    });
+    assert_eq!(applied, 1);
+    //  Packed at the bottom, except for the hole left by the texture freed meanwhile.
+    let offsets: Vec<u64> = textures.values().map(|a| a.offset()).collect();
+    let expected: Vec<u64> = (0..50).filter(|&n| n != 25).map(|n| n * 64 * KB).collect();
+    assert_eq!(offsets, expected);
+    let big = pool.alloc(None, 4 * MB, 4096, Tiling::Optimal).unwrap();
+    assert_eq!(big.memory(), first_block);
+    pool.free(big).unwrap();
+    for (_, texture) in textures {
+        pool.free(texture).unwrap();
+    }
+    assert_eq!(pool.device_allocation_counts(), (0, 0));
+}
+
+#[test]
+/// A failed relocate leaves the allocation where it was, and a cancelled plan reopens the block.
+fn test_memorypool_defrag_cancel() {
+    let backend = Arc::new(RecordingBackend::default());
+    let pool = DeviceMemoryPool::new(MemoryPolicy::MemoryUsage, backend, 0, 0, 1);
+    let a = pool.alloc(None, MB, 1, Tiling::Linear).unwrap();
+    let b = pool.alloc(None, MB, 1, Tiling::Linear).unwrap();
+    let mut c = pool.alloc(None, MB, 1, Tiling::Linear).unwrap();
+    let block = a.memory();
+    let plan = pool.plan_defrag(&[(0, &a), (1, &b), (2, &c)]).unwrap();
+    assert!(plan.moves().is_empty()); // already packed
+
+    //  A move onto a live allocation fails, and c stays put.
+    let bad = DefragMove {
+        id: 2,
+        src_offset: c.offset(),
+        dst_offset: a.offset(),
+        size: MB,
+    };
+    assert!(pool.relocate(&mut c, &bad).is_err());
+    assert_eq!(c.offset(), 2 * MB);
+    //  Closed until cancelled.
+    let d = pool.alloc(None, MB, 1, Tiling::Linear).unwrap();
+    assert_ne!(d.memory(), block);
+    pool.free(d).unwrap();
+    pool.cancel_defrag(&plan).unwrap();
+    assert!(pool.cancel_defrag(&plan).is_err()); // not in progress any more
+    let d = pool.alloc(None, MB, 1, Tiling::Linear).unwrap();
+    assert_eq!(d.memory(), block);
+    for allocation in [a, b, c, d] {
+        pool.free(allocation).unwrap();
+    }
+    assert_eq!(pool.device_allocation_counts(), (0, 0));
+}
+
+#[test]
+/// Allocations freed to the wrong pool are refused, and stay tracked.
+fn test_memorypool_foreign_free() {
+    let backend = Arc::new(RecordingBackend::default());
+    let tracker = Arc::new(AllocTracker::new());
+    let pool = DeviceMemoryPool::new(MemoryPolicy::Performance, backend.clone(), 0, 0, 1)
+        .with_tracker(&tracker);
+    let other = DeviceMemoryPool::new(MemoryPolicy::Performance, backend.clone(), 0, 0, 1)
+        .with_tracker(&tracker);
+    let dedicated = pool.alloc(Some("big"), 40 * MB, 1, Tiling::Linear).unwrap();
+    let block = pool.alloc(Some("small"), MB, 1, Tiling::Linear).unwrap();
+    let memory = (dedicated.memory(), block.memory());
+    assert!(other.free(dedicated).is_err());
+    assert!(other.free(block).is_err());
+    assert_eq!(pool.device_allocation_counts(), (1, 1));
+    assert_eq!(other.device_allocation_counts(), (0, 0));
+    assert_eq!(tracker.live_count(), 2);
+    let live = backend.live.lock().unwrap();
+    assert!(live.iter().any(|&(m, _)| m == memory.0));
+    assert!(live.iter().any(|&(m, _)| m == memory.1));
+}
+
+#[test]
+/// Allocations are tracked under their labels until freed.
+fn test_memorypool_labels() {
+    let backend = Arc::new(RecordingBackend::default());
+    let tracker = Arc::new(AllocTracker::new());
+    let pool =
+        DeviceMemoryPool::new(MemoryPolicy::Performance, backend, 3, 0, 1).with_tracker(&tracker);
+    let a = pool
+        .alloc(Some("terrain"), MB, 256, Tiling::Optimal)
+        .unwrap();
+    let b = pool
+        .alloc(Some("terrain"), 2 * MB, 256, Tiling::Optimal)
+        .unwrap();
+    let c = pool
+        .alloc(Some("avatars"), 40 * MB, 256, Tiling::Optimal)
+        .unwrap(); // dedicated
+    let d = pool.alloc(None, 4096, 1, Tiling::Linear).unwrap();
+    assert_eq!(tracker.totals(Some("terrain")).bytes, 3 * MB);
+    assert_eq!(tracker.totals(Some("avatars")).bytes, 40 * MB);
+    pool.free(a).unwrap();
+    pool.free(c).unwrap();
+    let report = tracker.report();
+    assert_eq!(report.count(), 2);
+    assert_eq!(report.bytes(), 2 * MB + 4096);
+    assert_eq!(report.labels[1].label.as_deref(), Some("terrain"));
+    assert_eq!(
+        report.labels[1].allocations[0].source,
+        "DeviceMemoryPool, memory type 3"
+    );
+    assert_eq!(report.labels[1].totals.peak_bytes, 3 * MB);
+    pool.free(b).unwrap();
+    pool.free(d).unwrap();
+    assert_eq!(tracker.live_count(), 0);
+}
@@ -0,0 +1,209 @@
+//! # Objectpool -- fixed-size objects in one big GPU storage buffer.
+//!
+//! Per-drawable data, such as transforms, material parameters and
+//! per-instance records, all have a fixed size. They go in one large
+//! storage buffer, an array of `T`, and shaders index into it by slot.
+//!
+//! Slots are managed by a BitAlloc, so allocation and free are lock-free.
+//! The handle's index is the array index the shader uses, and the byte
+//! offset for uploads is the index times the size of `T`.
+//!
+//! The pool grows a chunk at a time, up to a maximum. The buffer itself
+//! belongs to the renderer, which should check `buffer_size` once per frame
+//! and enlarge the buffer if it has grown. Existing slots never move.
+//!
+//! Animats
+//! November, 2024
+//!
+#![forbid(unsafe_code)]
+use crate::bitalloc::BitAlloc;
+use crate::tracker::AllocTracker;
+use bytemuck::Pod;
+use std::marker::PhantomData;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// Id for the next pool created, so handles can be checked against their pool.
+static NEXT_POOL_ID: AtomicU32 = AtomicU32::new(0);
+
+/// A slot in an object pool. Not Clone, so it can only be freed once.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ObjectHandle<T> {
+    /// Slot index
+    index: u32,
+    /// Id of the pool it came from
+    pool: u32,
+    /// Type of the pool it came from
+    phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> ObjectHandle<T> {
+    /// Array index in the storage buffer. Pass this to shaders.
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+}
+
+/// Pool of fixed-size `T` slots in a storage buffer.
+pub struct ObjectPool<T: Pod> {
+    /// Unique to this pool, and in each of its handles
+    id: u32,
+    /// Slot allocation
+    slots: BitAlloc,
+    /// Slots added per growth step
+    chunk: usize,
+    /// Element type
+    phantom: PhantomData<fn() -> T>,
+}
+
+impl<T: Pod> ObjectPool<T> {
+    /// Usual new. Starts with one chunk of `chunk` slots, and can grow to `max` slots.
+    /// Both are rounded up to a multiple of 64.
+    pub fn new(chunk: u32, max: u32) -> Self {
+        assert!(chunk > 0, "Object pool chunk size is zero");
+        assert!(
+            std::mem::size_of::<T>() > 0,
+            "Object pool of zero-sized type"
+        );
+        //  Rounded as usize, since rounding near u32::MAX would overflow a u32.
+        let chunk = (chunk as usize).next_multiple_of(u64::BITS as usize);
+        let max = (max as usize)
+            .max(chunk)
+            .next_multiple_of(u64::BITS as usize);
+        Self {
+            id: NEXT_POOL_ID.fetch_add(1, Ordering::Relaxed),
+            slots: BitAlloc::new_growable(chunk, max),
+            chunk,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Report slots to `tracker`, under `label`.
+    pub fn with_tracker(mut self, tracker: &Arc<AllocTracker>, label: Option<&str>) -> Self {
+        self.slots = self.slots.with_tracker(tracker, label);
+        self
+    }
+
+    /// Size of one element, in bytes. The array stride in the shader.
+    pub fn element_size() -> u64 {
+        std::mem::size_of::<T>() as u64
+    }
+
+    /// Slots available now.
+    pub fn capacity(&self) -> u64 {
+        self.slots.len() as u64
+    }
+
+    /// Most slots the pool can grow to.
+    pub fn max_capacity(&self) -> u64 {
+        self.slots.ceiling() as u64
+    }
+
+    /// Size the storage buffer must be, in bytes, to hold every slot.
+    pub fn buffer_size(&self) -> u64 {
+        self.capacity() * Self::element_size()
+    }
+
+    /// Slots in use.
+    pub fn count(&self) -> usize {
+        self.slots.allocated()
+    }
+
+    /// Allocate a slot, adding a chunk if the pool is full. None if at the maximum.
+    /// Any thread. Does not block.
+    pub fn alloc(&self) -> Option<ObjectHandle<T>> {
+        let chunk = self.chunk;
+        let ix = self.slots.alloc_bit_or_grow_with(|len| len + chunk)?;
+        Some(ObjectHandle {
+            index: ix as u32,
+            pool: self.id,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Free a slot. The caller must be sure the GPU is done with it, such as through a RetireQueue.
+    pub fn free(&self, handle: ObjectHandle<T>) {
+        if handle.pool != self.id {
+            //  Clearing the slot here would free some other object's slot.
+            log::error!(
+                "ObjectPool free of slot {} from another pool.",
+                handle.index
+            );
+            return;
+        }
+        if let Err(e) = self.slots.clear_bit(handle.index as usize) {
+            log::error!("ObjectPool free of slot {}: {}", handle.index, e);
+        }
+    }
+
+    /// Byte offset of a slot in the storage buffer.
+    pub fn offset(&self, handle: &ObjectHandle<T>) -> u64 {
+        debug_assert_eq!(handle.pool, self.id, "Object handle from another pool");
+        u64::from(handle.index) * Self::element_size()
+    }
+
+    /// Byte range of a slot in the storage buffer.
+    pub fn range(&self, handle: &ObjectHandle<T>) -> Range<u64> {
+        let offset = self.offset(handle);
+        offset..offset + Self::element_size()
+    }
+
+    /// Where and what to upload to set a slot to `value`.
+    pub fn upload<'a>(&self, handle: &ObjectHandle<T>, value: &'a T) -> (u64, &'a [u8]) {
+        (self.offset(handle), bytemuck::bytes_of(value))
+    }
+}
+
+#[test]
+/// Slots, offsets, growth by chunks, and reuse.
+fn test_objectpool_chunks() {
+    /// A 4x4 matrix, as shaders see it
+    type Transform = [f32; 16];
+    let pool: ObjectPool<Transform> = ObjectPool::new(100, 300);
+    assert_eq!(ObjectPool::<Transform>::element_size(), 64);
+    assert_eq!((pool.capacity(), pool.max_capacity()), (128, 320));
+    let handles: Vec<ObjectHandle<Transform>> = (0..200).map(|_| pool.alloc().unwrap()).collect();
+    assert_eq!(pool.capacity(), 256); // grew by one chunk
+    assert_eq!(pool.buffer_size(), 256 * 64);
+    let h = &handles[150];
+    assert_eq!(pool.offset(h), u64::from(h.index()) * 64);
+    assert_eq!(pool.range(h), pool.offset(h)..pool.offset(h) + 64);
+    let mut m: Transform = [0.0; 16];
+    m[0] = 1.0;
+    let (offset, bytes) = pool.upload(h, &m);
+    assert_eq!(offset, pool.offset(h));
+    assert_eq!(bytes.len(), 64);
+    assert_eq!(&bytes[0..4], &1.0f32.to_ne_bytes());
+    //  Indices are unique.
+    let mut indices: Vec<u32> = handles.iter().map(|h| h.index()).collect();
+    indices.sort();
+    indices.dedup();
+    assert_eq!(indices.len(), 200);
+    //  Fill to the maximum.
+    let more: Vec<ObjectHandle<Transform>> = std::iter::from_fn(|| pool.alloc()).collect();
+    assert_eq!(more.len(), 120);
+    assert_eq!(pool.capacity(), 320);
+    assert_eq!(pool.count(), 320);
+    //  Freed slots are reused, and the pool doesn't shrink.
+    let freed = handles[10].index();
+    for h in handles {
+        pool.free(h);
+    }
+    assert_eq!(pool.count(), 120);
+    let again = pool.alloc().unwrap();
+    assert!(again.index() < 200);
+    assert!(again.index() <= freed);
+    assert_eq!(pool.capacity(), 320);
+    //  A handle from another pool is refused, not freed here.
+    let other: ObjectPool<Transform> = ObjectPool::new(64, 64);
+    let stranger = other.alloc().unwrap();
+    let count = pool.count();
+    pool.free(stranger);
+    assert_eq!(pool.count(), count);
+    assert_eq!(other.count(), 1);
+    //  Sizes near the top of the u32 range round up without overflow.
+    let huge: ObjectPool<Transform> = ObjectPool::new(64, u32::MAX - 10);
+    assert_eq!(huge.max_capacity(), 1 << 32);
+    assert_eq!(huge.capacity(), 64);
+}
@@ -0,0 +1,210 @@
+//! # Retirequeue -- deferred release of slots, keyed by frame epoch.
+//!
+//! A slot freed while the CPU is building frame N may still be referenced
+//! by frames the GPU has not finished yet. So it can't go back to the
+//! BitAlloc right away. It goes into this queue, tagged with the frame
+//! epoch, and is released only after the caller reports that frame
+//! N + frames_in_flight has retired.
+//!
+//! Any thread can retire slots. The render thread advances the epoch
+//! and drains the queue once per frame. Neither side blocks.
+//!
+//! Animats
+//! November, 2024
+//!
+#![forbid(unsafe_code)]
+use crate::bitalloc::BitAlloc;
+use crate::slotguard::SlotGuard;
+use crossbeam_queue::SegQueue;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// A slot waiting for the GPU to be done with it.
+struct RetiredSlot {
+    /// Frame epoch in which the slot was freed
+    epoch: u64,
+    /// Index of the slot
+    index: usize,
+}
+
+/// Queue of freed slots waiting for their frames to retire.
+pub struct RetireQueue {
+    /// Allocator to which slots are returned
+    bit_alloc: Arc<BitAlloc>,
+    /// How many frames the GPU can have in flight
+    frames_in_flight: u64,
+    /// Frame the CPU is currently building
+    current_epoch: AtomicU64,
+    /// The slots waiting for release. Lock-free.
+    pending: SegQueue<RetiredSlot>,
+}
+
+impl RetireQueue {
+    /// Usual new.
+    pub fn new(bit_alloc: Arc<BitAlloc>, frames_in_flight: u64) -> Self {
+        Self {
+            bit_alloc,
+            frames_in_flight,
+            current_epoch: AtomicU64::new(0),
+            pending: SegQueue::new(),
+        }
+    }
+
+    /// The allocator to which slots are returned.
+    pub fn bit_alloc(&self) -> &Arc<BitAlloc> {
+        &self.bit_alloc
+    }
+
+    /// The frame the CPU is currently building.
+    pub fn current_epoch(&self) -> u64 {
+        self.current_epoch.load(Ordering::SeqCst)
+    }
+
+    /// Number of slots waiting for release.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// True if no slots are waiting.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Queue a slot for release once the current frame is safely past.
+    ///
+    /// This can be called from any thread at any time. It does not block.
+    pub fn retire(&self, index: usize) {
+        let epoch = self.current_epoch();
+        self.pending.push(RetiredSlot { epoch, index });
+    }
+
+    /// Queue a guarded slot for release. The guard gives up the slot to the queue.
+    pub fn retire_guard(&self, guard: SlotGuard) {
+        self.retire(guard.into_raw());
+    }
+
+    /// Start building the next frame. Render thread only. Returns the new epoch.
+    pub fn advance_epoch(&self) -> u64 {
+        self.current_epoch.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// The GPU has finished with frame `retired_epoch`.
+    /// Release every slot freed in a frame at least `frames_in_flight` frames before it.
+    /// Render thread only, once per frame. Returns the number of slots released.
+    pub fn frame_retired(&self, retired_epoch: u64) -> usize {
+        let ready = self.take_ready(retired_epoch);
+        self.release(&ready);
+        ready.len()
+    }
+
+    /// Take the slots `frame_retired` would release, without releasing them.
+    /// The caller must `release` them later, such as after overwriting whatever they pointed to.
+    /// Render thread only.
+    pub fn take_ready(&self, retired_epoch: u64) -> Vec<usize> {
+        let mut ready = Vec::new();
+        let mut not_ready = Vec::new();
+        //  Take everything queued so far. Slots pushed by other threads during this loop
+        //  may or may not be seen; either way they stay queued until they are ready.
+        while let Some(slot) = self.pending.pop() {
+            if slot.epoch + self.frames_in_flight <= retired_epoch {
+                ready.push(slot.index);
+            } else {
+                not_ready.push(slot);
+            }
+        }
+        //  Put back the ones which are still too new.
+        for slot in not_ready {
+            self.pending.push(slot);
+        }
+        ready
+    }
+
+    /// Return slots from `take_ready` to the allocator.
+    pub fn release(&self, indices: &[usize]) {
+        for &index in indices {
+            if let Err(e) = self.bit_alloc.clear_bit(index) {
+                log::error!("RetireQueue release of slot {}: {:?}", index, e);
+            }
+        }
+    }
+}
+
+impl Drop for RetireQueue {
+    fn drop(&mut self) {
+        if !self.pending.is_empty() {
+            //  The queue is going away, so the GPU must be idle. Nothing can still be using these.
+            log::info!(
+                "RetireQueue dropped with {} slots pending, releasing.",
+                self.pending.len()
+            );
+            let _ = self.frame_retired(u64::MAX - self.frames_in_flight);
+        }
+    }
+}
+
+#[test]
+/// Slots are not released until their frame plus frames in flight has retired.
+fn test_retirequeue_epochs() {
+    let bit_alloc: Arc<BitAlloc> = Arc::new(BitAlloc::new(100));
+    let queue = RetireQueue::new(Arc::clone(&bit_alloc), 2);
+    let a = bit_alloc.alloc_bit().unwrap();
+    let b = bit_alloc.alloc_guard().unwrap();
+    let b_index = b.index();
+    queue.retire(a); // freed in frame 0
+    assert_eq!(queue.advance_epoch(), 1);
+    queue.retire_guard(b); // freed in frame 1
+    assert!(bit_alloc.get_bit(b_index)); // guard must not have cleared it
+    assert_eq!(queue.frame_retired(1), 0);
+    assert!(bit_alloc.get_bit(a));
+    assert_eq!(queue.frame_retired(2), 1);
+    assert!(!bit_alloc.get_bit(a));
+    assert!(bit_alloc.get_bit(b_index));
+    assert_eq!(queue.len(), 1);
+    assert_eq!(queue.frame_retired(3), 1);
+    assert!(!bit_alloc.get_bit(b_index));
+    assert!(queue.is_empty());
+    //  Two-phase release. Bits stay set until released.
+    let c = bit_alloc.alloc_bit().unwrap();
+    queue.retire(c);
+    assert_eq!(queue.take_ready(queue.current_epoch() + 2), [c]);
+    assert!(queue.is_empty());
+    assert!(bit_alloc.get_bit(c));
+    queue.release(&[c]);
+    assert!(!bit_alloc.get_bit(c));
+}
+
+#[test]
+/// Many threads retiring while the render thread drains.
+fn test_retirequeue_threads() {
+    const THREADS: usize = 8;
+    const PER_THREAD: usize = 1000;
+    let bit_alloc: Arc<BitAlloc> = Arc::new(BitAlloc::new(THREADS * PER_THREAD));
+    let queue = Arc::new(RetireQueue::new(Arc::clone(&bit_alloc), 2));
+    let workers: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let queue = Arc::clone(&queue);
+            std::thread::spawn(move || {
+                for _ in 0..PER_THREAD {
+                    let guard = queue.bit_alloc().alloc_guard().unwrap();
+                    queue.retire_guard(guard);
+                }
+            })
+        })
+        .collect();
+    //  Render thread loop, running concurrently with the workers.
+    let mut released = 0;
+    while workers.iter().any(|w| !w.is_finished()) {
+        let epoch = queue.advance_epoch();
+        released += queue.frame_retired(epoch.saturating_sub(1));
+    }
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    //  Run out the frames in flight.
+    let epoch = queue.advance_epoch();
+    for retired in epoch..epoch + 3 {
+        released += queue.frame_retired(retired);
+    }
+    assert_eq!(released, THREADS * PER_THREAD);
+    assert!((0..bit_alloc.len()).all(|n| !bit_alloc.get_bit(n)));
+}
@@ -0,0 +1,171 @@
+//! # Segvec -- array which can grow while other threads are using it.
+//!
+//! Storage is a list of segments, each twice the size of the one before,
+//! up to a fixed ceiling. Growing fills in new segments and then raises
+//! the length. Existing elements never move, so other threads can keep
+//! reading and updating them through shared references while that happens.
+//!
+//! The segment list itself uses `OnceLock`, which loom does not model.
+//! Loom tests should not grow a SegVec from more than one thread.
+//!
+//! Animats
+//! November, 2024
+//!
+#![forbid(unsafe_code)]
+use crate::sync::{AtomicUsize, Ordering};
+use std::ops::Index;
+use std::sync::OnceLock;
+
+/// Segmented array with lock-free reads and growth up to a ceiling.
+pub(crate) struct SegVec<T> {
+    /// Size of segment 0. Segment k > 0 holds first_len << (k-1) elements.
+    first_len: usize,
+    /// Hard limit on length
+    ceiling: usize,
+    /// Current usable length
+    len: AtomicUsize,
+    /// The segments, filled in as needed
+    segments: Vec<OnceLock<Box<[T]>>>,
+}
+
+impl<T: Default> SegVec<T> {
+    /// Usual new. Length starts at `len` and may grow to `ceiling`.
+    pub fn new(len: usize, ceiling: usize) -> Self {
+        assert!(
+            len <= ceiling,
+            "SegVec initial length {} over ceiling {}",
+            len,
+            ceiling
+        );
+        let first_len = len.max(1);
+        //  Enough segments to reach the ceiling.
+        let mut segment_count = 1;
+        while Self::segment_base(first_len, segment_count) < ceiling {
+            segment_count += 1;
+        }
+        let mut segments = Vec::new();
+        segments.resize_with(segment_count, OnceLock::new);
+        let seg_vec = Self {
+            first_len,
+            ceiling,
+            len: AtomicUsize::new(0),
+            segments,
+        };
+        let _ = seg_vec.grow(len);
+        seg_vec
+    }
+
+    /// Current length.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    /// True if length is zero.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Maximum length.
+    pub fn ceiling(&self) -> usize {
+        self.ceiling
+    }
+
+    /// Grow to at least `new_len`, limited by the ceiling. Never shrinks.
+    ///
+    /// Safe to call while other threads are using the array.
+    /// Returns the length after growing.
+    pub fn grow(&self, new_len: usize) -> usize {
+        self.grow_with(new_len, |_| T::default())
+    }
+
+    /// Grow, as for `grow`, with new elements made by `init` from their index.
+    ///
+    /// Elements are made before the length covers them, so no other thread
+    /// sees them until they are complete.
+    pub fn grow_with(&self, new_len: usize, init: impl Fn(usize) -> T) -> usize {
+        let new_len = new_len.min(self.ceiling);
+        if new_len > 0 {
+            //  Make sure every segment up to the new end exists before anyone can index into it.
+            let (last_segment, _) = Self::locate(self.first_len, new_len - 1);
+            for segment in 0..=last_segment {
+                let _ = self.segments[segment].get_or_init(|| {
+                    let base = Self::segment_base(self.first_len, segment);
+                    let size = Self::segment_size(self.first_len, segment).min(self.ceiling - base);
+                    (base..base + size).map(&init).collect()
+                });
+            }
+        }
+        self.len.fetch_max(new_len, Ordering::SeqCst).max(new_len)
+    }
+
+    /// First element index of segment `segment`.
+    fn segment_base(first_len: usize, segment: usize) -> usize {
+        if segment == 0 {
+            0
+        } else {
+            first_len << (segment - 1)
+        }
+    }
+
+    /// Number of elements in segment `segment`, before ceiling clipping.
+    fn segment_size(first_len: usize, segment: usize) -> usize {
+        if segment == 0 {
+            first_len
+        } else {
+            first_len << (segment - 1)
+        }
+    }
+
+    /// Which segment, and where in it, for an element index.
+    fn locate(first_len: usize, index: usize) -> (usize, usize) {
+        if index < first_len {
+            (0, index)
+        } else {
+            let q = index / first_len; // at least 1
+            let segment = (usize::BITS - q.leading_zeros()) as usize;
+            (segment, index - Self::segment_base(first_len, segment))
+        }
+    }
+}
+
+impl<T: Default> Index<usize> for SegVec<T> {
+    type Output = T;
+    fn index(&self, index: usize) -> &T {
+        assert!(
+            index < self.len(),
+            "SegVec index {} out of range {}",
+            index,
+            self.len()
+        );
+        let (segment, offset) = Self::locate(self.first_len, index);
+        &self.segments[segment]
+            .get()
+            .expect("SegVec segment missing")[offset]
+    }
+}
+
+#[test]
+/// Growth keeps existing elements in place, and stops at the ceiling.
+fn test_segvec_grow() {
+    use std::sync::atomic::AtomicU64;
+    let seg_vec: SegVec<AtomicU64> = SegVec::new(3, 100);
+    assert_eq!(seg_vec.len(), 3);
+    seg_vec[2].store(42, Ordering::SeqCst);
+    assert_eq!(seg_vec.grow(50), 50);
+    assert_eq!(seg_vec[2].load(Ordering::SeqCst), 42);
+    for n in 0..50 {
+        seg_vec[n].store(n as u64, Ordering::SeqCst);
+    }
+    assert_eq!(seg_vec.grow(10), 50); // never shrinks
+    assert_eq!(seg_vec.grow(1000), 100); // ceiling
+    assert!((0..50).all(|n| seg_vec[n].load(Ordering::SeqCst) == n as u64));
+    assert!((50..100).all(|n| seg_vec[n].load(Ordering::SeqCst) == 0));
+    //  Element addresses must be unique.
+    let addrs: std::collections::HashSet<*const AtomicU64> =
+        (0..100).map(|n| &seg_vec[n] as *const AtomicU64).collect();
+    assert_eq!(addrs.len(), 100);
+    //  Empty start.
+    let seg_vec: SegVec<AtomicU64> = SegVec::new(0, 10);
+    assert!(seg_vec.is_empty());
+    assert_eq!(seg_vec.grow(10), 10);
+}
@@ -0,0 +1,148 @@
+//! # Slotalloc -- generation-checked slot handles on top of BitAlloc.
+//!
+//! A bare index from BitAlloc can be freed and handed out again,
+//! so a stale copy of it silently refers to somebody else's slot.
+//! A SlotHandle pairs the index with the generation of the slot
+//! at allocation time. Freeing a slot advances its generation,
+//! so stale handles are rejected by lookups and frees.
+//!
+//! Animats
+//! November, 2024
+//!
+#![forbid(unsafe_code)]
+use crate::bitalloc::BitAlloc;
+use anyhow::{anyhow, Error};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Generation counter type. Wraps around, which takes 4 billion reuses of one slot.
+type GenerationType = u32;
+type AtomicGenerationType = AtomicU32;
+
+/// Handle for an allocated slot. Index plus generation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SlotHandle {
+    /// Slot index
+    index: usize,
+    /// Generation of the slot when allocated
+    generation: GenerationType,
+}
+
+impl SlotHandle {
+    /// The slot index. This is what the GPU sees.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The generation of the slot when this handle was issued.
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+/// Slot allocator with generation checking
+pub struct SlotAlloc {
+    /// Which slots are in use
+    bits: BitAlloc,
+    /// Generation count for each slot, advanced on every free
+    generations: Vec<AtomicGenerationType>,
+}
+
+impl SlotAlloc {
+    /// Usual new.
+    pub fn new(size: usize) -> Self {
+        let bits = BitAlloc::new(size);
+        let mut generations: Vec<AtomicGenerationType> = Vec::new();
+        generations.resize_with(bits.len(), || AtomicGenerationType::new(0));
+        Self { bits, generations }
+    }
+
+    /// Length, in slots
+    pub fn len(&self) -> usize {
+        self.bits.len()
+    }
+
+    /// True if there are no slots at all.
+    pub fn is_empty(&self) -> bool {
+        self.bits.is_empty()
+    }
+
+    /// Allocate a slot, if any are available.
+    pub fn alloc(&self) -> Option<SlotHandle> {
+        let index = self.bits.alloc_bit()?;
+        let generation = self.generations[index].load(Ordering::SeqCst);
+        Some(SlotHandle { index, generation })
+    }
+
+    /// Check that a handle is current, and return its index.
+    pub fn lookup(&self, handle: SlotHandle) -> Result<usize, Error> {
+        if self.is_valid(handle) {
+            Ok(handle.index)
+        } else {
+            Err(anyhow!(
+                "Stale slot handle: index {}, generation {}.",
+                handle.index,
+                handle.generation
+            ))
+        }
+    }
+
+    /// True if the handle refers to a slot which is still allocated to it.
+    pub fn is_valid(&self, handle: SlotHandle) -> bool {
+        match self.generations.get(handle.index) {
+            Some(generation) => {
+                generation.load(Ordering::SeqCst) == handle.generation
+                    && self.bits.get_bit(handle.index)
+            }
+            None => false,
+        }
+    }
+
+    /// Free a slot. Fails if the handle is stale, which includes freeing it twice.
+    pub fn free(&self, handle: SlotHandle) -> Result<(), Error> {
+        let generation = self
+            .generations
+            .get(handle.index)
+            .ok_or_else(|| anyhow!("Slot handle index {} out of range.", handle.index))?;
+        //  Advancing the generation is the atomic step which decides who frees the slot.
+        //  Only one free of a given handle can win this.
+        let swap_result = generation.compare_exchange(
+            handle.generation,
+            handle.generation.wrapping_add(1),
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+        if let Err(current) = swap_result {
+            return Err(anyhow!(
+                "Free of stale slot handle: index {}, generation {}, current generation {}.",
+                handle.index,
+                handle.generation,
+                current
+            ));
+        }
+        self.bits.clear_bit(handle.index)?;
+        Ok(())
+    }
+}
+
+#[test]
+/// Stale handles must be rejected.
+fn test_slotalloc_generations() {
+    let slots = SlotAlloc::new(100);
+    let h0 = slots.alloc().unwrap();
+    let h1 = slots.alloc().unwrap();
+    assert_eq!(slots.lookup(h0).unwrap(), 0);
+    assert_eq!(slots.lookup(h1).unwrap(), 1);
+    //  Free and reuse slot 0. The old handle must now be stale.
+    slots.free(h0).unwrap();
+    assert!(!slots.is_valid(h0));
+    let h0b = slots.alloc().unwrap();
+    assert_eq!(h0b.index(), h0.index());
+    assert_ne!(h0b.generation(), h0.generation());
+    assert!(slots.lookup(h0).is_err());
+    assert_eq!(slots.lookup(h0b).unwrap(), 0);
+    //  Double free, or free through a stale handle, is an error and does not disturb the new owner.
+    assert!(slots.free(h0).is_err());
+    assert!(slots.is_valid(h0b));
+    slots.free(h0b).unwrap();
+    assert!(slots.free(h0b).is_err());
+}
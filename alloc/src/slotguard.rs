@@ -0,0 +1,97 @@
+//! # Slotguard -- RAII ownership of a BitAlloc bit.
+//!
+//! A SlotGuard owns one allocated bit and releases it when dropped.
+//! It cannot be copied or cloned, so the bit cannot be cleared twice
+//! through it. Getting the raw index back out, without the release
+//! on drop, has to be asked for with `into_raw`.
+//!
+//! Animats
+//! November, 2024
+//!
+#![forbid(unsafe_code)]
+use crate::bitalloc::BitAlloc;
+use std::sync::Arc;
+
+/// Owner of one allocated bit.
+pub struct SlotGuard {
+    /// Allocator which owns the bitmap
+    bit_alloc: Arc<BitAlloc>,
+    /// Index of the bit we own
+    index: usize,
+}
+
+impl SlotGuard {
+    /// Allocate a bit, if any are available, and return a guard which owns it.
+    pub fn new(bit_alloc: &Arc<BitAlloc>) -> Option<Self> {
+        let index = bit_alloc.alloc_bit()?;
+        Some(Self {
+            bit_alloc: Arc::clone(bit_alloc),
+            index,
+        })
+    }
+
+    /// Take ownership of a bit allocated earlier and given up with `into_raw`.
+    ///
+    /// The caller must actually own the bit, or it will be cleared out from under its real owner.
+    pub fn from_raw(bit_alloc: &Arc<BitAlloc>, index: usize) -> Self {
+        debug_assert!(
+            bit_alloc.get_bit(index),
+            "SlotGuard::from_raw on a clear bit"
+        );
+        Self {
+            bit_alloc: Arc::clone(bit_alloc),
+            index,
+        }
+    }
+
+    /// The index of the bit we own.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Give up ownership without releasing the bit.
+    /// The caller becomes responsible for clearing it.
+    pub fn into_raw(self) -> usize {
+        let index = self.index;
+        std::mem::forget(self); // don't run drop, which would clear the bit
+        index
+    }
+}
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.bit_alloc.clear_bit(self.index) {
+            log::error!("SlotGuard drop: {:?}", e);
+        }
+    }
+}
+
+impl BitAlloc {
+    /// Allocate a bit, if any are available, owned by a guard which releases it on drop.
+    pub fn alloc_guard(self: &Arc<Self>) -> Option<SlotGuard> {
+        SlotGuard::new(self)
+    }
+}
+
+#[test]
+/// Guards release their bits on drop, unless converted to raw.
+fn test_slotguard_drop() {
+    fn assert_send<T: Send>() {}
+    assert_send::<SlotGuard>();
+    let bit_alloc: Arc<BitAlloc> = Arc::new(BitAlloc::new(100));
+    let g0 = bit_alloc.alloc_guard().unwrap();
+    let g1 = bit_alloc.alloc_guard().unwrap();
+    assert_eq!(g0.index(), 0);
+    assert_eq!(g1.index(), 1);
+    drop(g0);
+    assert!(!bit_alloc.get_bit(0));
+    assert!(bit_alloc.get_bit(1));
+    //  Raw conversion keeps the bit set after the guard is gone.
+    let raw = g1.into_raw();
+    assert!(bit_alloc.get_bit(raw));
+    //  And taking it back re-establishes ownership.
+    let g1 = SlotGuard::from_raw(&bit_alloc, raw);
+    //  Guards can move to other threads and be dropped there.
+    std::thread::spawn(move || drop(g1)).join().unwrap();
+    assert!(!bit_alloc.get_bit(raw));
+}
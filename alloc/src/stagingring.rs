@@ -0,0 +1,315 @@
+//! # Stagingring -- per-frame ring allocator for upload staging memory.
+//!
+//! Uploads go through CPU-visible staging memory, which is only needed until
+//! the GPU has done the copy. So staging space is handed out from a ring,
+//! in order, and taken back a whole frame at a time when that frame's fence retires.
+//!
+//! Allocation is a compare and swap on the ring's head, so any number of
+//! threads can allocate at once without locking. If the ring is full, the
+//! allocation fails at once with `RingError::Full`. The caller can wait for
+//! a frame to retire, or fall back to some other memory. It never stalls here.
+//!
+//! Positions are kept as byte counts since creation, which never wrap.
+//! The offset in the ring is the position modulo the capacity.
+//!
+//! Animats
+//! November, 2024
+//!
+#![forbid(unsafe_code)]
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// A range of staging memory. Good until the frame it belongs to retires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StagingRange {
+    /// Offset into the staging buffer
+    offset: u64,
+    /// Size in bytes
+    size: u64,
+    /// Frame this range belongs to
+    epoch: u64,
+}
+
+impl StagingRange {
+    /// Offset into the staging buffer.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Size in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The frame this range belongs to. The copy out of it must be submitted
+    /// with this frame, or one whose fence retires no later.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+}
+
+/// Reasons a staging allocation can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RingError {
+    /// Not enough space until some frame retires. Wait, or use other memory.
+    Full,
+    /// Bigger than the whole ring. This will never succeed.
+    TooLarge(u64),
+    /// Alignment is not a power of two dividing the capacity. This will never succeed.
+    BadAlignment(u64),
+}
+
+impl std::fmt::Display for RingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RingError::Full => write!(f, "Staging ring full."),
+            RingError::TooLarge(size) => {
+                write!(
+                    f,
+                    "Staging request of {} bytes is bigger than the ring.",
+                    size
+                )
+            }
+            RingError::BadAlignment(alignment) => {
+                write!(
+                    f,
+                    "Staging alignment of {} is not a power of two dividing the ring size.",
+                    alignment
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for RingError {}
+
+/// End of a frame's allocations.
+struct FrameMark {
+    /// The frame
+    epoch: u64,
+    /// Head position when the frame ended
+    head: u64,
+}
+
+/// Lock-free ring allocator for staging memory.
+pub struct StagingRing {
+    /// Size of the staging buffer
+    capacity: u64,
+    /// Position of the next free byte
+    head: AtomicU64,
+    /// Position of the oldest byte still in use
+    tail: AtomicU64,
+    /// Frame being built
+    current_epoch: AtomicU64,
+    /// Where each unretired frame's allocations end, oldest first.
+    /// Only the render thread touches this, so the lock is never contended.
+    marks: Mutex<VecDeque<FrameMark>>,
+}
+
+impl StagingRing {
+    /// Usual new. `capacity` is the size of the staging buffer.
+    pub fn new(capacity: u64) -> Self {
+        assert!(capacity > 0, "Staging ring capacity is zero");
+        Self {
+            capacity,
+            head: AtomicU64::new(0),
+            tail: AtomicU64::new(0),
+            current_epoch: AtomicU64::new(0),
+            marks: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Size of the staging buffer.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Bytes in use, waiting for their frames to retire. Includes space skipped at wraparound.
+    pub fn used(&self) -> u64 {
+        self.head.load(Ordering::SeqCst) - self.tail.load(Ordering::SeqCst)
+    }
+
+    /// The frame being built.
+    pub fn current_epoch(&self) -> u64 {
+        self.current_epoch.load(Ordering::SeqCst)
+    }
+
+    /// Allocate `size` bytes aligned to `alignment`, for the frame being built.
+    ///
+    /// Any thread, any time. Does not block. A range never wraps around the end
+    /// of the buffer, so `alignment` must be a power of two dividing the capacity,
+    /// or this returns `BadAlignment`.
+    pub fn alloc(&self, size: u64, alignment: u64) -> Result<StagingRange, RingError> {
+        if !alignment.is_power_of_two() || !self.capacity.is_multiple_of(alignment) {
+            return Err(RingError::BadAlignment(alignment));
+        }
+        if size > self.capacity {
+            return Err(RingError::TooLarge(size));
+        }
+        //  The epoch must be read before the head is advanced. end_frame reads the
+        //  head before advancing the epoch. So a range can only be tagged with a frame
+        //  at or before the one whose mark covers it, and is never reclaimed too soon.
+        let epoch = self.current_epoch.load(Ordering::SeqCst);
+        //  Retry loop for atomic CAS
+        loop {
+            let head = self.head.load(Ordering::SeqCst);
+            let mut start = head.next_multiple_of(alignment);
+            if start % self.capacity + size > self.capacity {
+                //  Doesn't fit before the end of the buffer. Skip to the beginning.
+                start = start.next_multiple_of(self.capacity);
+            }
+            let end = start + size;
+            if end - self.tail.load(Ordering::SeqCst) > self.capacity {
+                return Err(RingError::Full);
+            }
+            let swap_result =
+                self.head
+                    .compare_exchange(head, end, Ordering::SeqCst, Ordering::Relaxed);
+            if swap_result.is_ok() {
+                return Ok(StagingRange {
+                    offset: start % self.capacity,
+                    size,
+                    epoch,
+                });
+            }
+            //  Another thread allocated first. Try again after its range.
+        }
+    }
+
+    /// Finish the frame being built, and start the next. Render thread only.
+    /// Returns the new epoch.
+    pub fn end_frame(&self) -> u64 {
+        let head = self.head.load(Ordering::SeqCst); // before advancing the epoch, see alloc
+        let epoch = self.current_epoch.load(Ordering::SeqCst);
+        self.marks
+            .lock()
+            .expect("Staging ring marks poisoned")
+            .push_back(FrameMark { epoch, head });
+        self.current_epoch.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// The GPU has finished frame `retired_epoch`. Reclaim the space of that frame
+    /// and all earlier ones. Render thread only. Returns the number of bytes reclaimed.
+    pub fn frame_retired(&self, retired_epoch: u64) -> u64 {
+        let mut marks = self.marks.lock().expect("Staging ring marks poisoned");
+        let mut new_tail = None;
+        while let Some(mark) = marks.front() {
+            if mark.epoch > retired_epoch {
+                break;
+            }
+            new_tail = Some(mark.head);
+            let _ = marks.pop_front();
+        }
+        match new_tail {
+            Some(new_tail) => {
+                let old_tail = self.tail.fetch_max(new_tail, Ordering::SeqCst);
+                new_tail.saturating_sub(old_tail)
+            }
+            None => 0,
+        }
+    }
+}
+
+#[test]
+/// Allocation, alignment, wraparound, and reclaim by frame.
+fn test_stagingring_frames() {
+    let ring = StagingRing::new(1000);
+    let a = ring.alloc(300, 1).unwrap();
+    let b = ring.alloc(300, 8).unwrap();
+    assert_eq!((a.offset(), a.epoch()), (0, 0));
+    assert_eq!(b.offset(), 304);
+    assert_eq!(ring.end_frame(), 1);
+    //  Frame 1. Doesn't fit before the end, so it goes back to the start, which is still in use.
+    assert_eq!(ring.alloc(500, 1), Err(RingError::Full));
+    let c = ring.alloc(300, 1).unwrap();
+    assert_eq!((c.offset(), c.epoch()), (604, 1));
+    assert_eq!(ring.alloc(2000, 1), Err(RingError::TooLarge(2000)));
+    assert_eq!(ring.alloc(16, 2048), Err(RingError::BadAlignment(2048)));
+    assert_eq!(ring.alloc(16, 3), Err(RingError::BadAlignment(3)));
+    assert_eq!(ring.end_frame(), 2);
+    //  Frame 0 retires. Its space comes back, and the wrapped allocation fits.
+    assert_eq!(ring.frame_retired(0), 604);
+    let d = ring.alloc(500, 1).unwrap();
+    assert_eq!((d.offset(), d.epoch()), (0, 2));
+    assert_eq!(ring.used(), 300 + 96 + 500); // frame 1, skipped end, frame 2
+    assert_eq!(ring.frame_retired(0), 0); // nothing more for frame 0
+    assert_eq!(ring.end_frame(), 3);
+    assert_eq!(ring.frame_retired(2), 300 + 96 + 500);
+    assert_eq!(ring.used(), 0);
+}
+
+#[test]
+/// Many threads allocating while the render thread runs frames. Live ranges must never overlap.
+fn test_stagingring_threads() {
+    use std::sync::Arc;
+    const THREADS: usize = 8;
+    const PER_THREAD: usize = 2000;
+    const CAPACITY: u64 = 1 << 16;
+    let ring = Arc::new(StagingRing::new(CAPACITY));
+    //  Which thread owns each byte of the ring, if any. 0 is nobody.
+    let owners: Arc<Vec<AtomicU64>> = Arc::new((0..CAPACITY).map(|_| AtomicU64::new(0)).collect());
+    //  Ranges handed out and not yet reclaimed.
+    let done: Arc<Mutex<Vec<StagingRange>>> = Arc::new(Mutex::new(Vec::new()));
+    //  Workers hold this shared while between allocating and recording a range.
+    //  The render thread holds it exclusively to change frames, so every range is recorded by then.
+    let gate = Arc::new(std::sync::RwLock::new(()));
+    let workers: Vec<_> = (1..=THREADS as u64)
+        .map(|me| {
+            let ring = Arc::clone(&ring);
+            let owners = Arc::clone(&owners);
+            let done = Arc::clone(&done);
+            let gate = Arc::clone(&gate);
+            std::thread::spawn(move || {
+                let mut got = 0;
+                let mut full = 0;
+                while got < PER_THREAD {
+                    let _in_use = gate.read().unwrap();
+                    match ring.alloc(1 + (got as u64 * 37 + me) % 500, 4) {
+                        Ok(range) => {
+                            for byte in range.offset()..range.offset() + range.size() {
+                                let prev = owners[byte as usize].swap(me, Ordering::SeqCst);
+                                assert_eq!(prev, 0, "byte {} handed out twice", byte);
+                            }
+                            done.lock().unwrap().push(range);
+                            got += 1;
+                        }
+                        Err(RingError::Full) => {
+                            full += 1;
+                            std::thread::yield_now();
+                        }
+                        Err(e) => panic!("{}", e),
+                    }
+                }
+                full
+            })
+        })
+        .collect();
+    //  Render thread. The GPU is pretended to be one frame behind.
+    while workers.iter().any(|w| !w.is_finished()) {
+        let _frame_change = gate.write().unwrap();
+        let epoch = ring.end_frame();
+        if epoch >= 2 {
+            //  The frame's bytes are no longer owned once it retires.
+            let retired = epoch - 2;
+            done.lock().unwrap().retain(|range| {
+                if range.epoch() <= retired {
+                    for byte in range.offset()..range.offset() + range.size() {
+                        owners[byte as usize].store(0, Ordering::SeqCst);
+                    }
+                    false
+                } else {
+                    true
+                }
+            });
+            let _ = ring.frame_retired(retired);
+        }
+        drop(_frame_change);
+        std::thread::yield_now();
+    }
+    let full: usize = workers.into_iter().map(|w| w.join().unwrap()).sum();
+    log::info!("Staging ring full {} times", full);
+    let epoch = ring.end_frame();
+    let _ = ring.frame_retired(epoch);
+    assert_eq!(ring.used(), 0);
+}
@@ -0,0 +1,205 @@
+//! # Suballoc -- common interface for device memory suballocators.
+//!
+//! Each suballocator hands out offsets within one large `VkDeviceMemory` block.
+//! They differ in how they trade speed against wasted space:
+//!
+//! - TLSF fits any size and alignment closely. General purpose.
+//! - Buddy rounds every request up to a power of two. For power-of-two textures
+//!   that costs nothing, and freed space always merges back into big blocks.
+//!
+//! Code above this level, such as `TextureBufferHandle::new`, works through
+//! `SubAllocator` and picks a strategy with `AllocatorKind`.
+//!
+//! Animats
+//! November, 2024
+//!
+#![forbid(unsafe_code)]
+use crate::buddy::BuddyAlloc;
+use crate::tlsf::TlsfAlloc;
+
+/// Placement class of a resource, for `bufferImageGranularity`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Tiling {
+    /// Buffers, and images with `VK_IMAGE_TILING_LINEAR`
+    Linear,
+    /// Images with `VK_IMAGE_TILING_OPTIMAL`
+    Optimal,
+}
+
+/// One allocation from a suballocator. Not Clone, so it can only be freed once.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SubAllocation {
+    /// Offset into the memory block
+    offset: u64,
+    /// Size requested
+    size: u64,
+    /// Bookkeeping for the allocator which made this
+    tag: u32,
+}
+
+impl SubAllocation {
+    /// Usual new. For suballocators only.
+    pub(crate) fn new(offset: u64, size: u64, tag: u32) -> Self {
+        Self { offset, size, tag }
+    }
+
+    /// Offset into the memory block. This goes to `vkBindBufferMemory` or `vkBindImageMemory`.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Size, as requested.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Allocator bookkeeping, such as a table index.
+    pub(crate) fn tag(&self) -> u32 {
+        self.tag
+    }
+}
+
+/// Errors from freeing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubAllocError {
+    /// The allocation is not a live allocation of this allocator. It may belong to another one.
+    NotAllocated(u64),
+    /// An allocation at this offset is live, but of a different size. It belongs to another allocator.
+    WrongSize {
+        /// Offset of the allocation
+        offset: u64,
+        /// Size of the allocation being freed
+        size: u64,
+        /// Size of the live allocation at that offset
+        recorded: u64,
+    },
+}
+
+impl std::fmt::Display for SubAllocError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubAllocError::NotAllocated(offset) => {
+                write!(f, "Free at offset {}: not allocated here.", offset)
+            }
+            SubAllocError::WrongSize {
+                offset,
+                size,
+                recorded,
+            } => write!(
+                f,
+                "Free at offset {} of {} bytes: allocated here with {} bytes.",
+                offset, size, recorded
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SubAllocError {}
+
+/// A suballocator for one memory block.
+pub trait SubAllocator: Send {
+    /// Allocate `size` bytes aligned to `alignment`, for a resource with the given tiling.
+    ///
+    /// Returns None if there is no free space big enough.
+    fn alloc(&mut self, size: u64, alignment: u64, tiling: Tiling) -> Option<SubAllocation>;
+
+    /// Free an allocation.
+    fn free(&mut self, allocation: SubAllocation) -> Result<(), SubAllocError>;
+
+    /// Size of the memory block being managed.
+    fn size(&self) -> u64;
+
+    /// Bytes allocated, as requested.
+    fn used(&self) -> u64;
+
+    /// Bytes taken up by allocations, including any rounding up of the requests.
+    fn reserved(&self) -> u64;
+
+    /// Number of live allocations.
+    fn allocation_count(&self) -> usize;
+
+    /// Size of the largest free block.
+    fn largest_free(&self) -> u64;
+
+    /// True if nothing is allocated.
+    fn is_empty(&self) -> bool {
+        self.allocation_count() == 0
+    }
+
+    /// Internal fragmentation, 0.0 to 1.0. The fraction of reserved space
+    /// which was not asked for, lost to rounding up requests.
+    fn internal_fragmentation(&self) -> f64 {
+        let reserved = self.reserved();
+        if reserved > 0 {
+            (reserved - self.used()) as f64 / reserved as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Which suballocator to use for a memory block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AllocatorKind {
+    /// Two-level segregated fit. Any size.
+    Tlsf,
+    /// Buddy system. Best for power-of-two textures.
+    Buddy,
+}
+
+impl AllocatorKind {
+    /// Smallest block the buddy allocator hands out, unless `bufferImageGranularity` is larger.
+    /// A 32x32 RGBA8 texture.
+    pub const BUDDY_MIN_BLOCK: u64 = 4096;
+
+    /// The better kind for a request of `size` bytes.
+    /// Power-of-two sizes go to the buddy allocator, where they waste nothing.
+    pub fn for_size(size: u64) -> Self {
+        if size.is_power_of_two() && size >= Self::BUDDY_MIN_BLOCK {
+            AllocatorKind::Buddy
+        } else {
+            AllocatorKind::Tlsf
+        }
+    }
+
+    /// A new suballocator of this kind for a memory block of `size` bytes.
+    ///
+    /// `granularity` is the device's `bufferImageGranularity`. A buddy allocator
+    /// needs a power-of-two `size`, and uses blocks of at least one granularity page,
+    /// so linear and optimal resources never share a page.
+    pub fn new_allocator(self, size: u64, granularity: u64) -> Box<dyn SubAllocator> {
+        match self {
+            AllocatorKind::Tlsf => Box::new(TlsfAlloc::new(size, granularity)),
+            AllocatorKind::Buddy => Box::new(BuddyAlloc::new(
+                size,
+                Self::BUDDY_MIN_BLOCK.max(granularity),
+            )),
+        }
+    }
+}
+
+#[test]
+/// Both kinds work through the trait, and the choice of kind follows the size.
+fn test_suballoc_kinds() {
+    assert_eq!(
+        AllocatorKind::for_size(4096 * 4096 * 4),
+        AllocatorKind::Buddy
+    );
+    assert_eq!(AllocatorKind::for_size(1000), AllocatorKind::Tlsf);
+    assert_eq!(AllocatorKind::for_size(1024), AllocatorKind::Tlsf); // below the smallest buddy block
+    for kind in [AllocatorKind::Tlsf, AllocatorKind::Buddy] {
+        let mut suballoc = kind.new_allocator(1 << 24, 1024);
+        let a = suballoc.alloc(64 * 64 * 4, 256, Tiling::Optimal).unwrap();
+        let b = suballoc.alloc(5000, 16, Tiling::Linear).unwrap();
+        assert_eq!(suballoc.allocation_count(), 2);
+        assert_eq!(suballoc.used(), 64 * 64 * 4 + 5000);
+        assert!(suballoc.reserved() >= suballoc.used());
+        //  Different tilings, so different granularity pages.
+        assert!(b.offset() / 1024 != (a.offset() + a.size() - 1) / 1024);
+        suballoc.free(a).unwrap();
+        suballoc.free(b).unwrap();
+        assert!(suballoc.is_empty());
+        assert_eq!(suballoc.largest_free(), 1 << 24);
+        assert_eq!(suballoc.internal_fragmentation(), 0.0);
+    }
+}
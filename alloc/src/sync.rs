@@ -0,0 +1,13 @@
+//! # Sync -- atomic types used by the allocators.
+//!
+//! Normally these are the standard library atomics. When built with
+//! `RUSTFLAGS="--cfg loom"`, they are loom's model-checked versions instead,
+//! so the allocators can be run under loom. See `loomtests.rs`.
+//!
+//! Animats
+//! November, 2024
+//!
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+#[cfg(not(loom))]
+pub(crate) use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
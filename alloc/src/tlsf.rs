@@ -0,0 +1,697 @@
+//! # Tlsf -- two-level segregated fit suballocator for GPU device memory.
+//!
+//! Vulkan implementations allow only a few thousand `vkAllocateMemory` calls,
+//! so device memory is obtained in large blocks and carved up here.
+//! This allocator only deals in offsets. It never touches the memory, so it
+//! is plain Rust and can be tested without a GPU.
+//!
+//! Free space is kept in lists by size class. A size class is a power of two
+//! (first level) split linearly into 32 steps (second level). Bitmaps over the
+//! lists find the smallest nonempty class that fits in a few instructions, so
+//! allocation and free are O(1). Freed blocks are merged with free neighbors at once.
+//!
+//! Two Vulkan placement rules are honored:
+//!
+//! - Alignment, from `VkMemoryRequirements::alignment`. Always a power of two.
+//! - `bufferImageGranularity`. Linear resources (buffers and linear images) and
+//!   optimal-tiled images must not share a page of that size. Neighbors of the same
+//!   kind can share a page, so no space is lost unless the kinds alternate.
+//!
+//! The metadata lives in a CPU-side table, not in the memory being managed.
+//! Not thread safe by itself. One of these per memory block, behind a lock.
+//!
+//! Animats
+//! November, 2024
+//!
+#![forbid(unsafe_code)]
+use crate::suballoc::{SubAllocError, SubAllocation, SubAllocator, Tiling};
+
+/// Log2 of the number of second level lists per first level class
+const SL_LOG2: u32 = 5;
+/// Number of second level lists per first level class
+const SL_COUNT: usize = 1 << SL_LOG2;
+/// Number of first level classes. Enough for any u64 size.
+const FL_COUNT: usize = (u64::BITS - SL_LOG2 + 1) as usize;
+
+/// State of a block table entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockState {
+    /// Free space, on the free list for its size class
+    Free {
+        /// Previous block in the same free list
+        prev_free: Option<u32>,
+        /// Next block in the same free list
+        next_free: Option<u32>,
+    },
+    /// Allocated
+    Used(Tiling),
+    /// Table entry not describing any memory, available for reuse
+    Unused,
+}
+
+/// A contiguous piece of the memory block, free or allocated.
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    /// Start offset
+    offset: u64,
+    /// Size in bytes
+    size: u64,
+    /// Block just below this one in memory
+    prev_phys: Option<u32>,
+    /// Block just above this one in memory
+    next_phys: Option<u32>,
+    /// Free, used, or unused table entry
+    state: BlockState,
+}
+
+impl Block {
+    /// One past the last byte
+    fn end(&self) -> u64 {
+        self.offset + self.size
+    }
+}
+
+/// TLSF suballocator for one memory block.
+pub struct TlsfAlloc {
+    /// Size of the memory block
+    size: u64,
+    /// `bufferImageGranularity`, a power of two
+    granularity: u64,
+    /// All blocks, free and used, linked in memory order
+    blocks: Vec<Block>,
+    /// Table entries available for reuse
+    unused: Vec<u32>,
+    /// Head of each free list, indexed by first level * SL_COUNT + second level
+    heads: Vec<Option<u32>>,
+    /// A set bit means that first level class has a nonempty list
+    fl_bitmap: u64,
+    /// A set bit means that second level list is nonempty
+    sl_bitmap: [u32; FL_COUNT],
+    /// Bytes allocated, as requested
+    used: u64,
+    /// Live allocations
+    allocation_count: usize,
+}
+
+impl TlsfAlloc {
+    /// Usual new. Manages `size` bytes, with the device's `bufferImageGranularity`.
+    pub fn new(size: u64, granularity: u64) -> Self {
+        assert!(size > 0, "Tlsf memory block size is zero");
+        assert!(
+            granularity.is_power_of_two(),
+            "bufferImageGranularity {} is not a power of two",
+            granularity
+        );
+        let mut tlsf = Self {
+            size,
+            granularity,
+            blocks: Vec::new(),
+            unused: Vec::new(),
+            heads: vec![None; FL_COUNT * SL_COUNT],
+            fl_bitmap: 0,
+            sl_bitmap: [0; FL_COUNT],
+            used: 0,
+            allocation_count: 0,
+        };
+        let all = tlsf.new_block(Block {
+            offset: 0,
+            size,
+            prev_phys: None,
+            next_phys: None,
+            state: BlockState::Unused,
+        });
+        tlsf.insert_free(all);
+        tlsf
+    }
+
+    /// The usual O(1) search. Returns a free block and the offset in it.
+    fn find_block(&self, size: u64, alignment: u64, tiling: Tiling) -> Option<(u32, u64)> {
+        //  Start with the smallest class whose blocks are all at least `size`.
+        //  Padding may make the head block of that list too small. Then try the next larger list.
+        let (mut fl, mut sl) = Self::mapping(Self::round_up(size));
+        while fl < FL_COUNT {
+            let (found_fl, found_sl) = self.find_suitable(fl, sl)?;
+            let block =
+                self.heads[found_fl * SL_COUNT + found_sl].expect("Tlsf bitmap and list disagree");
+            if let Some(offset) = self.placement(block, size, alignment, tiling) {
+                return Some((block, offset));
+            }
+            (fl, sl) = if found_sl + 1 < SL_COUNT {
+                (found_fl, found_sl + 1)
+            } else {
+                (found_fl + 1, 0)
+            };
+        }
+        None
+    }
+
+    /// Last resort. Look through the list holding blocks of about `size`,
+    /// some of which may fit even though rounding up skipped them.
+    /// This matters when nearly all the memory is wanted at once.
+    fn find_block_exact(&self, size: u64, alignment: u64, tiling: Tiling) -> Option<(u32, u64)> {
+        let (fl, sl) = Self::mapping(size);
+        let mut ix = self.heads[fl * SL_COUNT + sl];
+        while let Some(block) = ix {
+            if let Some(offset) = self.placement(block, size, alignment, tiling) {
+                return Some((block, offset));
+            }
+            ix = match self.blocks[block as usize].state {
+                BlockState::Free { next_free, .. } => next_free,
+                _ => None,
+            };
+        }
+        None
+    }
+
+    /// Where in free block `ix` an allocation would go, if it fits.
+    fn placement(&self, ix: u32, size: u64, alignment: u64, tiling: Tiling) -> Option<u64> {
+        let block = &self.blocks[ix as usize];
+        let mut offset = block.offset.next_multiple_of(alignment);
+        let mut limit = block.end();
+        if self.granularity > 1 {
+            //  Free blocks are never adjacent, so the neighbors are allocated, if they exist.
+            if let Some(prev) = block.prev_phys {
+                let prev = &self.blocks[prev as usize];
+                if self.conflicts(prev, tiling) && self.page(prev.end() - 1) == self.page(offset) {
+                    offset = offset.next_multiple_of(self.granularity.max(alignment));
+                }
+            }
+            if let Some(next) = block.next_phys {
+                let next = &self.blocks[next as usize];
+                if self.conflicts(next, tiling) {
+                    //  Must end before the page holding the start of the next block.
+                    limit = limit.min(next.offset - next.offset % self.granularity);
+                }
+            }
+        }
+        if offset.checked_add(size)? <= limit {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    /// True if a used block and a new resource of `tiling` can't share a page.
+    fn conflicts(&self, block: &Block, tiling: Tiling) -> bool {
+        matches!(block.state, BlockState::Used(t) if t != tiling)
+    }
+
+    /// Granularity page holding an offset.
+    fn page(&self, offset: u64) -> u64 {
+        offset / self.granularity
+    }
+
+    /// Allocate `offset..offset+size` from free block `ix`. Leftovers on either side become free blocks.
+    fn use_block(&mut self, ix: u32, offset: u64, size: u64, tiling: Tiling) {
+        self.remove_free(ix);
+        let block = self.blocks[ix as usize];
+        if offset > block.offset {
+            //  Padding in front.
+            let front = self.split(ix, offset - block.offset);
+            self.insert_free(front);
+        }
+        if self.blocks[ix as usize].size > size {
+            //  Space left over at the end.
+            let rest = self.split_tail(ix, size);
+            self.insert_free(rest);
+        }
+        self.blocks[ix as usize].state = BlockState::Used(tiling);
+        self.used += size;
+        self.allocation_count += 1;
+    }
+
+    /// Split `front_size` bytes off the front of block `ix` as a new block, which is returned.
+    /// Block `ix` keeps the rest, so its table index, and any allocation referring to it, stay put.
+    fn split(&mut self, ix: u32, front_size: u64) -> u32 {
+        let block = self.blocks[ix as usize];
+        let front = self.new_block(Block {
+            offset: block.offset,
+            size: front_size,
+            prev_phys: block.prev_phys,
+            next_phys: Some(ix),
+            state: BlockState::Unused,
+        });
+        if let Some(prev) = block.prev_phys {
+            self.blocks[prev as usize].next_phys = Some(front);
+        }
+        let block = &mut self.blocks[ix as usize];
+        block.offset += front_size;
+        block.size -= front_size;
+        block.prev_phys = Some(front);
+        front
+    }
+
+    /// Cut block `ix` down to `size` bytes. The rest becomes a new block, which is returned.
+    fn split_tail(&mut self, ix: u32, size: u64) -> u32 {
+        let block = self.blocks[ix as usize];
+        let rest = self.new_block(Block {
+            offset: block.offset + size,
+            size: block.size - size,
+            prev_phys: Some(ix),
+            next_phys: block.next_phys,
+            state: BlockState::Unused,
+        });
+        if let Some(next) = block.next_phys {
+            self.blocks[next as usize].prev_phys = Some(rest);
+        }
+        let block = &mut self.blocks[ix as usize];
+        block.size = size;
+        block.next_phys = Some(rest);
+        rest
+    }
+
+    /// Merge physically adjacent blocks `low` and `high`, which are not on free lists.
+    /// `low` survives and is returned.
+    fn merge(&mut self, low: u32, high: u32) -> u32 {
+        let high_block = self.blocks[high as usize];
+        debug_assert_eq!(self.blocks[low as usize].next_phys, Some(high));
+        let low_block = &mut self.blocks[low as usize];
+        low_block.size += high_block.size;
+        low_block.next_phys = high_block.next_phys;
+        if let Some(next) = high_block.next_phys {
+            self.blocks[next as usize].prev_phys = Some(low);
+        }
+        self.blocks[high as usize].state = BlockState::Unused;
+        self.unused.push(high);
+        low
+    }
+
+    /// Put a block in the table, reusing an unused entry if possible.
+    fn new_block(&mut self, block: Block) -> u32 {
+        match self.unused.pop() {
+            Some(ix) => {
+                self.blocks[ix as usize] = block;
+                ix
+            }
+            None => {
+                self.blocks.push(block);
+                u32::try_from(self.blocks.len() - 1).expect("Tlsf block table overflow")
+            }
+        }
+    }
+
+    /// Add block `ix` to the free list for its size.
+    fn insert_free(&mut self, ix: u32) {
+        let (fl, sl) = Self::mapping(self.blocks[ix as usize].size);
+        let list = fl * SL_COUNT + sl;
+        let head = self.heads[list];
+        self.blocks[ix as usize].state = BlockState::Free {
+            prev_free: None,
+            next_free: head,
+        };
+        if let Some(head) = head {
+            if let BlockState::Free { prev_free, .. } = &mut self.blocks[head as usize].state {
+                *prev_free = Some(ix);
+            }
+        }
+        self.heads[list] = Some(ix);
+        self.fl_bitmap |= 1 << fl;
+        self.sl_bitmap[fl] |= 1 << sl;
+    }
+
+    /// Take block `ix` off its free list.
+    fn remove_free(&mut self, ix: u32) {
+        let BlockState::Free {
+            prev_free,
+            next_free,
+        } = self.blocks[ix as usize].state
+        else {
+            panic!("Tlsf block {} is not free", ix);
+        };
+        if let Some(prev) = prev_free {
+            if let BlockState::Free { next_free: n, .. } = &mut self.blocks[prev as usize].state {
+                *n = next_free;
+            }
+        }
+        if let Some(next) = next_free {
+            if let BlockState::Free { prev_free: p, .. } = &mut self.blocks[next as usize].state {
+                *p = prev_free;
+            }
+        }
+        let (fl, sl) = Self::mapping(self.blocks[ix as usize].size);
+        let list = fl * SL_COUNT + sl;
+        if self.heads[list] == Some(ix) {
+            self.heads[list] = next_free;
+            if next_free.is_none() {
+                self.sl_bitmap[fl] &= !(1 << sl);
+                if self.sl_bitmap[fl] == 0 {
+                    self.fl_bitmap &= !(1 << fl);
+                }
+            }
+        }
+        self.blocks[ix as usize].state = BlockState::Unused;
+    }
+
+    /// First and second level list indices for a size.
+    fn mapping(size: u64) -> (usize, usize) {
+        if size < SL_COUNT as u64 {
+            (0, size as usize)
+        } else {
+            let log2 = u64::BITS - 1 - size.leading_zeros();
+            let sl = ((size >> (log2 - SL_LOG2)) as usize) ^ SL_COUNT;
+            ((log2 - SL_LOG2 + 1) as usize, sl)
+        }
+    }
+
+    /// Round a request up to the next list boundary, so every block on the list found will fit it.
+    fn round_up(size: u64) -> u64 {
+        if size < SL_COUNT as u64 {
+            size
+        } else {
+            let log2 = u64::BITS - 1 - size.leading_zeros();
+            size.saturating_add((1 << (log2 - SL_LOG2)) - 1)
+        }
+    }
+
+    /// The first nonempty list at or above list (fl, sl).
+    fn find_suitable(&self, fl: usize, sl: usize) -> Option<(usize, usize)> {
+        let sl_map = self.sl_bitmap[fl] & (u32::MAX << sl);
+        if sl_map != 0 {
+            return Some((fl, sl_map.trailing_zeros() as usize));
+        }
+        let fl_map = self.fl_bitmap & u64::MAX.checked_shl(fl as u32 + 1).unwrap_or(0);
+        if fl_map == 0 {
+            return None; // nothing big enough
+        }
+        let fl = fl_map.trailing_zeros() as usize;
+        Some((fl, self.sl_bitmap[fl].trailing_zeros() as usize))
+    }
+
+    /// Allocate exactly `offset..offset+size`, which must be free.
+    /// A linear search, for defragmentation only. The caller has checked the placement rules.
+    pub(crate) fn alloc_at(
+        &mut self,
+        offset: u64,
+        size: u64,
+        tiling: Tiling,
+    ) -> Option<SubAllocation> {
+        let ix = self.blocks.iter().position(|b| {
+            matches!(b.state, BlockState::Free { .. })
+                && b.offset <= offset
+                && offset.checked_add(size).is_some_and(|end| end <= b.end())
+        })?;
+        let ix = u32::try_from(ix).expect("Tlsf block table overflow");
+        self.use_block(ix, offset, size, tiling);
+        Some(SubAllocation::new(offset, size, ix))
+    }
+
+    /// Check all the internal links and bitmaps. For tests.
+    #[cfg(test)]
+    fn validate(&self) {
+        //  Walk memory order from offset 0.
+        let first = (0..self.blocks.len() as u32)
+            .find(|&ix| {
+                self.blocks[ix as usize].state != BlockState::Unused
+                    && self.blocks[ix as usize].offset == 0
+            })
+            .expect("no block at offset 0");
+        let mut ix = Some(first);
+        let mut prev: Option<u32> = None;
+        let mut offset = 0;
+        let mut free_count = 0;
+        while let Some(block_ix) = ix {
+            let block = &self.blocks[block_ix as usize];
+            assert_eq!(block.offset, offset, "gap or overlap at {}", offset);
+            assert_eq!(block.prev_phys, prev);
+            assert!(block.size > 0);
+            if let BlockState::Free { .. } = block.state {
+                free_count += 1;
+                if let Some(p) = prev {
+                    assert!(
+                        !matches!(self.blocks[p as usize].state, BlockState::Free { .. }),
+                        "adjacent free blocks"
+                    );
+                }
+                let (fl, sl) = Self::mapping(block.size);
+                assert!(self.sl_bitmap[fl] & (1 << sl) != 0);
+            }
+            offset = block.end();
+            prev = ix;
+            ix = block.next_phys;
+        }
+        assert_eq!(offset, self.size);
+        //  Every free list entry is a free block, and the lists hold all of them.
+        let listed: usize = self
+            .heads
+            .iter()
+            .map(|&head| {
+                let mut n = 0;
+                let mut ix = head;
+                while let Some(block) = ix {
+                    let BlockState::Free { next_free, .. } = self.blocks[block as usize].state
+                    else {
+                        panic!("non-free block {} on free list", block);
+                    };
+                    n += 1;
+                    ix = next_free;
+                }
+                n
+            })
+            .sum();
+        assert_eq!(listed, free_count);
+    }
+}
+
+impl SubAllocator for TlsfAlloc {
+    fn alloc(&mut self, size: u64, alignment: u64, tiling: Tiling) -> Option<SubAllocation> {
+        assert!(
+            alignment.is_power_of_two(),
+            "Tlsf alignment {} is not a power of two",
+            alignment
+        );
+        if size == 0 || size > self.size {
+            return None;
+        }
+        let block = self
+            .find_block(size, alignment, tiling)
+            .or_else(|| self.find_block_exact(size, alignment, tiling));
+        let (block, offset) = block?;
+        self.use_block(block, offset, size, tiling);
+        Some(SubAllocation::new(offset, size, block))
+    }
+
+    /// Free an allocation. Its space is merged with any free neighbors.
+    fn free(&mut self, allocation: SubAllocation) -> Result<(), SubAllocError> {
+        let ix = allocation.tag();
+        match self.blocks.get(ix as usize) {
+            Some(block)
+                if block.offset == allocation.offset()
+                    && matches!(block.state, BlockState::Used(_)) => {}
+            _ => return Err(SubAllocError::NotAllocated(allocation.offset())),
+        }
+        //  A used block is cut to exactly the size requested.
+        let recorded = self.blocks[ix as usize].size;
+        if recorded != allocation.size() {
+            return Err(SubAllocError::WrongSize {
+                offset: allocation.offset(),
+                size: allocation.size(),
+                recorded,
+            });
+        }
+        self.used -= allocation.size();
+        self.allocation_count -= 1;
+        //  Merge with the free neighbors, if any.
+        let mut ix = ix;
+        if let Some(prev) = self.blocks[ix as usize].prev_phys {
+            if matches!(self.blocks[prev as usize].state, BlockState::Free { .. }) {
+                self.remove_free(prev);
+                ix = self.merge(prev, ix);
+            }
+        }
+        if let Some(next) = self.blocks[ix as usize].next_phys {
+            if matches!(self.blocks[next as usize].state, BlockState::Free { .. }) {
+                self.remove_free(next);
+                ix = self.merge(ix, next);
+            }
+        }
+        self.insert_free(ix);
+        Ok(())
+    }
+
+    /// Size of the memory block being managed.
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Bytes allocated, as requested.
+    fn used(&self) -> u64 {
+        self.used
+    }
+
+    /// Same as `used`. Alignment padding stays free space, so nothing is rounded up.
+    fn reserved(&self) -> u64 {
+        self.used
+    }
+
+    /// Number of live allocations.
+    fn allocation_count(&self) -> usize {
+        self.allocation_count
+    }
+
+    /// Size of the largest free block. Allocations up to this size, less padding, can succeed.
+    fn largest_free(&self) -> u64 {
+        if self.fl_bitmap == 0 {
+            return 0;
+        }
+        let fl = (u64::BITS - 1 - self.fl_bitmap.leading_zeros()) as usize;
+        let sl = (u32::BITS - 1 - self.sl_bitmap[fl].leading_zeros()) as usize;
+        let mut ix = self.heads[fl * SL_COUNT + sl];
+        let mut largest = 0;
+        //  Sizes within one list vary by up to one step, so look at all of them.
+        while let Some(block) = ix {
+            largest = largest.max(self.blocks[block as usize].size);
+            ix = match self.blocks[block as usize].state {
+                BlockState::Free { next_free, .. } => next_free,
+                _ => None,
+            };
+        }
+        largest
+    }
+}
+
+#[test]
+/// Allocate, free, and merge back to one block.
+fn test_tlsf_basics() {
+    let mut tlsf = TlsfAlloc::new(1 << 20, 1);
+    let a = tlsf.alloc(1000, 1, Tiling::Linear).unwrap();
+    let b = tlsf.alloc(3000, 256, Tiling::Linear).unwrap();
+    let c = tlsf.alloc(5, 1, Tiling::Linear).unwrap();
+    tlsf.validate();
+    assert_eq!(a.offset(), 0);
+    assert_eq!(b.offset(), 1024); // aligned up past a
+    assert_eq!(c.offset(), 1000); // fits in the alignment padding
+    assert_eq!(tlsf.used(), 4005);
+    assert_eq!(tlsf.allocation_count(), 3);
+    //  Free in an order which merges on both sides.
+    tlsf.free(a).unwrap();
+    tlsf.free(c).unwrap();
+    tlsf.validate();
+    tlsf.free(b).unwrap();
+    tlsf.validate();
+    assert!(tlsf.is_empty());
+    assert_eq!(tlsf.largest_free(), 1 << 20);
+    //  Whole block, then nothing left.
+    let all = tlsf.alloc(1 << 20, 1, Tiling::Optimal).unwrap();
+    assert!(tlsf.alloc(1, 1, Tiling::Optimal).is_none());
+    assert_eq!(tlsf.largest_free(), 0);
+    tlsf.free(all).unwrap();
+    assert!(tlsf.alloc((1 << 20) + 1, 1, Tiling::Linear).is_none());
+    assert!(tlsf.alloc(0, 1, Tiling::Linear).is_none());
+}
+
+#[test]
+/// Linear and optimal resources never share a granularity page. Same kinds may.
+fn test_tlsf_granularity() {
+    const PAGE: u64 = 1024;
+    let mut tlsf = TlsfAlloc::new(64 * PAGE, PAGE);
+    let buf0 = tlsf.alloc(100, 16, Tiling::Linear).unwrap();
+    let buf1 = tlsf.alloc(100, 16, Tiling::Linear).unwrap();
+    assert_eq!(buf1.offset(), 112); // same kind, same page is fine
+    let img = tlsf.alloc(100, 16, Tiling::Optimal).unwrap();
+    assert_eq!(img.offset(), PAGE); // pushed to the next page
+
+    //  The gap below the image is only usable by linear resources.
+    let buf2 = tlsf.alloc(200, 16, Tiling::Linear).unwrap();
+    assert_eq!(buf2.offset(), 224);
+    //  A linear resource right after the image must also move to a new page.
+    let buf3 = tlsf.alloc(2000, 16, Tiling::Linear).unwrap();
+    assert_eq!(buf3.offset(), 2 * PAGE);
+    //  But another image can use the rest of the image's page.
+    let img2 = tlsf.alloc(100, 16, Tiling::Optimal).unwrap();
+    assert_eq!(img2.offset(), PAGE + 112);
+    //  An image can't go in the gap between buf2 and the image page, which is linear territory.
+    tlsf.free(buf1).unwrap();
+    let img3 = tlsf.alloc(50, 16, Tiling::Optimal).unwrap();
+    assert!(img3.offset() >= PAGE);
+    tlsf.validate();
+    for a in [buf0, buf2, buf3, img, img2, img3] {
+        tlsf.free(a).unwrap();
+    }
+    tlsf.validate();
+    assert_eq!(tlsf.largest_free(), 64 * PAGE);
+}
+
+#[test]
+/// Random allocation and free. No overlaps, alignment kept, granularity kept, everything merges back.
+fn test_tlsf_churn() {
+    const SIZE: u64 = 256 << 20; // a typical device memory block
+    const PAGE: u64 = 4096;
+    let mut tlsf = TlsfAlloc::new(SIZE, PAGE);
+    let mut live: Vec<(SubAllocation, Tiling)> = Vec::new();
+    let mut rand: u64 = 0x9E3779B97F4A7C15;
+    let mut failures = 0;
+    for n in 0..20000 {
+        rand ^= rand << 13;
+        rand ^= rand >> 7;
+        rand ^= rand << 17;
+        if live.len() < 500 && !rand.is_multiple_of(3) {
+            let size = 1 + (rand >> 8) % (1 << (8 + rand % 13)); // mostly small, some up to 1MB
+            let alignment = 1 << ((rand >> 40) % 13);
+            let tiling = if rand & 0x100 != 0 {
+                Tiling::Optimal
+            } else {
+                Tiling::Linear
+            };
+            match tlsf.alloc(size, alignment, tiling) {
+                Some(a) => {
+                    assert_eq!(a.offset() % alignment, 0);
+                    assert!(a.offset() + size <= SIZE);
+                    live.push((a, tiling));
+                }
+                None => failures += 1,
+            }
+        } else if !live.is_empty() {
+            let (a, _) = live.swap_remove((rand % live.len() as u64) as usize);
+            tlsf.free(a).unwrap();
+        }
+        if n % 1000 == 0 {
+            tlsf.validate();
+            //  Check overlaps and pages against all the live allocations.
+            let mut sorted: Vec<&(SubAllocation, Tiling)> = live.iter().collect();
+            sorted.sort_by_key(|(a, _)| a.offset());
+            for pair in sorted.windows(2) {
+                let ((lo, lo_tiling), (hi, hi_tiling)) = (pair[0], pair[1]);
+                assert!(lo.offset() + lo.size() <= hi.offset(), "overlap");
+                if lo_tiling != hi_tiling {
+                    assert!(
+                        (lo.offset() + lo.size() - 1) / PAGE < hi.offset() / PAGE,
+                        "page shared"
+                    );
+                }
+            }
+        }
+    }
+    assert_eq!(failures, 0); // the block is far from full
+    assert_eq!(tlsf.used(), live.iter().map(|(a, _)| a.size()).sum::<u64>());
+    for (a, _) in live {
+        tlsf.free(a).unwrap();
+    }
+    tlsf.validate();
+    assert!(tlsf.is_empty());
+    assert_eq!(tlsf.largest_free(), SIZE);
+}
+
+#[test]
+/// Frees of allocations which are not live here are errors.
+fn test_tlsf_bad_free() {
+    let mut tlsf = TlsfAlloc::new(4096, 1);
+    let mut other = TlsfAlloc::new(4096, 1);
+    let _a = tlsf.alloc(100, 1, Tiling::Linear).unwrap();
+    let b = tlsf.alloc(100, 1, Tiling::Linear).unwrap();
+    //  Not allocated in `other`, whose only block is free.
+    assert_eq!(other.free(b), Err(SubAllocError::NotAllocated(100)));
+    //  Same table index and offset in `other`, but a different size.
+    let _c = other.alloc(100, 1, Tiling::Linear).unwrap();
+    let d = other.alloc(300, 1, Tiling::Linear).unwrap();
+    assert_eq!(
+        tlsf.free(d),
+        Err(SubAllocError::WrongSize {
+            offset: 100,
+            size: 300,
+            recorded: 100
+        })
+    );
+    assert_eq!(tlsf.used(), 200);
+    tlsf.validate();
+    other.validate();
+}
@@ -0,0 +1,274 @@
+//! # Tracker -- labeled allocation tracking and leak reports.
+//!
+//! Which subsystem owns which GPU memory? Allocators given an `AllocTracker`
+//! record each live allocation with an optional label, like the `label`
+//! fields of WGPU descriptors. Totals are kept per label. `report` lists
+//! the live allocations grouped by label.
+//!
+//! The device holds the tracker, and the allocators share it. When the last
+//! reference goes away, at device drop, anything still live is a leak, and
+//! the report is logged.
+//!
+//! Tracking takes a lock per allocation and free, so it is optional.
+//!
+//! Animats
+//! November, 2024
+//!
+#![forbid(unsafe_code)]
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// Totals for one label.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LabelTotals {
+    /// Live allocations
+    pub count: usize,
+    /// Live bytes. Slot allocations count as zero bytes.
+    pub bytes: u64,
+    /// Most live bytes at once
+    pub peak_bytes: u64,
+    /// Allocations ever made
+    pub total_count: u64,
+}
+
+/// A live allocation, as listed in a report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveAllocation {
+    /// Allocator it came from
+    pub source: String,
+    /// Allocator's index for it, such as a slot number
+    pub index: u64,
+    /// Size in bytes
+    pub bytes: u64,
+}
+
+/// Live allocations with one label.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelReport {
+    /// The label. None for unlabeled allocations.
+    pub label: Option<String>,
+    /// Totals for the label
+    pub totals: LabelTotals,
+    /// The allocations, by source and index
+    pub allocations: Vec<LiveAllocation>,
+}
+
+/// Live allocations, grouped by label. Display it for a readable listing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AllocReport {
+    /// One entry per label with live allocations, in label order
+    pub labels: Vec<LabelReport>,
+}
+
+impl AllocReport {
+    /// Number of live allocations.
+    pub fn count(&self) -> usize {
+        self.labels.iter().map(|l| l.totals.count).sum()
+    }
+
+    /// Live bytes.
+    pub fn bytes(&self) -> u64 {
+        self.labels.iter().map(|l| l.totals.bytes).sum()
+    }
+}
+
+impl std::fmt::Display for AllocReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} live allocations, {} bytes, under {} labels.",
+            self.count(),
+            self.bytes(),
+            self.labels.len()
+        )?;
+        for label in &self.labels {
+            writeln!(
+                f,
+                "  {}: {} allocations, {} bytes, peak {} bytes, {} ever allocated.",
+                label.label.as_deref().unwrap_or("(unlabeled)"),
+                label.totals.count,
+                label.totals.bytes,
+                label.totals.peak_bytes,
+                label.totals.total_count
+            )?;
+            for a in &label.allocations {
+                writeln!(f, "    {} #{}: {} bytes", a.source, a.index, a.bytes)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One live allocation.
+struct Tracked {
+    /// Owner's label
+    label: Option<String>,
+    /// Size in bytes
+    bytes: u64,
+}
+
+/// The locked part of the tracker.
+#[derive(Default)]
+struct TrackerInner {
+    /// Names of the allocators reporting here, indexed by source number
+    sources: Vec<String>,
+    /// Live allocations, by source and index
+    live: BTreeMap<(u32, u64), Tracked>,
+    /// Totals, by label
+    totals: BTreeMap<Option<String>, LabelTotals>,
+}
+
+/// Record of live allocations, shared by the allocators of one device.
+#[derive(Default)]
+pub struct AllocTracker {
+    /// Everything, behind a lock
+    inner: Mutex<TrackerInner>,
+}
+
+impl AllocTracker {
+    /// Usual new.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register an allocator. Returns its source number, for `track` and `untrack`.
+    pub fn add_source(&self, name: &str) -> u32 {
+        let mut inner = self.inner.lock().expect("Tracker lock poisoned");
+        inner.sources.push(name.to_string());
+        u32::try_from(inner.sources.len() - 1).expect("Too many tracker sources")
+    }
+
+    /// Record an allocation. `index` identifies it within its source.
+    pub fn track(&self, source: u32, index: u64, label: Option<&str>, bytes: u64) {
+        let mut inner = self.inner.lock().expect("Tracker lock poisoned");
+        let label = label.map(str::to_string);
+        let totals = inner.totals.entry(label.clone()).or_default();
+        totals.count += 1;
+        totals.bytes += bytes;
+        totals.peak_bytes = totals.peak_bytes.max(totals.bytes);
+        totals.total_count += 1;
+        if let Some(old) = inner.live.insert((source, index), Tracked { label, bytes }) {
+            //  Not untracked. Don't count it twice.
+            log::error!(
+                "Allocation {} of tracker source {} tracked twice.",
+                index,
+                source
+            );
+            Self::remove_totals(&mut inner, old);
+        }
+    }
+
+    /// Record a free. Returns false if the allocation wasn't being tracked.
+    pub fn untrack(&self, source: u32, index: u64) -> bool {
+        let mut inner = self.inner.lock().expect("Tracker lock poisoned");
+        match inner.live.remove(&(source, index)) {
+            Some(old) => {
+                Self::remove_totals(&mut inner, old);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Totals for one label.
+    pub fn totals(&self, label: Option<&str>) -> LabelTotals {
+        let inner = self.inner.lock().expect("Tracker lock poisoned");
+        inner
+            .totals
+            .get(&label.map(str::to_string))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Number of live allocations.
+    pub fn live_count(&self) -> usize {
+        self.inner.lock().expect("Tracker lock poisoned").live.len()
+    }
+
+    /// Live allocations, grouped by label.
+    pub fn report(&self) -> AllocReport {
+        let inner = self.inner.lock().expect("Tracker lock poisoned");
+        let mut labels: BTreeMap<&Option<String>, Vec<LiveAllocation>> = BTreeMap::new();
+        for (&(source, index), tracked) in &inner.live {
+            labels
+                .entry(&tracked.label)
+                .or_default()
+                .push(LiveAllocation {
+                    source: inner.sources[source as usize].clone(),
+                    index,
+                    bytes: tracked.bytes,
+                });
+        }
+        AllocReport {
+            labels: labels
+                .into_iter()
+                .map(|(label, allocations)| LabelReport {
+                    label: label.clone(),
+                    totals: inner.totals[label],
+                    allocations,
+                })
+                .collect(),
+        }
+    }
+
+    /// Take a freed allocation out of its label's totals.
+    fn remove_totals(inner: &mut TrackerInner, old: Tracked) {
+        if let Some(totals) = inner.totals.get_mut(&old.label) {
+            totals.count -= 1;
+            totals.bytes -= old.bytes;
+        }
+    }
+}
+
+impl Drop for AllocTracker {
+    fn drop(&mut self) {
+        let report = self.report();
+        if !report.labels.is_empty() {
+            log::warn!("GPU allocation leak report: {}", report);
+        }
+    }
+}
+
+#[test]
+/// Totals per label, and a report of what's left.
+fn test_tracker_labels() {
+    let tracker = AllocTracker::new();
+    let pool = tracker.add_source("pool");
+    let slots = tracker.add_source("slots");
+    tracker.track(pool, 1, Some("terrain"), 1000);
+    tracker.track(pool, 2, Some("terrain"), 3000);
+    tracker.track(pool, 3, None, 500);
+    tracker.track(slots, 1, Some("textures"), 0);
+    assert!(tracker.untrack(pool, 1));
+    assert!(!tracker.untrack(pool, 1)); // already gone
+    let terrain = tracker.totals(Some("terrain"));
+    assert_eq!(
+        terrain,
+        LabelTotals {
+            count: 1,
+            bytes: 3000,
+            peak_bytes: 4000,
+            total_count: 2
+        }
+    );
+    let report = tracker.report();
+    assert_eq!((report.count(), report.bytes()), (3, 3500));
+    let labels: Vec<Option<&str>> = report.labels.iter().map(|l| l.label.as_deref()).collect();
+    assert_eq!(labels, [None, Some("terrain"), Some("textures")]);
+    assert_eq!(
+        report.labels[1].allocations,
+        [LiveAllocation {
+            source: "pool".to_string(),
+            index: 2,
+            bytes: 3000
+        }]
+    );
+    let text = report.to_string();
+    assert!(text.contains("terrain: 1 allocations, 3000 bytes, peak 4000 bytes"));
+    assert!(text.contains("slots #1: 0 bytes"));
+    assert!(tracker.untrack(pool, 2));
+    assert!(tracker.untrack(pool, 3));
+    assert!(tracker.untrack(slots, 1));
+    assert!(tracker.report().labels.is_empty());
+    assert_eq!(tracker.totals(Some("terrain")).peak_bytes, 4000);
+}
@@ -0,0 +1,253 @@
+//! # Bindless.rs
+//!
+//! Typed slot handles for the bindless descriptor tables.
+//!
+//! A `BindlessSlot<K>` is the opaque handle the renderer gets back for a
+//! buffer or texture. It is an index into descriptor table `K`, which is
+//! what shaders use. The type parameter keeps a buffer index from being
+//! passed where a texture index is expected.
+//!
+//! Each table has its own slot allocator. Dropping a handle does not free
+//! the slot right away, because frames still in flight may use it. The
+//! slot goes into the table's retire queue. Once the render thread reports
+//! those frames done, the slot is rewritten to the fallback, and only then
+//! released for reuse.
+//!
+//! Animats
+//! December, 2024.
+//!
+use crate::descriptors::DescriptorTableType;
+use crate::fallback::FallbackMode;
+use crate::gpuinfo::GpuInfo;
+use crate::writes::DescriptorUpdate;
+use alloc::{AllocTracker, RetireQueue};
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+/// Marker for the table a slot belongs to.
+pub trait TableKind: Send + Sync + 'static {
+    /// The table
+    const TABLE: DescriptorTableType;
+}
+
+/// Marker for the storage buffer table.
+#[derive(Debug)]
+pub enum StorageBuffer {}
+
+/// Marker for the sampled image table.
+#[derive(Debug)]
+pub enum SampledImage {}
+
+/// Marker for the storage image table.
+#[derive(Debug)]
+pub enum StorageImage {}
+
+impl TableKind for StorageBuffer {
+    const TABLE: DescriptorTableType = DescriptorTableType::StorageBuffer;
+}
+
+impl TableKind for SampledImage {
+    const TABLE: DescriptorTableType = DescriptorTableType::SampledImage;
+}
+
+impl TableKind for StorageImage {
+    const TABLE: DescriptorTableType = DescriptorTableType::StorageImage;
+}
+
+/// A slot in descriptor table `K`. Not Clone. Released when dropped, once the GPU is done with it.
+pub struct BindlessSlot<K: TableKind> {
+    /// Index in the table
+    index: u32,
+    /// Where the slot goes when dropped
+    retire_queue: Arc<RetireQueue>,
+    /// Table it belongs to
+    phantom: PhantomData<fn() -> K>,
+}
+
+impl<K: TableKind> BindlessSlot<K> {
+    /// Index in the descriptor table. Pass this to shaders.
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// The table this slot is in.
+    pub fn table(&self) -> DescriptorTableType {
+        K::TABLE
+    }
+}
+
+impl<K: TableKind> std::fmt::Debug for BindlessSlot<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "BindlessSlot<{}>({})", K::TABLE.name(), self.index)
+    }
+}
+
+impl<K: TableKind> Drop for BindlessSlot<K> {
+    fn drop(&mut self) {
+        self.retire_queue.retire(self.index as usize);
+    }
+}
+
+/// Slot allocators for all the descriptor tables.
+pub struct BindlessSlots {
+    /// One retire queue per table, in set index order. Each owns its table's slot allocator.
+    retire_queues: Vec<Arc<RetireQueue>>,
+}
+
+impl BindlessSlots {
+    /// Usual new. Tables can grow to the device limits.
+    pub fn new(gpu: &GpuInfo, frames_in_flight: u64) -> Self {
+        Self::build(gpu, frames_in_flight, None)
+    }
+
+    /// As `new`, with the slots of each table reported to `tracker` under the table's name.
+    /// Usually the tracker is `DeviceContext::tracker`.
+    pub fn new_tracked(gpu: &GpuInfo, frames_in_flight: u64, tracker: &Arc<AllocTracker>) -> Self {
+        Self::build(gpu, frames_in_flight, Some(tracker))
+    }
+
+    /// The tables, with or without tracking.
+    fn build(gpu: &GpuInfo, frames_in_flight: u64, tracker: Option<&Arc<AllocTracker>>) -> Self {
+        Self {
+            retire_queues: DescriptorTableType::all_types()
+                .map(|ty| {
+                    let slot_allocator = match tracker {
+                        Some(tracker) => ty
+                            .slot_allocator(gpu)
+                            .with_tracker(tracker, Some(ty.name())),
+                        None => ty.slot_allocator(gpu),
+                    };
+                    Arc::new(RetireQueue::new(Arc::new(slot_allocator), frames_in_flight))
+                })
+                .collect(),
+        }
+    }
+
+    /// Allocate a slot in table `K`, growing the table's allocator if needed.
+    /// None if the table is full. Any thread. Does not block.
+    pub fn alloc<K: TableKind>(&self) -> Option<BindlessSlot<K>> {
+        let retire_queue = self.retire_queue(K::TABLE);
+        let index = retire_queue.bit_alloc().alloc_bit_or_grow()?;
+        Some(BindlessSlot {
+            index: index as u32,
+            retire_queue: Arc::clone(retire_queue),
+            phantom: PhantomData,
+        })
+    }
+
+    /// Retire queue for a table.
+    pub fn retire_queue(&self, ty: DescriptorTableType) -> &Arc<RetireQueue> {
+        &self.retire_queues[ty.set_index() as usize]
+    }
+
+    /// Slots in use in a table, including those waiting to be released.
+    pub fn count(&self, ty: DescriptorTableType) -> usize {
+        self.retire_queue(ty).bit_alloc().allocated()
+    }
+
+    /// Start building the next frame. Render thread only. Returns the new epoch.
+    pub fn advance_epoch(&self) -> u64 {
+        self.retire_queues
+            .iter()
+            .map(|q| q.advance_epoch())
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// The GPU has finished with frame `retired_epoch`. Release the slots it was the last to use.
+    ///
+    /// Each slot is first pointed at the fallback. `rewrite` gets those updates, and must have
+    /// written them to the descriptor sets when it returns. Then the slots are released.
+    /// Render thread only, once per frame, while the GPU is not rendering.
+    /// Returns the number of slots released.
+    pub fn frame_retired(
+        &self,
+        retired_epoch: u64,
+        fallback: &FallbackMode,
+        rewrite: impl FnOnce(&[DescriptorUpdate]),
+    ) -> usize {
+        let ready: Vec<Vec<usize>> = self
+            .retire_queues
+            .iter()
+            .map(|q| q.take_ready(retired_epoch))
+            .collect();
+        let updates: Vec<DescriptorUpdate> = DescriptorTableType::all_types()
+            .zip(&ready)
+            .flat_map(|(table, indices)| {
+                indices.iter().map(move |&index| DescriptorUpdate {
+                    table,
+                    index: index as u32,
+                    resource: fallback.resource(table),
+                })
+            })
+            .collect();
+        if !updates.is_empty() {
+            rewrite(&updates);
+        }
+        for (queue, indices) in self.retire_queues.iter().zip(&ready) {
+            queue.release(indices);
+        }
+        updates.len()
+    }
+}
+
+#[test]
+/// Slots come from separate tables, and are released after the frames in flight.
+fn test_bindless_slots() {
+    use crate::writes::DescriptorResource;
+    let mut gpu = GpuInfo::default();
+    let props = &mut gpu.properties.properties12;
+    props.max_descriptor_set_update_after_bind_storage_buffers = 1000;
+    props.max_per_stage_descriptor_update_after_bind_storage_buffers = 1000;
+    props.max_descriptor_set_update_after_bind_sampled_images = 1000;
+    props.max_per_stage_descriptor_update_after_bind_sampled_images = 1000;
+    props.max_descriptor_set_update_after_bind_storage_images = 60;
+    props.max_per_stage_descriptor_update_after_bind_storage_images = 60;
+    props.max_per_stage_update_after_bind_resources = 10_000;
+    props.max_update_after_bind_descriptors_in_all_pools = 10_000;
+    let tracker = Arc::new(AllocTracker::new());
+    let slots = BindlessSlots::new_tracked(&gpu, 2, &tracker);
+    let texture: BindlessSlot<SampledImage> = slots.alloc().unwrap();
+    let buffer: BindlessSlot<StorageBuffer> = slots.alloc().unwrap();
+    //  Separate tables, so both get the first slot.
+    assert_eq!((texture.index(), buffer.index()), (0, 0));
+    assert_eq!(texture.table(), DescriptorTableType::SampledImage);
+    assert_eq!(format!("{:?}", buffer), "BindlessSlot<storage_buffer>(0)");
+    //  The storage image table fills at its device limit.
+    let images: Vec<BindlessSlot<StorageImage>> = std::iter::from_fn(|| slots.alloc()).collect();
+    assert_eq!(images.len(), 60);
+    assert_eq!(slots.count(DescriptorTableType::StorageImage), 60);
+    assert_eq!(tracker.totals(Some("storage_image")).count, 60);
+    //  Dropped in frame 1. Not reusable until frame 3 retires.
+    assert_eq!(slots.advance_epoch(), 1);
+    drop(texture);
+    drop(images);
+    assert_eq!(slots.count(DescriptorTableType::SampledImage), 1);
+    assert!(slots.alloc::<StorageImage>().is_none());
+    let fallback = FallbackMode::NullDescriptor;
+    let no_rewrite = |_: &[DescriptorUpdate]| panic!("Nothing to rewrite yet");
+    assert_eq!(slots.frame_retired(2, &fallback, no_rewrite), 0);
+    //  Each slot is rewritten while still allocated, then released.
+    let mut rewritten = Vec::new();
+    let released = slots.frame_retired(3, &fallback, |updates| {
+        assert_eq!(slots.count(DescriptorTableType::StorageImage), 60);
+        rewritten = updates.to_vec();
+    });
+    assert_eq!(released, 61);
+    assert_eq!(rewritten.len(), 61);
+    assert_eq!(
+        rewritten[0],
+        DescriptorUpdate {
+            table: DescriptorTableType::SampledImage,
+            index: 0,
+            resource: DescriptorResource::Null,
+        }
+    );
+    assert!(rewritten[1..]
+        .iter()
+        .all(|u| u.table == DescriptorTableType::StorageImage));
+    assert_eq!(slots.count(DescriptorTableType::SampledImage), 0);
+    assert_eq!(tracker.totals(Some("storage_image")).count, 0);
+    assert_eq!(slots.alloc::<SampledImage>().unwrap().index(), 0);
+    assert_eq!(slots.count(DescriptorTableType::StorageBuffer), 1);
+}
@@ -5,18 +5,41 @@
 //! The descriptors live in GPU memory.
 //! The CPU writes them, and the GPU reads them from shaders.
 //!
+//! There is one descriptor set per table type, each holding one large
+//! array binding. Shaders index into the arrays. The bindings are
+//! update-after-bind, partially bound, and variable-count, so slots can be
+//! written while the sets are in use, unused slots need not be valid,
+//! and each table is sized from the device limits. Freed slots are rewritten
+//! to the fallback. With error resources, every slot is also filled with it
+//! when the tables are created. With null descriptors, that fill is skipped,
+//! and slots never written are left unbound, as partial binding allows.
+//!
 //! Animats
 //! December, 2024.
-///
-use log;
-use anyhow::{Error};
-use ash::{vk};
+//!
+use crate::bindless::{BindlessSlot, BindlessSlots, TableKind};
+use crate::device::DeviceContext;
+use crate::fallback::FallbackMode;
+use crate::gpuinfo::GpuInfo;
+use crate::writes::{
+    DescriptorBackend, DescriptorResource, DescriptorUpdate, DescriptorWriteQueue,
+};
+use alloc::BitAlloc;
+use anyhow::{anyhow, Error};
+use ash::vk;
+use std::sync::Arc;
 use vk::Handle;
 
+/// Initial slot count for a descriptor table. Tables grow from here toward the device limit.
+const INITIAL_TABLE_SLOTS: usize = 4096;
+
+/// Slots per update call when filling tables with the fallback.
+const FILL_CHUNK_SLOTS: u32 = 65536;
+
 #[repr(u32)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 /// The types of descriptor tables. These live in the GPU.
-enum DescriptorTableType {
+pub enum DescriptorTableType {
     StorageBuffer = 0,
     SampledImage = 1,
     StorageImage = 2,
@@ -24,24 +47,27 @@ enum DescriptorTableType {
 
 impl DescriptorTableType {
     /// Return all the types
-    fn all_types() -> impl Iterator<Item = Self> {
+    pub fn all_types() -> impl Iterator<Item = Self> {
         [Self::StorageBuffer, Self::SampledImage, Self::StorageImage].into_iter()
     }
 
-    fn set_index(self) -> u32 {
+    /// Descriptor set index of this table. Shaders use this as the set number.
+    pub fn set_index(self) -> u32 {
         self as u32
     }
 
-    fn from_set_index(set_index: u32) -> Self {
+    /// Table type from a descriptor set index.
+    pub fn from_set_index(set_index: u32) -> Self {
         match set_index {
             0 => Self::StorageBuffer,
             1 => Self::SampledImage,
+            2 => Self::StorageImage,
             _ => panic!("invalid set index"),
         }
     }
 
-    /// Local name to Vulkann ame.
-    fn to_vk(self) -> vk::DescriptorType {
+    /// Local name to Vulkan name.
+    pub fn to_vk(self) -> vk::DescriptorType {
         match self {
             DescriptorTableType::StorageBuffer => vk::DescriptorType::STORAGE_BUFFER,
             DescriptorTableType::SampledImage => vk::DescriptorType::SAMPLED_IMAGE,
@@ -50,7 +76,7 @@ impl DescriptorTableType {
     }
 
     /// Display name
-    fn name(self) -> &'static str {
+    pub fn name(self) -> &'static str {
         match self {
             DescriptorTableType::StorageBuffer => "storage_buffer",
             DescriptorTableType::SampledImage => "sampled_image",
@@ -58,7 +84,8 @@ impl DescriptorTableType {
         }
     }
 
-    fn max_count(self, gpu: &GpuInfo) -> u32 {
+    /// Most descriptors a table of this type can have on this GPU.
+    pub fn max_count(self, gpu: &GpuInfo) -> u32 {
         let props = &gpu.properties.properties12;
         match self {
             DescriptorTableType::StorageBuffer => u32::min(
@@ -75,72 +102,367 @@ impl DescriptorTableType {
             ),
         }
     }
-}
 
+    /// Size of each table on this GPU, in set index order.
+    ///
+    /// Each table gets up to its `max_count`. If together they would pass the device's
+    /// limits on update-after-bind descriptors per stage or in all pools, all are scaled
+    /// down to fit.
+    pub fn table_sizes(gpu: &GpuInfo) -> Vec<u32> {
+        let props = &gpu.properties.properties12;
+        let total = u64::from(u32::min(
+            props.max_per_stage_update_after_bind_resources,
+            props.max_update_after_bind_descriptors_in_all_pools,
+        ));
+        let sizes: Vec<u32> = Self::all_types().map(|ty| ty.max_count(gpu)).collect();
+        let sum: u64 = sizes.iter().map(|&size| u64::from(size)).sum();
+        if sum <= total {
+            return sizes;
+        }
+        sizes
+            .iter()
+            .map(|&size| (u64::from(size) * total / sum) as u32)
+            .collect()
+    }
+
+    /// Size of this table on this GPU, as from `table_sizes`.
+    pub fn table_size(self, gpu: &GpuInfo) -> u32 {
+        Self::table_sizes(gpu)[self.set_index() as usize]
+    }
 
-/// Descriptors
+    /// Slot allocator for this table. Starts small, and can grow up to the table's size on this GPU.
+    /// The bitmap is whole words, so any bits past the table's end are reserved.
+    pub fn slot_allocator(self, gpu: &GpuInfo) -> BitAlloc {
+        let size = self.table_size(gpu) as usize;
+        let bit_alloc = BitAlloc::new_growable(INITIAL_TABLE_SLOTS.min(size), size);
+        let past_end = size..bit_alloc.ceiling();
+        bit_alloc.with_reserved(&[past_end])
+    }
+}
+
+/// The bindless descriptor tables. Owns the layouts, pool and sets, and destroys them on drop.
 pub struct Descriptors {
-    /// The descriptor set
+    /// Device they belong to
+    ctx: Arc<DeviceContext>,
+    /// Pool the sets come from
+    descriptor_pool: vk::DescriptorPool,
+    /// One layout per table type, in set index order
+    descriptor_layouts: Vec<vk::DescriptorSetLayout>,
+    /// The descriptor sets, in set index order
     descriptor_sets: Vec<vk::DescriptorSet>,
+    /// Size of each table, in set index order
+    table_sizes: Vec<u32>,
+    /// Slot writes waiting for the next flush
+    writes: DescriptorWriteQueue,
+    /// What empty slots hold
+    fallback: FallbackMode,
 }
 
 impl Descriptors {
     /// Create the descriptor tables.
     ///
     /// Loosely modeled after how Orbit does this.
-    pub fn new() -> Result<Self, Error> {
+    pub fn new(
+        ctx: Arc<DeviceContext>,
+        gpu: &GpuInfo,
+        fallback: FallbackMode,
+    ) -> Result<Self, Error> {
+        fallback.check(gpu)?;
+        //  Built up a piece at a time. If anything fails, drop cleans up what was made so far.
+        let mut descriptors = Self {
+            ctx,
+            descriptor_pool: vk::DescriptorPool::null(),
+            descriptor_layouts: Vec::new(),
+            descriptor_sets: Vec::new(),
+            table_sizes: DescriptorTableType::table_sizes(gpu),
+            writes: DescriptorWriteQueue::new(),
+            fallback,
+        };
+        for ty in DescriptorTableType::all_types() {
+            let layout = descriptors.create_layout(ty)?;
+            descriptors.descriptor_layouts.push(layout);
+        }
+
         let pool_sizes: Vec<_> = DescriptorTableType::all_types()
             .map(|desc_ty| vk::DescriptorPoolSize {
                 ty: desc_ty.to_vk(),
-                descriptor_count: desc_ty.max_count(&gpu),
+                descriptor_count: descriptors.table_size(desc_ty),
             })
             .collect();
 
-        let pool_create_info = vk::DescriptorPoolCreateInfo::builder()
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
             .flags(vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND)
-            .max_sets(4)
+            .max_sets(descriptors.descriptor_layouts.len() as u32)
             .pool_sizes(&pool_sizes);
 
-        let descriptor_pool = unsafe {
-            device
-                .create_descriptor_pool(&pool_create_info, None)
-                .unwrap()
+        descriptors.descriptor_pool = unsafe {
+            descriptors
+                .ctx
+                .device()
+                .create_descriptor_pool(&pool_create_info, None)?
         };
 
-        set_debug_name(
+        descriptors.ctx.set_debug_name(
             vk::DescriptorPool::TYPE,
-            descriptor_pool.as_raw(),
+            descriptors.descriptor_pool.as_raw(),
             "bindless_descriptor_pool",
         );
 
-        let descriptor_counts: Vec<_> = DescriptorTableType::all_types()
-            .map(|ty| ty.max_count(&gpu))
-            .collect();
-
-        let mut variable_count = vk::DescriptorSetVariableDescriptorCountAllocateInfo::builder()
-            .descriptor_counts(&descriptor_counts);
+        let mut variable_count = vk::DescriptorSetVariableDescriptorCountAllocateInfo::default()
+            .descriptor_counts(&descriptors.table_sizes);
 
-        let alloc_info = vk::DescriptorSetAllocateInfo::builder()
-            .descriptor_pool(descriptor_pool)
-            .set_layouts(&descriptor_layouts)
+        let alloc_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptors.descriptor_pool)
+            .set_layouts(&descriptors.descriptor_layouts)
             .push_next(&mut variable_count);
 
-        let descriptor_sets = unsafe { device.allocate_descriptor_sets(&alloc_info).unwrap() };
+        //  Sets are freed with the pool, so there's nothing else to clean up if this fails.
+        let descriptor_sets = unsafe {
+            descriptors
+                .ctx
+                .device()
+                .allocate_descriptor_sets(&alloc_info)?
+        };
+        descriptors.descriptor_sets = descriptor_sets;
 
-        let names = [
-            "buffer_descriptor_set",
-            "sampled_image_descriptor_set",
-            "storage_image_descriptor_index",
-        ];
+        for (ty, descriptor_set) in
+            DescriptorTableType::all_types().zip(&descriptors.descriptor_sets)
+        {
+            descriptors.ctx.set_debug_name(
+                vk::DescriptorSet::TYPE,
+                descriptor_set.as_raw(),
+                &format!("{}_descriptor_set", ty.name()),
+            );
+        }
 
-        for (i, descriptor_set) in descriptor_sets.iter().enumerate() {
-            set_debug_name(vk::DescriptorSet::TYPE, descriptor_set.as_raw(), names[i]);
+        descriptors.fill_fallback();
+        log::info!(
+            "Created descriptor tables, sizes {:?}, fallback {:?}",
+            descriptors.table_sizes,
+            descriptors.fallback
+        );
+        Ok(descriptors)
+    }
+
+    /// Point every slot of every table at the error resources. Done once, before any slot is used.
+    ///
+    /// This writes every slot of every table at once, up to the device limits, which can be
+    /// millions of descriptors. So it is skipped for null descriptors, where a never-written
+    /// slot is unbound, as partial binding allows, and freed slots are rewritten to null anyway.
+    fn fill_fallback(&self) {
+        if !matches!(self.fallback, FallbackMode::ErrorResources(_)) {
+            return;
         }
+        for ty in DescriptorTableType::all_types() {
+            let size = self.table_size(ty);
+            for start in (0..size).step_by(FILL_CHUNK_SLOTS as usize) {
+                let updates: Vec<DescriptorUpdate> = (start..size.min(start + FILL_CHUNK_SLOTS))
+                    .map(|index| DescriptorUpdate {
+                        table: ty,
+                        index,
+                        resource: self.fallback.resource(ty),
+                    })
+                    .collect();
+                self.ctx.update_descriptors(&self.descriptor_sets, &updates);
+            }
+        }
+    }
 
-        log::info!("created descriptor table");
-        
-        Ok(Self {
-            descriptor_sets
-        })
+    /// Layout for one table. A single array binding, sized for the whole table.
+    fn create_layout(&self, ty: DescriptorTableType) -> Result<vk::DescriptorSetLayout, Error> {
+        let bindings = [vk::DescriptorSetLayoutBinding::default()
+            .binding(0)
+            .descriptor_type(ty.to_vk())
+            .descriptor_count(self.table_size(ty))
+            .stage_flags(vk::ShaderStageFlags::ALL)];
+        //  Written while in use, not all slots valid, and sized at allocation time.
+        let binding_flags = [vk::DescriptorBindingFlags::UPDATE_AFTER_BIND
+            | vk::DescriptorBindingFlags::PARTIALLY_BOUND
+            | vk::DescriptorBindingFlags::VARIABLE_DESCRIPTOR_COUNT];
+        let mut binding_flags_info =
+            vk::DescriptorSetLayoutBindingFlagsCreateInfo::default().binding_flags(&binding_flags);
+        let layout_create_info = vk::DescriptorSetLayoutCreateInfo::default()
+            .flags(vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL)
+            .bindings(&bindings)
+            .push_next(&mut binding_flags_info);
+        let layout = unsafe {
+            self.ctx
+                .device()
+                .create_descriptor_set_layout(&layout_create_info, None)?
+        };
+        self.ctx.set_debug_name(
+            vk::DescriptorSetLayout::TYPE,
+            layout.as_raw(),
+            &format!("{}_descriptor_layout", ty.name()),
+        );
+        Ok(layout)
     }
+
+    /// The descriptor set for a table type.
+    pub fn descriptor_set(&self, ty: DescriptorTableType) -> vk::DescriptorSet {
+        self.descriptor_sets[ty.set_index() as usize]
+    }
+
+    /// All the layouts, in set index order. For building pipeline layouts.
+    pub fn descriptor_layouts(&self) -> &[vk::DescriptorSetLayout] {
+        &self.descriptor_layouts
+    }
+
+    /// Number of slots in a table.
+    pub fn table_size(&self, ty: DescriptorTableType) -> u32 {
+        self.table_sizes[ty.set_index() as usize]
+    }
+
+    /// Queue a write of a slot, to take effect at the next flush. Any thread. Does not block.
+    /// A null is written as the fallback.
+    pub fn write(
+        &self,
+        ty: DescriptorTableType,
+        index: u32,
+        resource: DescriptorResource,
+    ) -> Result<(), Error> {
+        if index >= self.table_size(ty) {
+            return Err(anyhow!(
+                "Slot {} is past the end of the {} table, size {}",
+                index,
+                ty.name(),
+                self.table_size(ty)
+            ));
+        }
+        if !resource.fits(ty) {
+            return Err(anyhow!(
+                "{:?} can't go in the {} table",
+                resource,
+                ty.name()
+            ));
+        }
+        self.writes
+            .write(ty, index, self.fallback.resolve(ty, resource));
+        Ok(())
+    }
+
+    /// Queue a write of the slot a handle owns.
+    pub fn write_slot<K: TableKind>(
+        &self,
+        slot: &BindlessSlot<K>,
+        resource: DescriptorResource,
+    ) -> Result<(), Error> {
+        self.write(K::TABLE, slot.index(), resource)
+    }
+
+    /// Queue a clear of a slot back to the fallback, to take effect at the next flush.
+    /// Any thread. Does not block.
+    pub fn clear(&self, ty: DescriptorTableType, index: u32) -> Result<(), Error> {
+        self.write(ty, index, self.fallback.resource(ty))
+    }
+
+    /// What empty slots hold.
+    pub fn fallback(&self) -> &FallbackMode {
+        &self.fallback
+    }
+
+    /// Apply all queued writes, in one `vkUpdateDescriptorSets` call.
+    /// Render thread only, once per frame, at a point where the GPU is not rendering.
+    /// Returns the number of slots updated.
+    pub fn flush(&self) -> usize {
+        self.writes.flush(&self.descriptor_sets, self.ctx.as_ref())
+    }
+
+    /// End of frame work. The GPU has finished with frame `retired_epoch`.
+    /// Slots dropped at least the frames in flight before it are rewritten to the fallback,
+    /// along with all other queued writes, in one update call. Then they are released for reuse.
+    /// Render thread only, once per frame, at a point where the GPU is not rendering.
+    /// Returns the number of slots released.
+    pub fn frame_retired(&self, slots: &BindlessSlots, retired_epoch: u64) -> usize {
+        let mut flushed = false;
+        let released = slots.frame_retired(retired_epoch, &self.fallback, |updates| {
+            //  Queued after any stale writes to the same slots, so these win.
+            for update in updates {
+                self.writes
+                    .write(update.table, update.index, update.resource);
+            }
+            let _ = self.flush();
+            flushed = true;
+        });
+        if !flushed {
+            let _ = self.flush();
+        }
+        released
+    }
+}
+
+impl Drop for Descriptors {
+    fn drop(&mut self) {
+        //  The GPU must be done with the sets. Destroying the pool frees them.
+        unsafe {
+            let device = self.ctx.device();
+            if self.descriptor_pool != vk::DescriptorPool::null() {
+                device.destroy_descriptor_pool(self.descriptor_pool, None);
+            }
+            for layout in self.descriptor_layouts.drain(..) {
+                device.destroy_descriptor_set_layout(layout, None);
+            }
+        }
+    }
+}
+
+#[test]
+/// Table sizes come from the device limits, and set indices round trip.
+fn test_descriptor_table_types() {
+    let mut gpu = GpuInfo::default();
+    let props = &mut gpu.properties.properties12;
+    props.max_descriptor_set_update_after_bind_storage_buffers = 500_000;
+    props.max_per_stage_descriptor_update_after_bind_storage_buffers = 1_000_000;
+    props.max_descriptor_set_update_after_bind_sampled_images = 1_000_000;
+    props.max_per_stage_descriptor_update_after_bind_sampled_images = 1_000_000;
+    props.max_descriptor_set_update_after_bind_storage_images = 1_000;
+    props.max_per_stage_descriptor_update_after_bind_storage_images = 2_000;
+    props.max_per_stage_update_after_bind_resources = u32::MAX;
+    props.max_update_after_bind_descriptors_in_all_pools = u32::MAX;
+    let sizes: Vec<u32> = DescriptorTableType::all_types()
+        .map(|ty| ty.max_count(&gpu))
+        .collect();
+    assert_eq!(sizes, [500_000, 1_000_000, 1_000]);
+    for ty in DescriptorTableType::all_types() {
+        assert_eq!(DescriptorTableType::from_set_index(ty.set_index()), ty);
+    }
+    //  Slot allocators start small and can grow to the table size.
+    let slots = DescriptorTableType::StorageImage.slot_allocator(&gpu);
+    assert_eq!(slots.ceiling(), 1024); // rounded up to whole words
+    assert!(slots.len() <= INITIAL_TABLE_SLOTS);
+}
+
+#[test]
+/// Tables together stay within the device's totals, which is what the pool and layouts are sized from.
+fn test_descriptor_table_sizes() {
+    let mut gpu = GpuInfo::default();
+    let props = &mut gpu.properties.properties12;
+    props.max_descriptor_set_update_after_bind_storage_buffers = 500_000;
+    props.max_per_stage_descriptor_update_after_bind_storage_buffers = 500_000;
+    props.max_descriptor_set_update_after_bind_sampled_images = 500_000;
+    props.max_per_stage_descriptor_update_after_bind_sampled_images = 500_000;
+    props.max_descriptor_set_update_after_bind_storage_images = 100_000;
+    props.max_per_stage_descriptor_update_after_bind_storage_images = 100_000;
+    props.max_per_stage_update_after_bind_resources = 2_000_000;
+    props.max_update_after_bind_descriptors_in_all_pools = 2_000_000;
+    //  Room for all of them.
+    assert_eq!(
+        DescriptorTableType::table_sizes(&gpu),
+        [500_000, 500_000, 100_000]
+    );
+    //  Per stage limit. Scaled down in proportion.
+    let props = &mut gpu.properties.properties12;
+    props.max_per_stage_update_after_bind_resources = 550_000;
+    assert_eq!(
+        DescriptorTableType::table_sizes(&gpu),
+        [250_000, 250_000, 50_000]
+    );
+    //  All pools limit, the lower of the two.
+    let props = &mut gpu.properties.properties12;
+    props.max_update_after_bind_descriptors_in_all_pools = 110_000;
+    let sizes = DescriptorTableType::table_sizes(&gpu);
+    assert_eq!(sizes, [50_000, 50_000, 10_000]);
+    assert_eq!(DescriptorTableType::SampledImage.table_size(&gpu), sizes[1]);
+    let slots = DescriptorTableType::StorageImage.slot_allocator(&gpu);
+    assert_eq!(slots.ceiling(), 10_048); // rounded up to whole words
 }
@@ -0,0 +1,74 @@
+//! # Device.rs
+//!
+//! The Vulkan device, as handed to us by the renderer.
+//!
+//! The renderer creates the instance and device, and destroys them after
+//! everything built on them is gone. Holders of a `DeviceContext` keep it
+//! in an `Arc`, so it lives as long as they do.
+//!
+//! The context also owns the allocation tracker. Allocators built on the
+//! device report to it, so its leak report, when the device goes away,
+//! covers all of them.
+//!
+//! Animats
+//! December, 2024.
+//!
+use alloc::AllocTracker;
+use ash::vk;
+use std::ffi::CString;
+use std::sync::Arc;
+
+/// Device, and the extensions we use with it.
+pub struct DeviceContext {
+    /// The logical device
+    device: ash::Device,
+    /// Debug utilities, for object names. None if the extension is not enabled.
+    debug_utils: Option<ash::ext::debug_utils::Device>,
+    /// Allocations made on this device, by label
+    tracker: Arc<AllocTracker>,
+}
+
+impl DeviceContext {
+    /// Usual new.
+    pub fn new(device: ash::Device) -> Self {
+        Self {
+            device,
+            debug_utils: None,
+            tracker: Arc::new(AllocTracker::new()),
+        }
+    }
+
+    /// Name Vulkan objects for debuggers and validation messages.
+    /// The instance must have `VK_EXT_debug_utils` enabled.
+    pub fn with_debug_utils(mut self, instance: &ash::Instance) -> Self {
+        self.debug_utils = Some(ash::ext::debug_utils::Device::new(instance, &self.device));
+        self
+    }
+
+    /// The logical device.
+    pub fn device(&self) -> &ash::Device {
+        &self.device
+    }
+
+    /// The allocation tracker for this device.
+    pub fn tracker(&self) -> &Arc<AllocTracker> {
+        &self.tracker
+    }
+
+    /// Name an object, if debug utilities are enabled.
+    pub fn set_debug_name(&self, object_type: vk::ObjectType, raw_handle: u64, name: &str) {
+        let Some(debug_utils) = &self.debug_utils else {
+            return;
+        };
+        let Ok(name) = CString::new(name) else {
+            log::warn!("Debug name {:?} contains a null.", name);
+            return;
+        };
+        let mut name_info = vk::DebugUtilsObjectNameInfoEXT::default().object_name(&name);
+        name_info.object_type = object_type;
+        name_info.object_handle = raw_handle;
+        if let Err(e) = unsafe { debug_utils.set_debug_utils_object_name(&name_info) } {
+            log::warn!("Setting debug name {:?}: {:?}", name, e);
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! # Fallback.rs
+//!
+//! What empty descriptor table slots hold.
+//!
+//! A shader with a bad index should not read whatever was in the slot
+//! before. So every freed slot is pointed at a fallback. There are two
+//! choices:
+//!
+//! - Null descriptors. Reads return zero. Needs the `nullDescriptor`
+//!   feature of `VK_EXT_robustness2`, enabled when the device is created.
+//! - Error resources. A magenta texture reading "descriptor index error"
+//!   in black, plus a storage buffer and storage image, so the bug shows
+//!   on screen. Works everywhere. The renderer makes the resources from
+//!   the contents generated here, and keeps them alive while the tables
+//!   exist.
+//!
+//! Slots are rewritten to the fallback after their frames retire, before
+//! they go back to the slot allocator. Error resources are also written to
+//! every slot when the tables are created. Null descriptors are not, since
+//! that would be millions of writes at startup. So with null descriptors, a
+//! slot never yet written is unbound, and a shader reading it gets undefined
+//! results, not zero.
+//!
+//! Animats
+//! December, 2024.
+//!
+use crate::descriptors::DescriptorTableType;
+use crate::gpuinfo::GpuInfo;
+use crate::writes::DescriptorResource;
+use anyhow::{anyhow, Error};
+
+/// Error color, RGBA.
+pub const ERROR_COLOR: [u8; 4] = [255, 0, 255, 255];
+
+/// The legend on the error texture, one line per entry.
+const ERROR_LEGEND: [&str; 2] = ["DESCRIPTOR", "INDEX ERROR"];
+
+/// Glyph cell of the legend font, in font pixels. 5x7 glyphs plus spacing.
+const GLYPH_CELL: (u32, u32) = (6, 9);
+
+/// Smallest texture, in texels, with room for the legend at one texel per font pixel.
+const LEGEND_MIN_SIZE: u32 = 72;
+
+/// 5x7 bitmaps for the legend's letters, top row first, bit 4 leftmost.
+fn glyph(c: char) -> [u8; 7] {
+    match c {
+        'C' => [0x0e, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0e],
+        'D' => [0x1e, 0x11, 0x11, 0x11, 0x11, 0x11, 0x1e],
+        'E' => [0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x1f],
+        'I' => [0x0e, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        'N' => [0x11, 0x19, 0x15, 0x13, 0x11, 0x11, 0x11],
+        'O' => [0x0e, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e],
+        'P' => [0x1e, 0x11, 0x11, 0x1e, 0x10, 0x10, 0x10],
+        'R' => [0x1e, 0x11, 0x11, 0x1e, 0x14, 0x12, 0x11],
+        'S' => [0x0f, 0x10, 0x10, 0x0e, 0x01, 0x01, 0x1e],
+        'T' => [0x1f, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'X' => [0x11, 0x11, 0x0a, 0x04, 0x0a, 0x11, 0x11],
+        _ => [0; 7],
+    }
+}
+
+/// Is texel (`x`, `y`) part of the legend, drawn centered on a `size` texture?
+fn legend_texel(x: u32, y: u32, size: u32) -> bool {
+    let scale = size / LEGEND_MIN_SIZE;
+    if scale == 0 {
+        return false; // too small to read
+    }
+    let height = (ERROR_LEGEND.len() as u32 * GLYPH_CELL.1 - 2) * scale;
+    let Some(ty) = y.checked_sub((size - height) / 2).map(|ty| ty / scale) else {
+        return false;
+    };
+    let Some(line) = ERROR_LEGEND.get((ty / GLYPH_CELL.1) as usize) else {
+        return false;
+    };
+    let width = (line.len() as u32 * GLYPH_CELL.0 - 1) * scale;
+    let Some(tx) = x.checked_sub((size - width) / 2).map(|tx| tx / scale) else {
+        return false;
+    };
+    let (row, col) = (ty % GLYPH_CELL.1, tx % GLYPH_CELL.0);
+    if row >= 7 || col >= 5 {
+        return false; // spacing between glyphs
+    }
+    line.chars()
+        .nth((tx / GLYPH_CELL.0) as usize)
+        .is_some_and(|c| glyph(c)[row as usize] & (0x10 >> col) != 0)
+}
+
+/// The renderer's error resources, one per table type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorResources {
+    /// Filled from `error_buffer_contents`
+    pub storage_buffer: DescriptorResource,
+    /// Filled from `error_texture_rgba`, in shader read layout
+    pub sampled_image: DescriptorResource,
+    /// Filled from `error_texture_rgba`, in general layout
+    pub storage_image: DescriptorResource,
+}
+
+/// What empty slots hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FallbackMode {
+    /// Null descriptors, through `VK_EXT_robustness2`
+    NullDescriptor,
+    /// The error resources
+    ErrorResources(ErrorResources),
+}
+
+impl FallbackMode {
+    /// Null descriptors if the GPU has them, else the error resources.
+    pub fn for_gpu(gpu: &GpuInfo, error_resources: ErrorResources) -> Self {
+        if gpu.supports_null_descriptor() {
+            Self::NullDescriptor
+        } else {
+            Self::ErrorResources(error_resources)
+        }
+    }
+
+    /// Check that this mode can be used on this GPU.
+    pub fn check(&self, gpu: &GpuInfo) -> Result<(), Error> {
+        match self {
+            Self::NullDescriptor => {
+                if !gpu.supports_null_descriptor() {
+                    return Err(anyhow!(
+                        "Null descriptor fallback needs VK_EXT_robustness2 nullDescriptor, which {} lacks",
+                        gpu.name()
+                    ));
+                }
+            }
+            Self::ErrorResources(_) => {
+                for ty in DescriptorTableType::all_types() {
+                    let resource = self.resource(ty);
+                    if resource == DescriptorResource::Null || !resource.fits(ty) {
+                        return Err(anyhow!(
+                            "Error resource {:?} can't go in the {} table",
+                            resource,
+                            ty.name()
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// What actually goes in a slot of a table when `resource` is written there.
+    /// A null becomes the fallback, so without null descriptors it's the error resource.
+    pub fn resolve(
+        &self,
+        ty: DescriptorTableType,
+        resource: DescriptorResource,
+    ) -> DescriptorResource {
+        match resource {
+            DescriptorResource::Null => self.resource(ty),
+            _ => resource,
+        }
+    }
+
+    /// What an empty slot of a table holds.
+    pub fn resource(&self, ty: DescriptorTableType) -> DescriptorResource {
+        match self {
+            Self::NullDescriptor => DescriptorResource::Null,
+            Self::ErrorResources(resources) => match ty {
+                DescriptorTableType::StorageBuffer => resources.storage_buffer,
+                DescriptorTableType::SampledImage => resources.sampled_image,
+                DescriptorTableType::StorageImage => resources.storage_image,
+            },
+        }
+    }
+}
+
+/// Contents of the "descriptor index error" texture. A `size` by `size` magenta square,
+/// RGBA8, rows top to bottom, with "DESCRIPTOR INDEX ERROR" in black across the middle.
+/// Below 72 texels there is no room for the legend, and it is plain magenta.
+pub fn error_texture_rgba(size: u32) -> Vec<u8> {
+    (0..size)
+        .flat_map(|y| (0..size).map(move |x| (x, y)))
+        .flat_map(|(x, y)| {
+            if legend_texel(x, y, size) {
+                [0, 0, 0, 255]
+            } else {
+                ERROR_COLOR
+            }
+        })
+        .collect()
+}
+
+/// Contents of the error storage buffer. The error color as RGBA `f32`, repeated, `len` bytes.
+pub fn error_buffer_contents(len: usize) -> Vec<u8> {
+    let color: Vec<u8> = ERROR_COLOR
+        .iter()
+        .flat_map(|&c| (f32::from(c) / 255.0).to_ne_bytes())
+        .collect();
+    color.iter().copied().cycle().take(len).collect()
+}
+
+#[test]
+/// Mode choice, checks, and generated contents.
+fn test_fallback_modes() {
+    use ash::vk;
+    use ash::vk::Handle;
+    let error_resources = ErrorResources {
+        storage_buffer: DescriptorResource::Buffer {
+            buffer: vk::Buffer::from_raw(1),
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        },
+        sampled_image: DescriptorResource::Image {
+            view: vk::ImageView::from_raw(2),
+            layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        },
+        storage_image: DescriptorResource::Image {
+            view: vk::ImageView::from_raw(3),
+            layout: vk::ImageLayout::GENERAL,
+        },
+    };
+    let mut gpu = GpuInfo::default();
+    let mode = FallbackMode::for_gpu(&gpu, error_resources);
+    assert_eq!(mode, FallbackMode::ErrorResources(error_resources));
+    assert!(mode.check(&gpu).is_ok());
+    assert!(FallbackMode::NullDescriptor.check(&gpu).is_err());
+    assert_eq!(
+        mode.resource(DescriptorTableType::StorageImage),
+        error_resources.storage_image
+    );
+    //  A null written without null descriptors is the error resource instead.
+    assert_eq!(
+        mode.resolve(DescriptorTableType::SampledImage, DescriptorResource::Null),
+        error_resources.sampled_image
+    );
+    assert_eq!(
+        mode.resolve(
+            DescriptorTableType::SampledImage,
+            error_resources.storage_image
+        ),
+        error_resources.storage_image
+    );
+    //  With robustness2, nulls.
+    gpu.extensions.push("VK_EXT_robustness2".to_string());
+    gpu.features.robustness2.null_descriptor = vk::TRUE;
+    let mode = FallbackMode::for_gpu(&gpu, error_resources);
+    assert_eq!(mode, FallbackMode::NullDescriptor);
+    assert!(mode.check(&gpu).is_ok());
+    assert_eq!(
+        mode.resource(DescriptorTableType::SampledImage),
+        DescriptorResource::Null
+    );
+    assert_eq!(
+        mode.resolve(DescriptorTableType::SampledImage, DescriptorResource::Null),
+        DescriptorResource::Null
+    );
+    //  A buffer where an image belongs is refused.
+    let wrong = FallbackMode::ErrorResources(ErrorResources {
+        sampled_image: error_resources.storage_buffer,
+        ..error_resources
+    });
+    assert!(wrong.check(&gpu).is_err());
+    //  Contents.
+    let texture = error_texture_rgba(16);
+    assert_eq!(texture.len(), 16 * 16 * 4);
+    assert!(texture.chunks(4).all(|t| t == ERROR_COLOR)); // no room for the legend
+    let texture = error_texture_rgba(144);
+    let black = |x: usize, y: usize| texture[(y * 144 + x) * 4..][..4] == [0, 0, 0, 255];
+    assert_eq!(texture[0..4], ERROR_COLOR);
+    //  At scale 2, "DESCRIPTOR" is 118 texels wide from x = 13, the block 32 high from y = 56.
+    //  The D's top left corner is set, and the texel right of its top row is not.
+    assert!(black(13, 56) && black(14, 57));
+    assert!(!black(21, 56));
+    //  "INDEX ERROR" starts at x = 7, on the second line at y = 74. The I's top row is 0x0e.
+    assert!(!black(7, 74) && black(9, 74));
+    let count = texture.chunks(4).filter(|t| *t == [0, 0, 0, 255]).count();
+    let bits: u32 = ERROR_LEGEND
+        .iter()
+        .flat_map(|line| line.chars())
+        .flat_map(glyph)
+        .map(u8::count_ones)
+        .sum();
+    assert_eq!(count as u32, bits * 4);
+    let buffer = error_buffer_contents(20);
+    assert_eq!(buffer.len(), 20);
+    assert_eq!(buffer[0..4], 1.0f32.to_ne_bytes());
+    assert_eq!(buffer[4..8], 0.0f32.to_ne_bytes());
+    assert_eq!(buffer[16..20], buffer[0..4]);
+}
@@ -0,0 +1,662 @@
+//! # Gpuinfo.rs
+//!
+//! What the GPU can do, as far as bindless rendering cares.
+//!
+//! The Vulkan 1.0 to 1.3 properties, features and limits, filled in either
+//! from a live physical device or from a JSON device profile. The profile
+//! format is the one `vulkaninfo --json` writes, which gpuinfo.org also
+//! exports: `capabilities`, then for each capability block, `features` and
+//! `properties` keyed by Vulkan structure name, with Vulkan member names.
+//! So table sizing and feature checks can be tested against many GPUs on a
+//! machine with no GPU at all.
+//!
+//! From a profile, only the members which matter for bindless work are read.
+//! The rest stay zero.
+//!
+//! Animats
+//! December, 2024.
+//!
+use anyhow::{anyhow, Context, Error};
+use ash::vk;
+use serde_json::Value;
+
+/// Copy the members present in a profile structure into a Vulkan structure.
+macro_rules! profile_fields {
+    ($value:expr, $target:expr, $($json:literal => $field:ident),* $(,)?) => {
+        $(
+            if let Some(v) = $value.get($json) {
+                $target.$field = FromProfile::from_profile(v)
+                    .ok_or_else(|| anyhow!("Bad value {} for {}", v, $json))?;
+            }
+        )*
+    };
+}
+
+/// Copy members of the same name from one Vulkan structure into another.
+macro_rules! copy_fields {
+    ($from:expr, $to:expr, $($field:ident),* $(,)?) => {
+        $( $to.$field = $from.$field; )*
+    };
+}
+
+/// Information about the GPU.
+#[derive(Debug, Clone, Default)]
+pub struct GpuInfo {
+    /// Device properties and limits
+    pub properties: GpuProperties,
+    /// Device features
+    pub features: GpuFeatures,
+    /// Device extensions supported
+    pub extensions: Vec<String>,
+}
+
+/// Device properties, by Vulkan version. Every `p_next` chain is null.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GpuProperties {
+    /// Vulkan 1.0 properties, including the limits
+    pub properties10: vk::PhysicalDeviceProperties,
+    /// Vulkan 1.1 properties
+    pub properties11: vk::PhysicalDeviceVulkan11Properties<'static>,
+    /// Vulkan 1.2 properties, which include the descriptor indexing limits
+    pub properties12: vk::PhysicalDeviceVulkan12Properties<'static>,
+    /// Vulkan 1.3 properties
+    pub properties13: vk::PhysicalDeviceVulkan13Properties<'static>,
+}
+
+/// Device features, by Vulkan version. Every `p_next` chain is null.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GpuFeatures {
+    /// Vulkan 1.0 features
+    pub features10: vk::PhysicalDeviceFeatures,
+    /// Vulkan 1.1 features
+    pub features11: vk::PhysicalDeviceVulkan11Features<'static>,
+    /// Vulkan 1.2 features, which include descriptor indexing
+    pub features12: vk::PhysicalDeviceVulkan12Features<'static>,
+    /// Vulkan 1.3 features
+    pub features13: vk::PhysicalDeviceVulkan13Features<'static>,
+    /// `VK_EXT_robustness2` features, for null descriptors
+    pub robustness2: vk::PhysicalDeviceRobustness2FeaturesEXT<'static>,
+}
+
+impl GpuInfo {
+    /// Query a physical device.
+    pub fn new(instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> Self {
+        let mut info = Self::default();
+        let api_version =
+            unsafe { instance.get_physical_device_properties(physical_device) }.api_version;
+        match unsafe { instance.enumerate_device_extension_properties(physical_device) } {
+            Ok(extensions) => {
+                info.extensions = extensions
+                    .iter()
+                    .filter_map(|e| e.extension_name_as_c_str().ok())
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .collect();
+            }
+            Err(e) => log::warn!("Enumerating device extensions: {:?}", e),
+        }
+        //  Only chain the structures the device's Vulkan version knows about.
+        //  Before 1.2, descriptor indexing is an extension, with structures of its own.
+        let indexing_ext =
+            api_version < vk::API_VERSION_1_2 && info.has_extension("VK_EXT_descriptor_indexing");
+        let mut indexing_properties = vk::PhysicalDeviceDescriptorIndexingProperties::default();
+        let mut indexing_features = vk::PhysicalDeviceDescriptorIndexingFeatures::default();
+        let p = &mut info.properties;
+        {
+            let mut properties2 = vk::PhysicalDeviceProperties2::default();
+            if api_version >= vk::API_VERSION_1_1 {
+                properties2 = properties2.push_next(&mut p.properties11);
+            }
+            if api_version >= vk::API_VERSION_1_2 {
+                properties2 = properties2.push_next(&mut p.properties12);
+            }
+            if api_version >= vk::API_VERSION_1_3 {
+                properties2 = properties2.push_next(&mut p.properties13);
+            }
+            if indexing_ext {
+                properties2 = properties2.push_next(&mut indexing_properties);
+            }
+            unsafe { instance.get_physical_device_properties2(physical_device, &mut properties2) };
+            p.properties10 = properties2.properties;
+        }
+        let f = &mut info.features;
+        {
+            let mut features2 = vk::PhysicalDeviceFeatures2::default();
+            if api_version >= vk::API_VERSION_1_1 {
+                features2 = features2.push_next(&mut f.features11);
+            }
+            if api_version >= vk::API_VERSION_1_2 {
+                features2 = features2.push_next(&mut f.features12);
+            }
+            if api_version >= vk::API_VERSION_1_3 {
+                features2 = features2.push_next(&mut f.features13);
+            }
+            if info.extensions.iter().any(|e| e == "VK_EXT_robustness2") {
+                features2 = features2.push_next(&mut f.robustness2);
+            }
+            if indexing_ext {
+                features2 = features2.push_next(&mut indexing_features);
+            }
+            unsafe { instance.get_physical_device_features2(physical_device, &mut features2) };
+            f.features10 = features2.features;
+        }
+        if indexing_ext {
+            info.set_descriptor_indexing(&indexing_properties, &indexing_features);
+        }
+        info.clear_chains();
+        info
+    }
+
+    /// Fill in the Vulkan 1.2 descriptor indexing members from the `VK_EXT_descriptor_indexing`
+    /// structures, as the profile loader does for a 1.1 device with the extension.
+    fn set_descriptor_indexing(
+        &mut self,
+        properties: &vk::PhysicalDeviceDescriptorIndexingProperties,
+        features: &vk::PhysicalDeviceDescriptorIndexingFeatures,
+    ) {
+        copy_fields!(
+            properties,
+            self.properties.properties12,
+            max_update_after_bind_descriptors_in_all_pools,
+            shader_uniform_buffer_array_non_uniform_indexing_native,
+            shader_sampled_image_array_non_uniform_indexing_native,
+            shader_storage_buffer_array_non_uniform_indexing_native,
+            shader_storage_image_array_non_uniform_indexing_native,
+            shader_input_attachment_array_non_uniform_indexing_native,
+            robust_buffer_access_update_after_bind,
+            quad_divergent_implicit_lod,
+            max_per_stage_descriptor_update_after_bind_samplers,
+            max_per_stage_descriptor_update_after_bind_uniform_buffers,
+            max_per_stage_descriptor_update_after_bind_storage_buffers,
+            max_per_stage_descriptor_update_after_bind_sampled_images,
+            max_per_stage_descriptor_update_after_bind_storage_images,
+            max_per_stage_descriptor_update_after_bind_input_attachments,
+            max_per_stage_update_after_bind_resources,
+            max_descriptor_set_update_after_bind_samplers,
+            max_descriptor_set_update_after_bind_uniform_buffers,
+            max_descriptor_set_update_after_bind_uniform_buffers_dynamic,
+            max_descriptor_set_update_after_bind_storage_buffers,
+            max_descriptor_set_update_after_bind_storage_buffers_dynamic,
+            max_descriptor_set_update_after_bind_sampled_images,
+            max_descriptor_set_update_after_bind_storage_images,
+            max_descriptor_set_update_after_bind_input_attachments,
+        );
+        copy_fields!(
+            features,
+            self.features.features12,
+            shader_input_attachment_array_dynamic_indexing,
+            shader_uniform_texel_buffer_array_dynamic_indexing,
+            shader_storage_texel_buffer_array_dynamic_indexing,
+            shader_uniform_buffer_array_non_uniform_indexing,
+            shader_sampled_image_array_non_uniform_indexing,
+            shader_storage_buffer_array_non_uniform_indexing,
+            shader_storage_image_array_non_uniform_indexing,
+            shader_input_attachment_array_non_uniform_indexing,
+            shader_uniform_texel_buffer_array_non_uniform_indexing,
+            shader_storage_texel_buffer_array_non_uniform_indexing,
+            descriptor_binding_uniform_buffer_update_after_bind,
+            descriptor_binding_sampled_image_update_after_bind,
+            descriptor_binding_storage_image_update_after_bind,
+            descriptor_binding_storage_buffer_update_after_bind,
+            descriptor_binding_uniform_texel_buffer_update_after_bind,
+            descriptor_binding_storage_texel_buffer_update_after_bind,
+            descriptor_binding_update_unused_while_pending,
+            descriptor_binding_partially_bound,
+            descriptor_binding_variable_descriptor_count,
+            runtime_descriptor_array,
+        );
+    }
+
+    /// Load from a JSON device profile, as written by `vulkaninfo --json`.
+    pub fn from_profile_json(text: &str) -> Result<Self, Error> {
+        let profile: Value = serde_json::from_str(text)?;
+        let capabilities = profile
+            .get("capabilities")
+            .and_then(Value::as_object)
+            .ok_or_else(|| anyhow!("Device profile has no capabilities"))?;
+        let mut info = Self::default();
+        //  Capability blocks are merged. Later blocks override earlier ones.
+        for (block_name, block) in capabilities {
+            info.read_capability(block)
+                .with_context(|| format!("Device profile capability block {}", block_name))?;
+        }
+        Ok(info)
+    }
+
+    /// Load from a JSON device profile file.
+    pub fn from_profile_file(path: &std::path::Path) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Reading device profile {}", path.display()))?;
+        Self::from_profile_json(&text).with_context(|| format!("Device profile {}", path.display()))
+    }
+
+    /// Device name.
+    pub fn name(&self) -> String {
+        self.properties
+            .properties10
+            .device_name_as_c_str()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    /// Vulkan version the device supports.
+    pub fn api_version(&self) -> u32 {
+        self.properties.properties10.api_version
+    }
+
+    /// True if the device supports an extension.
+    pub fn has_extension(&self, name: &str) -> bool {
+        self.extensions.iter().any(|e| e == name)
+    }
+
+    /// What bindless descriptor tables need which this device lacks. Empty if it has it all.
+    pub fn missing_bindless_features(&self) -> Vec<&'static str> {
+        let mut missing = Vec::new();
+        if self.api_version() < vk::API_VERSION_1_2
+            && !self.has_extension("VK_EXT_descriptor_indexing")
+        {
+            missing.push("Vulkan 1.2 or VK_EXT_descriptor_indexing");
+        }
+        let f = &self.features.features12;
+        let required = [
+            (f.runtime_descriptor_array, "runtimeDescriptorArray"),
+            (
+                f.descriptor_binding_partially_bound,
+                "descriptorBindingPartiallyBound",
+            ),
+            (
+                f.descriptor_binding_variable_descriptor_count,
+                "descriptorBindingVariableDescriptorCount",
+            ),
+            (
+                f.descriptor_binding_sampled_image_update_after_bind,
+                "descriptorBindingSampledImageUpdateAfterBind",
+            ),
+            (
+                f.descriptor_binding_storage_image_update_after_bind,
+                "descriptorBindingStorageImageUpdateAfterBind",
+            ),
+            (
+                f.descriptor_binding_storage_buffer_update_after_bind,
+                "descriptorBindingStorageBufferUpdateAfterBind",
+            ),
+            (
+                f.shader_sampled_image_array_non_uniform_indexing,
+                "shaderSampledImageArrayNonUniformIndexing",
+            ),
+        ];
+        missing.extend(
+            required
+                .into_iter()
+                .filter(|&(supported, _)| supported == vk::FALSE)
+                .map(|(_, name)| name),
+        );
+        missing
+    }
+
+    /// True if the device can write null descriptors, through `VK_EXT_robustness2`.
+    pub fn supports_null_descriptor(&self) -> bool {
+        self.has_extension("VK_EXT_robustness2")
+            && self.features.robustness2.null_descriptor == vk::TRUE
+    }
+
+    /// True if the device can do bindless descriptor tables.
+    pub fn supports_bindless(&self) -> bool {
+        self.missing_bindless_features().is_empty()
+    }
+
+    /// Make sure no `p_next` pointer is left pointing at anything.
+    fn clear_chains(&mut self) {
+        self.properties.properties11.p_next = std::ptr::null_mut();
+        self.properties.properties12.p_next = std::ptr::null_mut();
+        self.properties.properties13.p_next = std::ptr::null_mut();
+        self.features.features11.p_next = std::ptr::null_mut();
+        self.features.features12.p_next = std::ptr::null_mut();
+        self.features.features13.p_next = std::ptr::null_mut();
+        self.features.robustness2.p_next = std::ptr::null_mut();
+    }
+
+    /// Read one capability block of a profile.
+    fn read_capability(&mut self, block: &Value) -> Result<(), Error> {
+        if let Some(extensions) = block.get("extensions").and_then(Value::as_object) {
+            for name in extensions.keys() {
+                if !self.has_extension(name) {
+                    self.extensions.push(name.clone());
+                }
+            }
+        }
+        let p = &mut self.properties;
+        for (name, s) in block
+            .get("properties")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+        {
+            match name.as_str() {
+                "VkPhysicalDeviceProperties" => read_properties10(&mut p.properties10, s)?,
+                "VkPhysicalDeviceVulkan11Properties" | "VkPhysicalDeviceMaintenance3Properties" => {
+                    profile_fields!(s, p.properties11,
+                        "maxPerSetDescriptors" => max_per_set_descriptors,
+                        "maxMemoryAllocationSize" => max_memory_allocation_size,
+                        "subgroupSize" => subgroup_size,
+                    );
+                }
+                "VkPhysicalDeviceVulkan12Properties"
+                | "VkPhysicalDeviceDescriptorIndexingProperties"
+                | "VkPhysicalDeviceDescriptorIndexingPropertiesEXT" => {
+                    profile_fields!(s, p.properties12,
+                        "maxUpdateAfterBindDescriptorsInAllPools" => max_update_after_bind_descriptors_in_all_pools,
+                        "shaderSampledImageArrayNonUniformIndexingNative" => shader_sampled_image_array_non_uniform_indexing_native,
+                        "shaderStorageBufferArrayNonUniformIndexingNative" => shader_storage_buffer_array_non_uniform_indexing_native,
+                        "shaderStorageImageArrayNonUniformIndexingNative" => shader_storage_image_array_non_uniform_indexing_native,
+                        "robustBufferAccessUpdateAfterBind" => robust_buffer_access_update_after_bind,
+                        "maxPerStageDescriptorUpdateAfterBindSamplers" => max_per_stage_descriptor_update_after_bind_samplers,
+                        "maxPerStageDescriptorUpdateAfterBindUniformBuffers" => max_per_stage_descriptor_update_after_bind_uniform_buffers,
+                        "maxPerStageDescriptorUpdateAfterBindStorageBuffers" => max_per_stage_descriptor_update_after_bind_storage_buffers,
+                        "maxPerStageDescriptorUpdateAfterBindSampledImages" => max_per_stage_descriptor_update_after_bind_sampled_images,
+                        "maxPerStageDescriptorUpdateAfterBindStorageImages" => max_per_stage_descriptor_update_after_bind_storage_images,
+                        "maxPerStageUpdateAfterBindResources" => max_per_stage_update_after_bind_resources,
+                        "maxDescriptorSetUpdateAfterBindSamplers" => max_descriptor_set_update_after_bind_samplers,
+                        "maxDescriptorSetUpdateAfterBindUniformBuffers" => max_descriptor_set_update_after_bind_uniform_buffers,
+                        "maxDescriptorSetUpdateAfterBindStorageBuffers" => max_descriptor_set_update_after_bind_storage_buffers,
+                        "maxDescriptorSetUpdateAfterBindSampledImages" => max_descriptor_set_update_after_bind_sampled_images,
+                        "maxDescriptorSetUpdateAfterBindStorageImages" => max_descriptor_set_update_after_bind_storage_images,
+                    );
+                }
+                "VkPhysicalDeviceVulkan13Properties" | "VkPhysicalDeviceMaintenance4Properties" => {
+                    profile_fields!(s, p.properties13,
+                        "maxInlineUniformBlockSize" => max_inline_uniform_block_size,
+                        "maxDescriptorSetUpdateAfterBindInlineUniformBlocks" => max_descriptor_set_update_after_bind_inline_uniform_blocks,
+                        "maxBufferSize" => max_buffer_size,
+                    );
+                }
+                _ => {} // not relevant here
+            }
+        }
+        let f = &mut self.features;
+        for (name, s) in block
+            .get("features")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+        {
+            match name.as_str() {
+                "VkPhysicalDeviceFeatures" => {
+                    profile_fields!(s, f.features10,
+                        "robustBufferAccess" => robust_buffer_access,
+                        "multiDrawIndirect" => multi_draw_indirect,
+                        "drawIndirectFirstInstance" => draw_indirect_first_instance,
+                        "samplerAnisotropy" => sampler_anisotropy,
+                        "textureCompressionBC" => texture_compression_bc,
+                        "shaderSampledImageArrayDynamicIndexing" => shader_sampled_image_array_dynamic_indexing,
+                        "shaderStorageBufferArrayDynamicIndexing" => shader_storage_buffer_array_dynamic_indexing,
+                        "shaderStorageImageArrayDynamicIndexing" => shader_storage_image_array_dynamic_indexing,
+                        "shaderInt64" => shader_int64,
+                    );
+                }
+                "VkPhysicalDeviceVulkan11Features"
+                | "VkPhysicalDeviceShaderDrawParametersFeatures" => {
+                    profile_fields!(s, f.features11,
+                        "storageBuffer16BitAccess" => storage_buffer16_bit_access,
+                        "shaderDrawParameters" => shader_draw_parameters,
+                    );
+                }
+                "VkPhysicalDeviceVulkan12Features"
+                | "VkPhysicalDeviceDescriptorIndexingFeatures"
+                | "VkPhysicalDeviceDescriptorIndexingFeaturesEXT"
+                | "VkPhysicalDeviceBufferDeviceAddressFeatures"
+                | "VkPhysicalDeviceTimelineSemaphoreFeatures" => {
+                    profile_fields!(s, f.features12,
+                        "drawIndirectCount" => draw_indirect_count,
+                        "descriptorIndexing" => descriptor_indexing,
+                        "shaderSampledImageArrayNonUniformIndexing" => shader_sampled_image_array_non_uniform_indexing,
+                        "shaderStorageBufferArrayNonUniformIndexing" => shader_storage_buffer_array_non_uniform_indexing,
+                        "shaderStorageImageArrayNonUniformIndexing" => shader_storage_image_array_non_uniform_indexing,
+                        "descriptorBindingSampledImageUpdateAfterBind" => descriptor_binding_sampled_image_update_after_bind,
+                        "descriptorBindingStorageImageUpdateAfterBind" => descriptor_binding_storage_image_update_after_bind,
+                        "descriptorBindingStorageBufferUpdateAfterBind" => descriptor_binding_storage_buffer_update_after_bind,
+                        "descriptorBindingUpdateUnusedWhilePending" => descriptor_binding_update_unused_while_pending,
+                        "descriptorBindingPartiallyBound" => descriptor_binding_partially_bound,
+                        "descriptorBindingVariableDescriptorCount" => descriptor_binding_variable_descriptor_count,
+                        "runtimeDescriptorArray" => runtime_descriptor_array,
+                        "scalarBlockLayout" => scalar_block_layout,
+                        "hostQueryReset" => host_query_reset,
+                        "timelineSemaphore" => timeline_semaphore,
+                        "bufferDeviceAddress" => buffer_device_address,
+                    );
+                }
+                "VkPhysicalDeviceVulkan13Features"
+                | "VkPhysicalDeviceDynamicRenderingFeatures"
+                | "VkPhysicalDeviceSynchronization2Features"
+                | "VkPhysicalDeviceMaintenance4Features" => {
+                    profile_fields!(s, f.features13,
+                        "inlineUniformBlock" => inline_uniform_block,
+                        "descriptorBindingInlineUniformBlockUpdateAfterBind" => descriptor_binding_inline_uniform_block_update_after_bind,
+                        "synchronization2" => synchronization2,
+                        "dynamicRendering" => dynamic_rendering,
+                        "maintenance4" => maintenance4,
+                    );
+                }
+                "VkPhysicalDeviceRobustness2FeaturesEXT"
+                | "VkPhysicalDeviceRobustness2FeaturesKHR" => {
+                    profile_fields!(s, f.robustness2,
+                        "robustBufferAccess2" => robust_buffer_access2,
+                        "robustImageAccess2" => robust_image_access2,
+                        "nullDescriptor" => null_descriptor,
+                    );
+                }
+                _ => {} // not relevant here
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read `VkPhysicalDeviceProperties`, with its limits.
+fn read_properties10(p: &mut vk::PhysicalDeviceProperties, s: &Value) -> Result<(), Error> {
+    if let Some(v) = s.get("apiVersion") {
+        p.api_version = parse_api_version(v).ok_or_else(|| anyhow!("Bad apiVersion {}", v))?;
+    }
+    if let Some(v) = s.get("deviceType") {
+        p.device_type = parse_device_type(v).ok_or_else(|| anyhow!("Bad deviceType {}", v))?;
+    }
+    if let Some(v) = s.get("deviceName").and_then(Value::as_str) {
+        let name = std::ffi::CString::new(v)?;
+        *p = p.device_name(&name)?;
+    }
+    profile_fields!(s, p,
+        "driverVersion" => driver_version,
+        "vendorID" => vendor_id,
+        "deviceID" => device_id,
+    );
+    if let Some(s) = s.get("limits") {
+        profile_fields!(s, p.limits,
+            "maxImageDimension2D" => max_image_dimension2_d,
+            "maxStorageBufferRange" => max_storage_buffer_range,
+            "maxPushConstantsSize" => max_push_constants_size,
+            "maxMemoryAllocationCount" => max_memory_allocation_count,
+            "bufferImageGranularity" => buffer_image_granularity,
+            "maxBoundDescriptorSets" => max_bound_descriptor_sets,
+            "maxPerStageDescriptorSamplers" => max_per_stage_descriptor_samplers,
+            "maxPerStageDescriptorStorageBuffers" => max_per_stage_descriptor_storage_buffers,
+            "maxPerStageDescriptorSampledImages" => max_per_stage_descriptor_sampled_images,
+            "maxPerStageDescriptorStorageImages" => max_per_stage_descriptor_storage_images,
+            "maxPerStageResources" => max_per_stage_resources,
+            "maxDescriptorSetSamplers" => max_descriptor_set_samplers,
+            "maxDescriptorSetStorageBuffers" => max_descriptor_set_storage_buffers,
+            "maxDescriptorSetSampledImages" => max_descriptor_set_sampled_images,
+            "maxDescriptorSetStorageImages" => max_descriptor_set_storage_images,
+            "maxSamplerAnisotropy" => max_sampler_anisotropy,
+            "minStorageBufferOffsetAlignment" => min_storage_buffer_offset_alignment,
+            "nonCoherentAtomSize" => non_coherent_atom_size,
+        );
+    }
+    Ok(())
+}
+
+/// `apiVersion` is a packed number, or a string such as "1.3.250".
+fn parse_api_version(v: &Value) -> Option<u32> {
+    if let Some(s) = v.as_str() {
+        let mut parts = s.split('.').map(|n| n.trim().parse::<u32>());
+        let major = parts.next()?.ok()?;
+        let minor = parts.next()?.ok()?;
+        let patch = parts.next().unwrap_or(Ok(0)).ok()?;
+        Some(vk::make_api_version(0, major, minor, patch))
+    } else {
+        u32::from_profile(v)
+    }
+}
+
+/// `deviceType` is a number, or an enum name such as "VK_PHYSICAL_DEVICE_TYPE_DISCRETE_GPU".
+fn parse_device_type(v: &Value) -> Option<vk::PhysicalDeviceType> {
+    let device_type = match v.as_str() {
+        Some("VK_PHYSICAL_DEVICE_TYPE_OTHER") => vk::PhysicalDeviceType::OTHER,
+        Some("VK_PHYSICAL_DEVICE_TYPE_INTEGRATED_GPU") => vk::PhysicalDeviceType::INTEGRATED_GPU,
+        Some("VK_PHYSICAL_DEVICE_TYPE_DISCRETE_GPU") => vk::PhysicalDeviceType::DISCRETE_GPU,
+        Some("VK_PHYSICAL_DEVICE_TYPE_VIRTUAL_GPU") => vk::PhysicalDeviceType::VIRTUAL_GPU,
+        Some("VK_PHYSICAL_DEVICE_TYPE_CPU") => vk::PhysicalDeviceType::CPU,
+        Some(_) => return None,
+        None => vk::PhysicalDeviceType::from_raw(i32::try_from(v.as_u64()?).ok()?),
+    };
+    Some(device_type)
+}
+
+/// A Vulkan member value from a profile.
+trait FromProfile: Sized {
+    /// Convert, if the value is of a suitable type.
+    fn from_profile(v: &Value) -> Option<Self>;
+}
+
+/// Also `VkBool32`, which profiles write as true or false.
+impl FromProfile for u32 {
+    fn from_profile(v: &Value) -> Option<Self> {
+        match v {
+            Value::Bool(b) => Some(u32::from(*b)),
+            _ => u32::try_from(v.as_u64()?).ok(),
+        }
+    }
+}
+
+impl FromProfile for u64 {
+    fn from_profile(v: &Value) -> Option<Self> {
+        v.as_u64()
+    }
+}
+
+impl FromProfile for f32 {
+    fn from_profile(v: &Value) -> Option<Self> {
+        v.as_f64().map(|f| f as f32)
+    }
+}
+
+#[test]
+/// Load the sample profiles, and check table sizing and feature negotiation against them.
+fn test_gpuinfo_profiles() {
+    use crate::descriptors::DescriptorTableType;
+    /// Sample profile, expected name, API version, table sizes, and missing features.
+    type Expected = (
+        &'static str,
+        &'static str,
+        u32,
+        [u32; 3],
+        &'static [&'static str],
+    );
+    let samples: [Expected; 3] = [
+        (
+            include_str!("../profiles/sample_discrete_vulkan13.json"),
+            "Sample Discrete GPU",
+            vk::make_api_version(0, 1, 3, 250),
+            [1_048_576, 1_048_576, 1_048_576],
+            &[],
+        ),
+        (
+            include_str!("../profiles/sample_integrated_vulkan11_ext.json"),
+            "Sample Integrated GPU",
+            vk::make_api_version(0, 1, 1, 0),
+            [65_536, 65_536, 16_384],
+            &[],
+        ),
+        (
+            include_str!("../profiles/sample_mobile_vulkan11.json"),
+            "Sample Mobile GPU",
+            vk::make_api_version(0, 1, 1, 0),
+            [0, 0, 0],
+            &[
+                "Vulkan 1.2 or VK_EXT_descriptor_indexing",
+                "runtimeDescriptorArray",
+                "descriptorBindingPartiallyBound",
+                "descriptorBindingVariableDescriptorCount",
+                "descriptorBindingSampledImageUpdateAfterBind",
+                "descriptorBindingStorageImageUpdateAfterBind",
+                "descriptorBindingStorageBufferUpdateAfterBind",
+                "shaderSampledImageArrayNonUniformIndexing",
+            ],
+        ),
+    ];
+    for (json, name, api_version, sizes, missing) in samples {
+        let gpu = GpuInfo::from_profile_json(json).unwrap();
+        assert_eq!(gpu.name(), name);
+        assert_eq!(gpu.api_version(), api_version, "{}", name);
+        assert_eq!(DescriptorTableType::table_sizes(&gpu), sizes, "{}", name);
+        assert_eq!(gpu.missing_bindless_features(), missing, "{}", name);
+        assert_eq!(gpu.supports_bindless(), missing.is_empty());
+    }
+    let gpu = GpuInfo::from_profile_json(samples[0].0).unwrap();
+    assert_eq!(
+        gpu.properties.properties10.device_type,
+        vk::PhysicalDeviceType::DISCRETE_GPU
+    );
+    assert_eq!(
+        gpu.properties.properties10.limits.buffer_image_granularity,
+        1024
+    );
+    assert_eq!(
+        gpu.properties.properties10.limits.max_sampler_anisotropy,
+        16.0
+    );
+    assert_eq!(gpu.features.features13.dynamic_rendering, vk::TRUE);
+    assert!(gpu.supports_null_descriptor());
+    let gpu = GpuInfo::from_profile_json(samples[1].0).unwrap();
+    assert!(!gpu.supports_null_descriptor());
+    //  Errors.
+    assert!(GpuInfo::from_profile_json("{}").is_err());
+    let bad = r#"{"capabilities": {"device": {"features": {"VkPhysicalDeviceVulkan12Features": {"runtimeDescriptorArray": "yes"}}}}}"#;
+    assert!(GpuInfo::from_profile_json(bad).is_err());
+}
+
+#[test]
+/// On a 1.1 device, the `VK_EXT_descriptor_indexing` structures fill in the 1.2 members.
+fn test_gpuinfo_descriptor_indexing_ext() {
+    use crate::descriptors::DescriptorTableType;
+    let mut gpu = GpuInfo::default();
+    gpu.properties.properties10.api_version = vk::make_api_version(0, 1, 1, 0);
+    gpu.extensions
+        .push("VK_EXT_descriptor_indexing".to_string());
+    assert!(!gpu.supports_bindless());
+    let properties = vk::PhysicalDeviceDescriptorIndexingProperties {
+        max_update_after_bind_descriptors_in_all_pools: 1_048_576,
+        max_per_stage_update_after_bind_resources: 262_144,
+        max_per_stage_descriptor_update_after_bind_storage_buffers: 65_536,
+        max_per_stage_descriptor_update_after_bind_sampled_images: 65_536,
+        max_per_stage_descriptor_update_after_bind_storage_images: 16_384,
+        max_descriptor_set_update_after_bind_storage_buffers: 65_536,
+        max_descriptor_set_update_after_bind_sampled_images: 65_536,
+        max_descriptor_set_update_after_bind_storage_images: 16_384,
+        ..Default::default()
+    };
+    let features = vk::PhysicalDeviceDescriptorIndexingFeatures {
+        shader_sampled_image_array_non_uniform_indexing: vk::TRUE,
+        descriptor_binding_sampled_image_update_after_bind: vk::TRUE,
+        descriptor_binding_storage_image_update_after_bind: vk::TRUE,
+        descriptor_binding_storage_buffer_update_after_bind: vk::TRUE,
+        descriptor_binding_partially_bound: vk::TRUE,
+        descriptor_binding_variable_descriptor_count: vk::TRUE,
+        runtime_descriptor_array: vk::TRUE,
+        ..Default::default()
+    };
+    gpu.set_descriptor_indexing(&properties, &features);
+    assert!(gpu.supports_bindless());
+    assert_eq!(
+        DescriptorTableType::table_sizes(&gpu),
+        [65_536, 65_536, 16_384]
+    );
+}
@@ -4,4 +4,19 @@
 //! Animats
 //! November, 2024
 //!
-////// ***TEMP TURNOFF*** mod descriptors;
+pub mod bindless;
+pub mod descriptors;
+pub mod device;
+pub mod fallback;
+pub mod gpuinfo;
+pub mod writes;
+
+//  Exports
+pub use bindless::{
+    BindlessSlot, BindlessSlots, SampledImage, StorageBuffer, StorageImage, TableKind,
+};
+pub use descriptors::{DescriptorTableType, Descriptors};
+pub use device::DeviceContext;
+pub use fallback::{error_buffer_contents, error_texture_rgba, ErrorResources, FallbackMode};
+pub use gpuinfo::{GpuFeatures, GpuInfo, GpuProperties};
+pub use writes::{DescriptorBackend, DescriptorResource, DescriptorUpdate, DescriptorWriteQueue};
@@ -0,0 +1,363 @@
+//! # Writes.rs
+//!
+//! Queued descriptor writes.
+//!
+//! Any thread can ask for a descriptor table slot to be written or
+//! cleared. The request goes into a lock-free queue. Once per frame, at a
+//! point where the GPU is not rendering, the render thread flushes the
+//! queue. All the requests are combined into one `vkUpdateDescriptorSets`
+//! call. If a slot was written more than once since the last flush, the
+//! last write wins.
+//!
+//! A cleared slot is written with the fallback, so shaders reading a stale
+//! index get nothing, or the error texture, rather than something freed.
+//!
+//! Animats
+//! December, 2024.
+//!
+use crate::descriptors::DescriptorTableType;
+use crate::device::DeviceContext;
+use crate::fallback::FallbackMode;
+use ash::vk;
+use crossbeam_queue::SegQueue;
+use std::collections::BTreeMap;
+
+/// What goes in a descriptor table slot.
+///
+/// The handles must stay valid until the slot is written again or cleared,
+/// and that write has been flushed and its frames have retired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorResource {
+    /// Part of a buffer, for the storage buffer table
+    Buffer {
+        /// The buffer
+        buffer: vk::Buffer,
+        /// Start, in bytes
+        offset: vk::DeviceSize,
+        /// Length, in bytes, or `vk::WHOLE_SIZE`
+        range: vk::DeviceSize,
+    },
+    /// An image view, for the sampled and storage image tables
+    Image {
+        /// The view
+        view: vk::ImageView,
+        /// Layout the image will be in when shaders use it
+        layout: vk::ImageLayout,
+    },
+    /// Nothing. What a cleared slot holds with null descriptors.
+    Null,
+}
+
+impl DescriptorResource {
+    /// True if this can go in a table of type `ty`.
+    pub fn fits(&self, ty: DescriptorTableType) -> bool {
+        match self {
+            DescriptorResource::Buffer { .. } => ty == DescriptorTableType::StorageBuffer,
+            DescriptorResource::Image { .. } => ty != DescriptorTableType::StorageBuffer,
+            DescriptorResource::Null => true,
+        }
+    }
+}
+
+/// One slot write, after coalescing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DescriptorUpdate {
+    /// Table
+    pub table: DescriptorTableType,
+    /// Slot in the table
+    pub index: u32,
+    /// What to put there
+    pub resource: DescriptorResource,
+}
+
+/// The descriptor update call. In real use this is `vkUpdateDescriptorSets`.
+pub trait DescriptorBackend: Send + Sync {
+    /// Apply all of one frame's updates, in one call.
+    /// `sets` are the descriptor sets in set index order. `updates` are sorted by table and index.
+    fn update_descriptors(&self, sets: &[vk::DescriptorSet], updates: &[DescriptorUpdate]);
+}
+
+impl DescriptorBackend for DeviceContext {
+    fn update_descriptors(&self, sets: &[vk::DescriptorSet], updates: &[DescriptorUpdate]) {
+        //  The info arrays must be complete before the writes point into them.
+        let mut buffer_infos = Vec::new();
+        let mut image_infos = Vec::new();
+        //  Nulls, and anything which doesn't fit its table, are written as null descriptors.
+        //  `Descriptors` passes nulls only in null descriptor mode, and rejects misfits.
+        for update in updates {
+            if update.table == DescriptorTableType::StorageBuffer {
+                buffer_infos.push(match update.resource {
+                    DescriptorResource::Buffer {
+                        buffer,
+                        offset,
+                        range,
+                    } => vk::DescriptorBufferInfo {
+                        buffer,
+                        offset,
+                        range,
+                    },
+                    _ => vk::DescriptorBufferInfo {
+                        buffer: vk::Buffer::null(),
+                        offset: 0,
+                        range: vk::WHOLE_SIZE,
+                    },
+                });
+            } else {
+                image_infos.push(match update.resource {
+                    DescriptorResource::Image { view, layout } => vk::DescriptorImageInfo {
+                        sampler: vk::Sampler::null(),
+                        image_view: view,
+                        image_layout: layout,
+                    },
+                    _ => vk::DescriptorImageInfo {
+                        sampler: vk::Sampler::null(),
+                        image_view: vk::ImageView::null(),
+                        image_layout: vk::ImageLayout::UNDEFINED,
+                    },
+                });
+            }
+        }
+        //  One write per run of consecutive slots in the same table.
+        let mut writes = Vec::new();
+        let (mut next_buffer, mut next_image) = (0, 0);
+        for run in update_runs(updates) {
+            let table = run[0].table;
+            let write = vk::WriteDescriptorSet::default()
+                .dst_set(sets[table.set_index() as usize])
+                .dst_binding(0)
+                .dst_array_element(run[0].index)
+                .descriptor_type(table.to_vk());
+            let write = if table == DescriptorTableType::StorageBuffer {
+                next_buffer += run.len();
+                write.buffer_info(&buffer_infos[next_buffer - run.len()..next_buffer])
+            } else {
+                next_image += run.len();
+                write.image_info(&image_infos[next_image - run.len()..next_image])
+            };
+            writes.push(write);
+        }
+        unsafe { self.device().update_descriptor_sets(&writes, &[]) };
+    }
+}
+
+/// Split sorted updates into runs of consecutive slots in the same table.
+pub fn update_runs(updates: &[DescriptorUpdate]) -> impl Iterator<Item = &[DescriptorUpdate]> {
+    updates.chunk_by(|a, b| a.table == b.table && a.index + 1 == b.index)
+}
+
+/// Queue of slot writes and clears waiting for the next flush.
+#[derive(Default)]
+pub struct DescriptorWriteQueue {
+    /// Requests, in the order made. Lock-free.
+    pending: SegQueue<DescriptorUpdate>,
+}
+
+impl DescriptorWriteQueue {
+    /// Usual new.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a write of `resource` into slot `index` of table `table`. Any thread. Does not block.
+    pub fn write(&self, table: DescriptorTableType, index: u32, resource: DescriptorResource) {
+        self.pending.push(DescriptorUpdate {
+            table,
+            index,
+            resource,
+        });
+    }
+
+    /// Queue a write of the fallback into a slot. Any thread. Does not block.
+    pub fn clear(&self, table: DescriptorTableType, index: u32, fallback: &FallbackMode) {
+        self.write(table, index, fallback.resource(table));
+    }
+
+    /// Number of requests waiting, before coalescing.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// True if nothing is waiting.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Take everything queued so far. One update per slot, the last one requested,
+    /// sorted by table and index.
+    pub fn take_updates(&self) -> Vec<DescriptorUpdate> {
+        let mut latest = BTreeMap::new();
+        //  Requests pushed during this loop may or may not be taken. If not, the next flush gets them.
+        while let Some(update) = self.pending.pop() {
+            latest.insert((update.table.set_index(), update.index), update);
+        }
+        latest.into_values().collect()
+    }
+
+    /// Apply everything queued so far with one backend call. Render thread only,
+    /// while the GPU is not rendering. Returns the number of slots updated.
+    pub fn flush(&self, sets: &[vk::DescriptorSet], backend: &dyn DescriptorBackend) -> usize {
+        let updates = self.take_updates();
+        if !updates.is_empty() {
+            backend.update_descriptors(sets, &updates);
+        }
+        updates.len()
+    }
+}
+
+/// Backend which records updates instead of making Vulkan calls.
+#[cfg(test)]
+#[derive(Default)]
+struct RecordingBackend {
+    /// The updates of each call
+    calls: std::sync::Mutex<Vec<Vec<DescriptorUpdate>>>,
+}
+
+#[cfg(test)]
+impl DescriptorBackend for RecordingBackend {
+    fn update_descriptors(&self, _sets: &[vk::DescriptorSet], updates: &[DescriptorUpdate]) {
+        self.calls.lock().unwrap().push(updates.to_vec());
+    }
+}
+
+#[test]
+/// Later writes to a slot override earlier ones, and each flush is one call, in table and index order.
+fn test_descriptor_write_coalescing() {
+    use ash::vk::Handle;
+    use DescriptorTableType::{SampledImage, StorageBuffer};
+    let image = |n| DescriptorResource::Image {
+        view: vk::ImageView::from_raw(n),
+        layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+    };
+    let buffer = DescriptorResource::Buffer {
+        buffer: vk::Buffer::from_raw(9),
+        offset: 256,
+        range: 1024,
+    };
+    assert!(buffer.fits(StorageBuffer) && !buffer.fits(SampledImage));
+    assert!(image(1).fits(SampledImage) && !image(1).fits(StorageBuffer));
+    let backend = RecordingBackend::default();
+    let queue = DescriptorWriteQueue::new();
+    queue.write(SampledImage, 7, image(1));
+    queue.write(SampledImage, 5, image(2));
+    queue.write(StorageBuffer, 3, buffer);
+    queue.write(SampledImage, 7, image(3)); // overrides the first
+    queue.write(SampledImage, 6, image(4));
+    queue.clear(SampledImage, 5, &FallbackMode::NullDescriptor); // overrides the second
+    assert_eq!(queue.len(), 6);
+    assert_eq!(queue.flush(&[], &backend), 4);
+    assert!(queue.is_empty());
+    let expected = [
+        DescriptorUpdate {
+            table: StorageBuffer,
+            index: 3,
+            resource: buffer,
+        },
+        DescriptorUpdate {
+            table: SampledImage,
+            index: 5,
+            resource: DescriptorResource::Null,
+        },
+        DescriptorUpdate {
+            table: SampledImage,
+            index: 6,
+            resource: image(4),
+        },
+        DescriptorUpdate {
+            table: SampledImage,
+            index: 7,
+            resource: image(3),
+        },
+    ];
+    assert_eq!(*backend.calls.lock().unwrap(), [expected.to_vec()]);
+    //  Slots 5 to 7 are one run, so one Vulkan write.
+    let runs: Vec<usize> = update_runs(&expected).map(|run| run.len()).collect();
+    assert_eq!(runs, [1, 3]);
+    //  Nothing queued, no call.
+    assert_eq!(queue.flush(&[], &backend), 0);
+    assert_eq!(backend.calls.lock().unwrap().len(), 1);
+}
+
+#[test]
+/// Writes from many threads all arrive, and a slot written by one thread in order ends with its last value.
+fn test_descriptor_write_threads() {
+    use ash::vk::Handle;
+    const THREADS: u32 = 8;
+    const PER_THREAD: u32 = 100;
+    let queue = std::sync::Arc::new(DescriptorWriteQueue::new());
+    let workers: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let queue = std::sync::Arc::clone(&queue);
+            std::thread::spawn(move || {
+                for n in 0..PER_THREAD {
+                    let view = vk::ImageView::from_raw(u64::from(n) + 1);
+                    queue.write(
+                        DescriptorTableType::SampledImage,
+                        t,
+                        DescriptorResource::Image {
+                            view,
+                            layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                        },
+                    );
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    let updates = queue.take_updates();
+    assert_eq!(updates.len(), THREADS as usize);
+    for (t, update) in updates.iter().enumerate() {
+        assert_eq!(update.index, t as u32);
+        assert_eq!(
+            update.resource,
+            DescriptorResource::Image {
+                view: vk::ImageView::from_raw(u64::from(PER_THREAD)),
+                layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            }
+        );
+    }
+}
+
+#[test]
+/// Without null descriptors, a cleared slot gets the error resource.
+fn test_descriptor_write_error_resources() {
+    use crate::fallback::ErrorResources;
+    use ash::vk::Handle;
+    use DescriptorTableType::{SampledImage, StorageBuffer, StorageImage};
+    let error_resources = ErrorResources {
+        storage_buffer: DescriptorResource::Buffer {
+            buffer: vk::Buffer::from_raw(1),
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        },
+        sampled_image: DescriptorResource::Image {
+            view: vk::ImageView::from_raw(2),
+            layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        },
+        storage_image: DescriptorResource::Image {
+            view: vk::ImageView::from_raw(3),
+            layout: vk::ImageLayout::GENERAL,
+        },
+    };
+    let fallback = FallbackMode::ErrorResources(error_resources);
+    let backend = RecordingBackend::default();
+    let queue = DescriptorWriteQueue::new();
+    queue.clear(StorageBuffer, 4, &fallback);
+    queue.clear(SampledImage, 4, &fallback);
+    queue.clear(StorageImage, 4, &fallback);
+    assert_eq!(queue.flush(&[], &backend), 3);
+    let resources: Vec<DescriptorResource> = backend.calls.lock().unwrap()[0]
+        .iter()
+        .map(|update| update.resource)
+        .collect();
+    assert_eq!(
+        resources,
+        [
+            error_resources.storage_buffer,
+            error_resources.sampled_image,
+            error_resources.storage_image
+        ]
+    );
+    assert!(!resources.contains(&DescriptorResource::Null));
+}
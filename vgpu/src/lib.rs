@@ -9,6 +9,9 @@ mod testdummies;
 pub mod wgputypes;
 
 //  Exports
-pub use stubs::{Features, Instance, Limits, MultisampleState, PowerPreference, PrimitiveState};
+pub use stubs::{
+    Device, DeviceDescriptor, Features, Instance, Limits, MultisampleState, PowerPreference,
+    PrimitiveState, Queue,
+};
 
 pub use wgputypes::{Color, LoadOp, MemoryHints, Operations, StoreOp};
@@ -1,6 +1,8 @@
 //! stubs.rs -- dummy stubs to be replaced with real code.
 
+use crate::wgputypes::MemoryHints;
 use anyhow::Error;
+use std::sync::Arc;
 /// These are types that WGPU defines and which must be emulated.
 
 /// Instance
@@ -26,6 +28,57 @@ pub struct SurfaceCapabilities {}
 
 pub struct Adapter {}
 
+impl Adapter {
+    pub async fn request_device(
+        &self,
+        desc: &DeviceDescriptor<'_>,
+        _trace_path: Option<&std::path::Path>,
+    ) -> Result<(Device, Queue), Error> {
+        Ok((
+            Device {
+                memory_policy: alloc::MemoryPolicy::from(&desc.memory_hints),
+            },
+            Queue {},
+        ))
+    }
+}
+
+/// DeviceDescriptor
+pub struct DeviceDescriptor<'a> {
+    pub label: Option<&'a str>,
+    pub required_features: Features,
+    pub required_limits: Limits,
+    pub memory_hints: MemoryHints,
+}
+
+/// Device
+pub struct Device {
+    /// How device memory is split into blocks, from the memory hints
+    memory_policy: alloc::MemoryPolicy,
+}
+
+impl Device {
+    /// Device memory for one memory type, allocated as the memory hints asked.
+    pub fn memory_pool(
+        &self,
+        backend: Arc<dyn alloc::DeviceMemoryBackend>,
+        memory_type: u32,
+        heap: usize,
+        granularity: u64,
+    ) -> alloc::DeviceMemoryPool {
+        alloc::DeviceMemoryPool::new(
+            self.memory_policy.clone(),
+            backend,
+            memory_type,
+            heap,
+            granularity,
+        )
+    }
+}
+
+/// Queue
+pub struct Queue {}
+
 /// PowerPreference
 #[derive(Default)]
 pub struct PowerPreference {}
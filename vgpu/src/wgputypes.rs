@@ -163,3 +163,18 @@ pub enum MemoryHints {
         suballocated_device_memory_block_size: Range<u64>,
     },
 }
+
+/// How the device memory pools carry out the hints.
+impl From<&MemoryHints> for alloc::MemoryPolicy {
+    fn from(hints: &MemoryHints) -> Self {
+        match hints {
+            MemoryHints::Performance => alloc::MemoryPolicy::Performance,
+            MemoryHints::MemoryUsage => alloc::MemoryPolicy::MemoryUsage,
+            MemoryHints::Manual {
+                suballocated_device_memory_block_size,
+            } => alloc::MemoryPolicy::Manual {
+                block_sizes: suballocated_device_memory_block_size.clone(),
+            },
+        }
+    }
+}